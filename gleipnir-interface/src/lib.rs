@@ -2,12 +2,16 @@
 
 use std::cmp::min;
 use std::fmt;
+use std::fs::File;
+use std::io;
 use std::mem::{self, MaybeUninit};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::RangeInclusive;
+use std::path::Path;
 
 use libc;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 pub mod unixtransport;
 
@@ -16,6 +20,13 @@ pub trait Daemon {
     async fn init_monitor(socket_path: String);
     async fn unlock() -> bool;
     async fn set_rules(rules: Rules);
+    async fn answer_ask(id: u64, answer: AskAnswer);
+    /// Accepts the changed executable of a pinned rule, see `PackageReport::broken_pin`
+    async fn approve_sha256(rule: usize, sha256: String);
+    async fn list_profiles() -> Profiles;
+    /// Switches to the rules of the profile, a new one starts as a copy of the active one
+    async fn activate_profile(name: String) -> bool;
+    async fn rename_profile(name: String, new_name: String) -> bool;
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -25,6 +36,14 @@ pub struct Rules {
     pub rate_rules: Vec<RateLimitRule>,
 }
 
+/// Named sets of rules, only the active one is applied
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Profiles {
+    /// Sorted
+    pub names: Vec<String>,
+    pub active: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct RateLimitRule {
     pub name: String,
@@ -35,6 +54,20 @@ pub struct RateLimitRule {
 pub trait Monitor {
     async fn on_packages(logs: Vec<PackageReport>);
     async fn on_rules_updated(rules: Rules);
+    async fn on_profiles_updated(profiles: Profiles);
+    async fn on_ask(id: u64, report: PackageReport);
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AskAnswer {
+    /// Accept the packets of this question only
+    AllowOnce,
+    /// Accept the program until the daemon restarts
+    Allow,
+    /// Drop the program until the daemon restarts
+    Deny,
+    /// Accept the program and save a rule for it
+    AllowForever,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,10 +75,30 @@ pub struct PackageReport {
     pub device: Device,
     pub protocol: Proto,
     pub addr: SocketAddr,
+    /// The name `addr` was resolved from, if the daemon saw the DNS answer
+    pub domain: Option<String>,
     pub len: usize,
     pub exe: String,
+    /// See `ProcessInfo::app`
+    pub app: String,
+    /// Only computed if a rule pins the executable
+    pub sha256: Option<String>,
+    /// See `ProcessInfo::cmdline`
+    pub cmdline: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// The name of `uid`, or the number if it has no name
+    pub user: String,
+    /// See `ProcessInfo::ancestors`
+    pub ancestors: Vec<String>,
+    /// See `ProcessInfo::cgroup`
+    pub cgroup: String,
+    /// See `ProcessInfo::unit`
+    pub unit: String,
     pub dropped: bool,
     pub matched_rule: Option<usize>,
+    /// A rule pinning the executable with another SHA-256, it was replaced or upgraded
+    pub broken_pin: Option<usize>,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Ord, PartialOrd, Serialize, Deserialize)]
@@ -69,6 +122,19 @@ pub enum Proto {
     Tcp = libc::IPPROTO_TCP as isize,
     Udp = libc::IPPROTO_UDP as isize,
     UdpLite = libc::IPPROTO_UDPLITE as isize,
+    Sctp = libc::IPPROTO_SCTP as isize,
+    Dccp = libc::IPPROTO_DCCP as isize,
+    Icmp = libc::IPPROTO_ICMP as isize,
+    IcmpV6 = libc::IPPROTO_ICMPV6 as isize,
+}
+
+impl Proto {
+    pub fn is_icmp(&self) -> bool {
+        match self {
+            Proto::Icmp | Proto::IcmpV6 => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Proto {
@@ -77,6 +143,10 @@ impl fmt::Display for Proto {
             Proto::Tcp => "TCP",
             Proto::Udp => "UDP",
             Proto::UdpLite => "UDPLite",
+            Proto::Sctp => "SCTP",
+            Proto::Dccp => "DCCP",
+            Proto::Icmp => "ICMP",
+            Proto::IcmpV6 => "ICMPv6",
         };
         f.write_str(s)
     }
@@ -86,32 +156,278 @@ impl fmt::Display for Proto {
 pub enum RuleTarget {
     Accept,
     Drop,
+    Ask,
+    /// Drop, and tell the other side with a TCP RST or ICMP port unreachable
+    Reject,
     RateLimit(usize), // index to rate_rules item
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+impl Default for RuleTarget {
+    fn default() -> Self {
+        RuleTarget::Accept
+    }
+}
+
+/// ICMP or ICMPv6 message type
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct IcmpType {
+    pub kind: u8,
+    /// Any code if `None`
+    pub code: Option<u8>,
+}
+
+impl IcmpType {
+    pub fn contains(&self, (kind, code): (u8, u8)) -> bool {
+        self.kind == kind && self.code.map(|c| c == code).unwrap_or(true)
+    }
+}
+
+/// Fields left `None` match anything
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Rule {
     pub device: Option<Device>,
     pub proto: Option<Proto>,
+    /// See `exe_matches`
     pub exe: Option<String>,
+    /// Lowercase hex SHA-256 of the executable, a replaced one doesn't match
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Matches `ProcessInfo::app`
+    #[serde(default)]
+    pub app: Option<String>,
+    /// Matches `ProcessInfo::cmdline` if equal, or a prefix of whole arguments
+    #[serde(default)]
+    pub cmdline: Option<String>,
+    /// UID of the process
+    #[serde(default)]
+    pub user: Option<u32>,
+    /// Primary GID of the process
+    #[serde(default)]
+    pub group: Option<u32>,
+    /// Matches if the process was spawned by this executable, directly or not
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// See `unit_matches`
+    #[serde(default)]
+    pub unit: Option<String>,
     #[serde(with = "rangeinclusive_serde")]
     pub port: Option<RangeInclusive<u16>>,
     pub subnet: Option<(IpAddr, u8)>, // mask
+    /// See `domain_matches`
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Only for ICMP and ICMPv6
+    #[serde(default)]
+    pub icmp: Option<IcmpType>,
+    /// The rule is skipped outside of it. Checked by the daemon with the clock,
+    /// `match_target` ignores it
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+    /// The daemon removes the rule then
+    #[serde(default)]
+    pub expiry: Option<Expiry>,
     pub target: RuleTarget,
 }
 
+/// When a temporary rule is removed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Expiry {
+    /// Seconds since the epoch
+    At(u64),
+    /// Seconds from when the daemon gets the rule, it turns them into `At`
+    For(u64),
+    /// When the daemon restarts
+    Restart,
+    /// When the process with this PID exits
+    ProcessExit {
+        pid: u32,
+        /// Tells the process from a later one with the same PID, the daemon fills it in when
+        /// it gets the rule
+        start_time: Option<u64>,
+    },
+}
+
+impl Expiry {
+    /// Rules expiring with the daemon or a process are never saved
+    pub fn is_volatile(&self) -> bool {
+        match self {
+            Expiry::Restart | Expiry::ProcessExit { .. } => true,
+            Expiry::At(_) | Expiry::For(_) => false,
+        }
+    }
+}
+
+/// Days of the week and times of the day
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Bit 0 is Sunday and bit 6 is Saturday, every day if none is set
+    pub days: u8,
+    /// Minutes since midnight, from the start until before the end. A range ending before
+    /// its start goes over midnight, into the next day. The whole day if empty
+    #[serde(default)]
+    pub times: Vec<(u16, u16)>,
+    /// Minutes east of UTC, the local time of the system if `None`
+    #[serde(default)]
+    pub utc_offset: Option<i32>,
+}
+
+impl Schedule {
+    /// `day` is the day of the week, Sunday is 0, `minute` is the minutes since midnight
+    pub fn contains(&self, day: u8, minute: u16) -> bool {
+        let on = |day: u8| self.days == 0 || self.days & (1 << (day % 7)) != 0;
+        if self.times.is_empty() {
+            return on(day);
+        }
+        self.times.iter().any(|&(start, end)| {
+            if start <= end {
+                on(day) && start <= minute && minute < end
+            } else {
+                // started the day before
+                (on(day) && start <= minute) || (on(day + 6) && minute < end)
+            }
+        })
+    }
+}
+
+/// The process owning a packet
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct ProcessInfo<'a> {
+    pub exe: &'a str,
+    /// See `Rule::sha256`, may be `None` if no rule pins the executable
+    pub sha256: Option<&'a str>,
+    /// The application of sandboxed or bundled programs, whose executables say little, like
+    /// `flatpak:org.mozilla.firefox`, `snap:firefox`, `appimage:<path of the image>`, or the
+    /// container like `docker:<name>`. Empty for other programs
+    pub app: &'a str,
+    /// Arguments joined by spaces, the interpreter and its options are replaced by the script
+    /// it runs, like `/home/user/foo.py --bar` for `python3 -u foo.py --bar`
+    pub cmdline: &'a str,
+    /// The real UID and GID, not changed by setuid executables
+    pub uid: u32,
+    pub gid: u32,
+    /// Executables of the parent, its parent and so on
+    pub ancestors: &'a [String],
+    /// Path in the cgroup v2 hierarchy, or the one of systemd with cgroup v1
+    pub cgroup: &'a str,
+    /// The innermost systemd service or scope in `cgroup`, like `systemd-resolved.service`
+    pub unit: &'a str,
+}
+
+/// Lowercase hex, see `Rule::sha256`
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// `pattern` is the path itself, or has `*` matching a part of a file or directory name, and
+/// `**` matching anything, even several directories
+pub fn exe_matches(pattern: &str, exe: &str) -> bool {
+    // `ends[i]` if the pattern so far matches the first `i` bytes of the path, so each part of
+    // the pattern is tried once for every position
+    fn matches(mut pattern: &[u8], path: &[u8]) -> bool {
+        let mut ends = vec![false; path.len() + 1];
+        ends[0] = true;
+        while !pattern.is_empty() {
+            let mut next = vec![false; path.len() + 1];
+            pattern = match pattern {
+                [b'*', b'*', rest @ ..] => {
+                    let mut reached = false;
+                    for (next, &end) in next.iter_mut().zip(&ends) {
+                        reached |= end;
+                        *next = reached;
+                    }
+                    rest
+                }
+                [b'*', rest @ ..] => {
+                    let mut reached = false;
+                    for (i, (next, &end)) in next.iter_mut().zip(&ends).enumerate() {
+                        if i > 0 && path[i - 1] == b'/' {
+                            reached = false;
+                        }
+                        reached |= end;
+                        *next = reached;
+                    }
+                    rest
+                }
+                [c, rest @ ..] => {
+                    for (next, (&end, b)) in next[1..].iter_mut().zip(ends.iter().zip(path)) {
+                        *next = end && b == c;
+                    }
+                    rest
+                }
+                [] => unreachable!(),
+            };
+            ends = next;
+        }
+        ends[path.len()]
+    }
+    if pattern.contains('*') {
+        matches(pattern.as_bytes(), exe.as_bytes())
+    } else {
+        pattern == exe
+    }
+}
+
+/// `pattern` is the name of a unit, or a cgroup if it starts with `/`, which contains the ones
+/// under it too. Names are of system units only, users can name theirs like any system one
+pub fn unit_matches(pattern: &str, unit: &str, cgroup: &str) -> bool {
+    if !pattern.starts_with('/') {
+        return pattern == unit && cgroup.starts_with("/system.slice/");
+    }
+    let pattern = pattern.trim_end_matches('/');
+    cgroup.starts_with(pattern)
+        && (cgroup.len() == pattern.len() || cgroup.as_bytes()[pattern.len()] == b'/')
+}
+
+/// `pattern` is a name the address was resolved from, or `*.<domain>` for any name under
+/// the domain. Names are lowercase without the trailing dot
+pub fn domain_matches(pattern: &str, name: &str) -> bool {
+    if pattern.starts_with("*.") {
+        name.ends_with(&pattern[1..])
+    } else {
+        pattern == name
+    }
+}
+
+/// `pattern` is the command line itself, or its first few arguments
+pub fn cmdline_matches(pattern: &str, cmdline: &str) -> bool {
+    cmdline.starts_with(pattern)
+        && (cmdline.len() == pattern.len() || cmdline.as_bytes()[pattern.len()] == b' ')
+}
+
 impl Rule {
+    /// `icmp` is the type and code of ICMP packets, ports of them are meaningless.
+    /// `domains` are the names `addr` was resolved from
     pub fn match_target(
         &self,
         device: Device,
         protocol: Proto,
         addr: SocketAddr,
-        exe: &str,
+        domains: &[String],
+        icmp: Option<(u8, u8)>,
+        process: ProcessInfo,
     ) -> Option<RuleTarget> {
         if (self.device.is_none() || device == self.device.unwrap())
             && (self.proto.is_none() || protocol == self.proto.unwrap())
-            && (self.exe.is_none() || exe == self.exe.as_ref().unwrap())
-            && (self.port.is_none() || self.port.as_ref().unwrap().contains(&addr.port()))
+            && (self.exe.is_none() || exe_matches(self.exe.as_ref().unwrap(), process.exe))
+            && (self.sha256.is_none() || process.sha256 == self.sha256.as_deref())
+            && (self.app.is_none() || process.app == self.app.as_ref().unwrap())
+            && (self.cmdline.is_none()
+                || cmdline_matches(self.cmdline.as_ref().unwrap(), process.cmdline))
+            && (self.user.is_none() || process.uid == self.user.unwrap())
+            && (self.group.is_none() || process.gid == self.group.unwrap())
+            && (self.parent.is_none()
+                || process
+                    .ancestors
+                    .iter()
+                    .any(|exe| exe == self.parent.as_ref().unwrap()))
+            && (self.unit.is_none()
+                || unit_matches(self.unit.as_ref().unwrap(), process.unit, process.cgroup))
+            && (self.port.is_none()
+                || (!protocol.is_icmp() && self.port.as_ref().unwrap().contains(&addr.port())))
+            && (self.icmp.is_none() || icmp.map_or(false, |t| self.icmp.unwrap().contains(t)))
             && (self.subnet.is_none()
                 || (addr.is_ipv4() == self.subnet.unwrap().0.is_ipv4()
                     && (match (addr.ip(), self.subnet.unwrap()) {
@@ -119,6 +435,10 @@ impl Rule {
                         (IpAddr::V6(addr), (IpAddr::V6(subnet), mask)) => addr.mask(mask) == subnet,
                         _ => unreachable!(),
                     })))
+            && (self.domain.is_none()
+                || domains
+                    .iter()
+                    .any(|name| domain_matches(self.domain.as_ref().unwrap(), name)))
         {
             Some(self.target)
         } else {
@@ -16,6 +16,15 @@ Pane {
         return port > 25565 ? 25565 : port;
     }
 
+    readonly property var dayNames: [qsTr("Sun"), qsTr("Mon"), qsTr("Tue"), qsTr("Wed"),
+                                     qsTr("Thu"), qsTr("Fri"), qsTr("Sat")]
+    // the days and times a rule applies, like "Mon Tue 09:00-17:00"
+    function scheduleText(days, times) {
+        if (!days && !times) return qsTr("Always")
+        const checked = dayNames.filter((_, i) => days & (1 << i)).join(" ")
+        return [checked, times].filter((s) => s.length > 0).join(" ")
+    }
+
     function isIP(s) {
         // IPv4 Segment
         const v4Seg = '(?:[0-9]|[1-9][0-9]|1[0-9][0-9]|2[0-4][0-9]|25[0-5])';
@@ -83,6 +92,17 @@ Pane {
             }
         }
         ToolSeparator {}
+        Pane {
+            id: firewallTitleUser
+            topPadding: 0
+            bottomPadding: 0
+            Label {
+                text: qsTr("User / Group")
+                font.bold: true
+                anchors.horizontalCenter: parent.horizontalCenter
+            }
+        }
+        ToolSeparator {}
         Pane {
             id: firewallTitle3
             topPadding: 0
@@ -99,7 +119,29 @@ Pane {
             topPadding: 0
             bottomPadding: 0
             Label {
-                text: qsTr("Port Range")
+                text: qsTr("Port Range / ICMP Type")
+                font.bold: true
+                anchors.horizontalCenter: parent.horizontalCenter
+            }
+        }
+        ToolSeparator {}
+        Pane {
+            id: firewallTitleSchedule
+            topPadding: 0
+            bottomPadding: 0
+            Label {
+                text: qsTr("Schedule")
+                font.bold: true
+                anchors.horizontalCenter: parent.horizontalCenter
+            }
+        }
+        ToolSeparator {}
+        Pane {
+            id: firewallTitleExpiry
+            topPadding: 0
+            bottomPadding: 0
+            Label {
+                text: qsTr("Expires")
                 font.bold: true
                 anchors.horizontalCenter: parent.horizontalCenter
             }
@@ -198,7 +240,7 @@ Pane {
                     currentIndex: proto
                     onCurrentIndexChanged: if (proto != currentIndex) proto = currentIndex
                     width: defaultFont.width * 7 + indicator.width
-                    model: [qsTr("Any"), "TCP", "UDP", "UDPLite"]
+                    model: [qsTr("Any"), "TCP", "UDP", "UDPLite", "SCTP", "DCCP", "ICMP", "ICMPv6"]
                     Component.onCompleted: firewallTitle1.implicitWidth = width
                 }
                 RowLayout {
@@ -214,6 +256,47 @@ Pane {
                         text: "..."
                         onClicked: fileDialog.open()
                     }
+                    TextField {
+                        Layout.fillWidth: true
+                        placeholderText: qsTr("Application")
+                        text: model.app
+                        onTextChanged: if (model.app != text) model.app = text
+                        selectByMouse: true
+                    }
+                    TextField {
+                        Layout.fillWidth: true
+                        placeholderText: qsTr("Command line")
+                        text: model.cmdline
+                        onTextChanged: if (model.cmdline != text) model.cmdline = text
+                        selectByMouse: true
+                    }
+                    TextField {
+                        Layout.fillWidth: true
+                        placeholderText: qsTr("Spawned by")
+                        text: model.parent
+                        onTextChanged: if (model.parent != text) model.parent = text
+                        selectByMouse: true
+                    }
+                    TextField {
+                        Layout.fillWidth: true
+                        placeholderText: qsTr("Unit or cgroup")
+                        text: model.unit
+                        onTextChanged: if (model.unit != text) model.unit = text
+                        selectByMouse: true
+                    }
+                    TextField {
+                        Layout.preferredWidth: defaultFont.width * 8
+                        placeholderText: "SHA-256"
+                        text: model.sha256
+                        onTextChanged: if (model.sha256 != text) model.sha256 = text
+                        selectByMouse: true
+                    }
+                    // pins the program as it is on disk now, again after upgrades
+                    RoundButton {
+                        text: "#"
+                        enabled: model.exe.length > 0
+                        onClicked: model.sha256 = backend.hash_program(model.exe)
+                    }
                     FileDialog {
                         id: fileDialog
                         title: qsTr("Please choose a program")
@@ -226,9 +309,41 @@ Pane {
                         }
                     }
                 }
+                Control {
+                    x: firewallTitleUser.x
+                    implicitWidth: ruleUser.width + userSlash.width + ruleGroup.width
+                    implicitHeight: ruleUser.height
+                    Component.onCompleted: firewallTitleUser.implicitWidth = width
+
+                    TextField {
+                        id: ruleUser
+                        width: defaultFont.width * 8
+                        selectByMouse: true
+                        horizontalAlignment: TextInput.AlignHCenter
+                        placeholderText: qsTr("User")
+                        text: model.user
+                        onTextChanged: if (model.user != text) model.user = text
+                    }
+                    Label {
+                        id: userSlash
+                        anchors.left: ruleUser.right
+                        text: " / "
+                        anchors.verticalCenter: parent.verticalCenter
+                    }
+                    TextField {
+                        id: ruleGroup
+                        anchors.left: userSlash.right
+                        width: defaultFont.width * 8
+                        selectByMouse: true
+                        horizontalAlignment: TextInput.AlignHCenter
+                        placeholderText: qsTr("Group")
+                        text: model.group
+                        onTextChanged: if (model.group != text) model.group = text
+                    }
+                }
                 Control {
                     x: firewallTitle3.x
-                    implicitWidth: addrIp.width + addrSlash.width + addrSubnetMask.width
+                    implicitWidth: addrIp.width + addrSlash.width + addrSubnetMask.width + ruleDomain.width
                     implicitHeight: addrIp.height
                     Component.onCompleted: firewallTitle3.implicitWidth = width
 
@@ -274,10 +389,22 @@ Pane {
                         text: model.mask
                         onTextChanged: parent.fixMaskLength()
                     }
+                    // a name it was resolved from, or *.<domain>
+                    TextField {
+                        id: ruleDomain
+                        anchors.left: addrSubnetMask.right
+                        width: defaultFont.width * 15
+                        selectByMouse: true
+                        placeholderText: qsTr("Domain")
+                        text: model.domain
+                        onTextChanged: if (model.domain != text) model.domain = text
+                    }
                 }
                 Control {
                     id: portRange
                     x: firewallTitle4.x
+                    // ICMP and ICMPv6 have types instead
+                    visible: proto < 6
                     implicitWidth: portRangeBegin.width + portHyphen.width + portRangeEnd.width
                     implicitHeight: portRangeBegin.height
                     Component.onCompleted: firewallTitle4.implicitWidth = width
@@ -334,6 +461,98 @@ Pane {
                         onEditingFinished: parent.fixPortRange()
                     }
                 }
+                Control {
+                    x: firewallTitle4.x
+                    visible: !portRange.visible
+                    implicitWidth: portRange.implicitWidth
+                    implicitHeight: icmpType.height
+
+                    TextField {
+                        id: icmpType
+                        width: font.pointSize * 5
+                        validator: RegExpValidator { regExp: /[0-9]{0,3}/ }
+                        selectByMouse: true
+                        horizontalAlignment: TextInput.AlignHCenter
+                        placeholderText: qsTr("Type")
+                        text: model.icmpType
+                        onTextChanged: if (model.icmpType != text) model.icmpType = text
+                    }
+                    Label {
+                        id: icmpSlash
+                        anchors.left: icmpType.right
+                        text: " / "
+                        anchors.verticalCenter: parent.verticalCenter
+                    }
+                    TextField {
+                        anchors.left: icmpSlash.right
+                        width: font.pointSize * 5
+                        validator: RegExpValidator { regExp: /[0-9]{0,3}/ }
+                        selectByMouse: true
+                        horizontalAlignment: TextInput.AlignHCenter
+                        placeholderText: qsTr("Code")
+                        enabled: icmpType.text.length > 0
+                        text: model.icmpCode
+                        onTextChanged: if (model.icmpCode != text) model.icmpCode = text
+                    }
+                }
+                // every day and all day if nothing is set
+                Button {
+                    id: ruleSchedule
+                    // the model of the row, hidden by the one of the days in the popup
+                    property var rule: model
+                    x: firewallTitleSchedule.x
+                    width: defaultFont.width * 14
+                    flat: true
+                    text: scheduleText(model.scheduleDays, model.scheduleTimes)
+                    onClicked: schedulePopup.open()
+                    Component.onCompleted: firewallTitleSchedule.implicitWidth = width
+
+                    Popup {
+                        id: schedulePopup
+                        y: parent.height
+
+                        ColumnLayout {
+                            Row {
+                                Repeater {
+                                    model: dayNames
+                                    CheckBox {
+                                        text: modelData
+                                        checked: (ruleSchedule.rule.scheduleDays & (1 << index)) != 0
+                                        onToggled: ruleSchedule.rule.scheduleDays ^= 1 << index
+                                    }
+                                }
+                            }
+                            TextField {
+                                Layout.fillWidth: true
+                                selectByMouse: true
+                                placeholderText: qsTr("Times, like 09:00-17:00, 22:00-06:00")
+                                text: ruleSchedule.rule.scheduleTimes
+                                onTextChanged: if (ruleSchedule.rule.scheduleTimes != text) {
+                                    ruleSchedule.rule.scheduleTimes = text
+                                }
+                            }
+                            TextField {
+                                Layout.fillWidth: true
+                                selectByMouse: true
+                                placeholderText: qsTr("UTC offset, like +02:00, local time if empty")
+                                text: ruleSchedule.rule.scheduleUtcOffset
+                                onTextChanged: if (ruleSchedule.rule.scheduleUtcOffset != text) {
+                                    ruleSchedule.rule.scheduleUtcOffset = text
+                                }
+                            }
+                        }
+                    }
+                }
+                // a local time, a duration, restart or pid N
+                TextField {
+                    x: firewallTitleExpiry.x
+                    width: defaultFont.width * 12
+                    selectByMouse: true
+                    placeholderText: qsTr("Never")
+                    text: model.expiry
+                    onTextChanged: if (model.expiry != text) model.expiry = text
+                    Component.onCompleted: firewallTitleExpiry.implicitWidth = width
+                }
                 ComboBox {
                     x: firewallTitle5.x
                     currentIndex: target
@@ -412,6 +631,57 @@ Pane {
         anchors.bottom: parent.bottom
         width: parent.width
         implicitHeight: applyBtn.height
+        Label {
+            text: qsTr("Profile: ")
+        }
+        // typing a new name creates a profile from the current rules
+        ComboBox {
+            id: profile
+            editable: true
+            enabled: backend.daemon_connected
+            model: backend.profiles
+            currentIndex: backend.profiles.indexOf(backend.active_profile)
+            onActivated: if (model[index] != backend.active_profile) {
+                backend.activate_profile(model[index])
+            }
+            onAccepted: if (editText != backend.active_profile) {
+                backend.activate_profile(editText)
+            }
+            Component.onCompleted: {
+                backend.profile_error.connect((err) => {
+                    errorPopup.message = qsTr("Failed to change profile:")
+                    errorPopup.error = err
+                    errorPopup.open()
+                    profile.currentIndex = backend.profiles.indexOf(backend.active_profile)
+                })
+            }
+        }
+        Button {
+            text: qsTr("Rename")
+            enabled: backend.daemon_connected
+            onClicked: renameProfilePopup.open()
+
+            Popup {
+                id: renameProfilePopup
+                y: -height
+                onOpened: {
+                    newProfileName.text = backend.active_profile
+                    newProfileName.forceActiveFocus()
+                }
+
+                TextField {
+                    id: newProfileName
+                    selectByMouse: true
+                    placeholderText: qsTr("New name")
+                    onAccepted: {
+                        if (text != backend.active_profile) {
+                            backend.rename_profile(text)
+                        }
+                        renameProfilePopup.close()
+                    }
+                }
+            }
+        }
         Label {
             text: qsTr("Default Target: ")
         }
@@ -426,8 +696,16 @@ Pane {
                 ListElement {
                     name: qsTr("Drop")
                 }
+                ListElement {
+                    name: qsTr("Ask")
+                }
+                ListElement {
+                    name: qsTr("Reject")
+                }
             }
             textRole: "name"
+            // Accept, Drop, Ask and Reject
+            readonly property int builtinTargets: 4
             Component.onCompleted: {
                 currentIndex = backend.default_target
 
@@ -442,11 +720,11 @@ Pane {
                 backend.rate_rules.dataChanged.connect((topLeft, bottomRight, roles) => {
                     console.assert(topLeft == bottomRight)
                     const name = backend.rate_rules.data(topLeft, Qt.UserRole)
-                    model.setProperty(2 + topLeft.row, "name", name.toString())
+                    model.setProperty(builtinTargets + topLeft.row, "name", name.toString())
                 })
                 backend.rate_rules.rowsRemoved.connect((_, first, last) => {
                     console.assert(first == last)
-                    model.remove(2 + first)
+                    model.remove(builtinTargets + first)
                 })
                 backend.rate_rules.rowsInserted.connect((_, first, last) => {
                     console.assert(first, last, model.count - 1)
@@ -102,6 +102,28 @@ Item {
                     }
                 }
                 ToolSeparator {}
+                Pane {
+                    id: logsTitleUser
+                    implicitWidth: defaultFont.width * 8
+                    padding: 0
+                    Label {
+                        text: "User"
+                        font.bold: true
+                        anchors.horizontalCenter: parent.horizontalCenter
+                    }
+                }
+                ToolSeparator {}
+                Pane {
+                    id: logsTitleUnit
+                    implicitWidth: defaultFont.width * 14
+                    padding: 0
+                    Label {
+                        text: "Unit"
+                        font.bold: true
+                        anchors.horizontalCenter: parent.horizontalCenter
+                    }
+                }
+                ToolSeparator {}
                 Pane {
                     id: logsTitle2
                     implicitWidth: defaultFont.width * 2
@@ -174,11 +196,57 @@ Item {
                         height: 40
                         color: if (model.dropped) { "red" } else { "green" }
                     }
-                    Label {
+                    Column {
                         x: logsTitle1.x
                         width: logsTitle1.width
                         clip: true
-                        text: model.exe
+                        anchors.verticalCenter: parent.verticalCenter
+                        // sandboxed and bundled programs are told by their apps
+                        Label {
+                            text: model.app.length > 0 ? model.app : model.exe
+                        }
+                        // the script of interpreters, or the arguments
+                        Label {
+                            visible: text.length > 0
+                            text: model.cmdline
+                            opacity: 0.7
+                            font.pointSize: defaultFont.font.pointSize * 0.8
+                        }
+                        // the program was replaced since it was pinned, maybe upgraded
+                        Row {
+                            visible: model.broken_pin != 0
+                            spacing: 4
+                            Label {
+                                text: qsTr("Changed since rule %1 pinned it").arg(model.broken_pin)
+                                color: "red"
+                                anchors.verticalCenter: parent.verticalCenter
+                            }
+                            Button {
+                                flat: true
+                                text: qsTr("Approve")
+                                onClicked: backend.approve_sha256(model.broken_pin, model.sha256)
+                            }
+                        }
+                        // the process tree, from the oldest ancestor
+                        Label {
+                            visible: text.length > 0
+                            text: model.ancestors
+                            opacity: 0.5
+                            font.pointSize: defaultFont.font.pointSize * 0.8
+                        }
+                    }
+                    Label {
+                        x: logsTitleUser.x
+                        width: logsTitleUser.width
+                        clip: true
+                        text: model.user
+                        anchors.verticalCenter: parent.verticalCenter
+                    }
+                    Label {
+                        x: logsTitleUnit.x
+                        width: logsTitleUnit.width
+                        clip: true
+                        text: model.unit
                         anchors.verticalCenter: parent.verticalCenter
                     }
                     Label {
@@ -187,12 +255,21 @@ Item {
                         font.pointSize: defaultFont.font.pointSize * 1.5
                         anchors.verticalCenter: parent.verticalCenter
                     }
-                    Label {
+                    // the name asked for, over the address it was resolved to
+                    Column {
                         x: logsTitle3.x
                         width: logsTitle3.width
                         clip: true
-                        text: model.addr
                         anchors.verticalCenter: parent.verticalCenter
+                        Label {
+                            visible: text.length > 0
+                            text: model.domain
+                        }
+                        Label {
+                            text: model.addr
+                            opacity: model.domain.length > 0 ? 0.7 : 1
+                            font.pointSize: defaultFont.font.pointSize * (model.domain.length > 0 ? 0.8 : 1)
+                        }
                     }
                     Label {
                         x: logsTitle4.x + (logsTitle4.width - width) / 2
@@ -108,6 +108,62 @@ ApplicationWindow {
             }
         }
     }
+    Popup {
+        id: askPopup
+        property real realY: Math.round((parent.height - height) / 2)
+        parent: Overlay.overlay
+        x: Math.round((parent.width - width) / 2)
+        y: realY
+        modal: true
+        closePolicy: Popup.NoAutoClose
+        visible: backend.asking
+        enter: Transition {
+            NumberAnimation {
+                property: "y"
+                easing.type: Easing.OutBack
+                from: 0
+                to: askPopup.realY
+                duration: 200
+            }
+        }
+        ColumnLayout {
+            anchors.fill: parent
+            Label {
+                Layout.alignment: Qt.AlignHCenter
+                text: backend.ask_input ? qsTr("Incoming connection") : qsTr("Outgoing connection")
+                font.bold: true
+            }
+            MenuSeparator {
+                Layout.fillWidth: true
+            }
+            Label {
+                text: backend.ask_exe
+                font.italic: true
+            }
+            Label {
+                text: backend.ask_protocol + " " + backend.ask_addr
+            }
+            RowLayout {
+                Button {
+                    text: qsTr("Allow Once")
+                    onClicked: backend.answer_ask(0)
+                }
+                Button {
+                    text: qsTr("Allow")
+                    onClicked: backend.answer_ask(1)
+                }
+                Button {
+                    text: qsTr("Deny")
+                    onClicked: backend.answer_ask(2)
+                }
+                Button {
+                    text: qsTr("Allow Forever")
+                    highlighted: true
+                    onClicked: backend.answer_ask(3)
+                }
+            }
+        }
+    }
     Popup {
         id: errorPopup
         property string message: ""
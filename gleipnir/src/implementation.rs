@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::env;
 use std::io;
@@ -13,12 +13,14 @@ use std::process::Command;
 use std::sync::atomic::Ordering;
 use std::thread;
 
+use chrono::{Local, NaiveDateTime, TimeZone};
 use failure::{self, Fail};
 use futures::future::FutureExt;
 use gleipnir_interface::{
-    unixtransport, DaemonClient, Device, PackageReport, Proto, RateLimitRule, Rule, RuleTarget,
-    Rules,
+    self, unixtransport, AskAnswer, DaemonClient, Device, Expiry, IcmpType, PackageReport,
+    Profiles, Proto, RateLimitRule, Rule, RuleTarget, Rules, Schedule,
 };
+use nix::unistd::{Gid, Group, Uid, User};
 use qmetaobject::*;
 use tarpc;
 use tokio::runtime::Runtime;
@@ -28,6 +30,40 @@ use crate::implementation;
 use crate::listmodel::{MutListItem, MutListModel};
 use crate::monitor;
 
+/// Accept, Drop, Ask and Reject, rate limit rules come after them
+const BUILTIN_TARGETS: usize = 4;
+/// Expiry times are shown and entered in local time
+const EXPIRY_TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+fn target_to_index(target: RuleTarget) -> usize {
+    match target {
+        RuleTarget::Accept => 0,
+        RuleTarget::Drop => 1,
+        RuleTarget::Ask => 2,
+        RuleTarget::Reject => 3,
+        RuleTarget::RateLimit(n) => n + BUILTIN_TARGETS,
+    }
+}
+
+/// The app of sandboxed or bundled programs, their executables say little
+fn program_name(report: &PackageReport) -> &str {
+    if report.app.is_empty() {
+        &report.exe
+    } else {
+        &report.app
+    }
+}
+
+fn index_to_target(index: usize) -> RuleTarget {
+    match index {
+        0 => RuleTarget::Accept,
+        1 => RuleTarget::Drop,
+        2 => RuleTarget::Ask,
+        3 => RuleTarget::Reject,
+        n => RuleTarget::RateLimit(n - BUILTIN_TARGETS),
+    }
+}
+
 #[derive(QGadget, SimpleListItem, Default, Debug)]
 pub struct QRule {
     pub device: qt_property!(usize),
@@ -38,6 +74,33 @@ pub struct QRule {
     pub addr: qt_property!(QString),
     pub mask: qt_property!(u8),
     pub target: qt_property!(usize),
+    /// Empty if any
+    pub icmp_type: qt_property!(QString),
+    pub icmp_code: qt_property!(QString),
+    /// Empty if any
+    pub cmdline: qt_property!(QString),
+    /// Name or ID, empty if any
+    pub user: qt_property!(QString),
+    pub group: qt_property!(QString),
+    /// Empty if any
+    pub parent: qt_property!(QString),
+    /// Empty if not pinned
+    pub sha256: qt_property!(QString),
+    /// Empty if any
+    pub app: qt_property!(QString),
+    /// Unit name or cgroup, empty if any
+    pub unit: qt_property!(QString),
+    /// Empty if any
+    pub domain: qt_property!(QString),
+    /// Bits of the days of the week from Sunday, every day if none is set
+    pub schedule_days: qt_property!(u8),
+    /// Like `09:00-17:00, 22:00-06:00`, empty if all day
+    pub schedule_times: qt_property!(QString),
+    /// Like `+02:00`, empty for the local time
+    pub schedule_utc_offset: qt_property!(QString),
+    /// A local time like `2020-01-01 18:00`, a duration like `30m`, `restart` or `pid 1234`,
+    /// empty if never
+    pub expiry: qt_property!(QString),
 }
 
 impl From<&Rule> for QRule {
@@ -52,6 +115,10 @@ impl From<&Rule> for QRule {
             Some(Proto::Tcp) => 1,
             Some(Proto::Udp) => 2,
             Some(Proto::UdpLite) => 3,
+            Some(Proto::Sctp) => 4,
+            Some(Proto::Dccp) => 5,
+            Some(Proto::Icmp) => 6,
+            Some(Proto::IcmpV6) => 7,
         };
         let exe = rule
             .exe
@@ -68,11 +135,106 @@ impl From<&Rule> for QRule {
             .subnet
             .map(|subnet| (subnet.0.to_string().into(), subnet.1))
             .unwrap_or_default();
-        let target = match rule.target {
-            RuleTarget::Accept => 0,
-            RuleTarget::Drop => 1,
-            RuleTarget::RateLimit(n) => n + 2,
+        let target = target_to_index(rule.target);
+        let (icmp_type, icmp_code) = match rule.icmp {
+            Some(icmp) => (
+                icmp.kind.to_string().into(),
+                icmp.code.map(|c| c.to_string()).unwrap_or_default().into(),
+            ),
+            None => Default::default(),
         };
+        let cmdline = rule
+            .cmdline
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let user = rule
+            .user
+            .map(|uid| match User::from_uid(Uid::from_raw(uid)) {
+                Ok(Some(user)) => user.name,
+                _ => uid.to_string(),
+            })
+            .unwrap_or_default()
+            .into();
+        let group = rule
+            .group
+            .map(|gid| match Group::from_gid(Gid::from_raw(gid)) {
+                Ok(Some(group)) => group.name,
+                _ => gid.to_string(),
+            })
+            .unwrap_or_default()
+            .into();
+        let parent = rule
+            .parent
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let sha256 = rule
+            .sha256
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let app = rule
+            .app
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let unit = rule
+            .unit
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let domain = rule
+            .domain
+            .as_ref()
+            .map(|s| s.as_str())
+            .unwrap_or_default()
+            .into();
+        let (schedule_days, schedule_times, schedule_utc_offset) = match &rule.schedule {
+            Some(schedule) => (
+                schedule.days,
+                schedule
+                    .times
+                    .iter()
+                    .map(|&(start, end)| {
+                        format!(
+                            "{:02}:{:02}-{:02}:{:02}",
+                            start / 60,
+                            start % 60,
+                            end / 60,
+                            end % 60
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ")
+                    .into(),
+                schedule
+                    .utc_offset
+                    .map(|offset| {
+                        let sign = if offset < 0 { '-' } else { '+' };
+                        format!("{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60)
+                    })
+                    .unwrap_or_default()
+                    .into(),
+            ),
+            None => Default::default(),
+        };
+        let expiry = match rule.expiry {
+            Some(Expiry::At(time)) => Local
+                .timestamp(time as i64, 0)
+                .format(EXPIRY_TIME_FORMAT)
+                .to_string(),
+            Some(Expiry::For(secs)) => format!("{}s", secs),
+            Some(Expiry::Restart) => "restart".to_owned(),
+            Some(Expiry::ProcessExit { pid, .. }) => format!("pid {}", pid),
+            None => String::new(),
+        }
+        .into();
         Self {
             device,
             proto,
@@ -82,6 +244,20 @@ impl From<&Rule> for QRule {
             addr,
             mask,
             target,
+            icmp_type,
+            icmp_code,
+            cmdline,
+            user,
+            group,
+            parent,
+            sha256,
+            app,
+            unit,
+            domain,
+            schedule_days,
+            schedule_times,
+            schedule_utc_offset,
+            expiry,
         }
     }
 }
@@ -92,6 +268,18 @@ pub enum InvalidQRule {
     PortRange { begin: u16, end: u16 },
     #[fail(display = "Invalid address: {}", _0)]
     Address(#[fail(cause)] AddrParseError),
+    #[fail(display = "Invalid ICMP type or code: {}", _0)]
+    IcmpType(String),
+    #[fail(display = "Unknown user: {}", _0)]
+    User(String),
+    #[fail(display = "Unknown group: {}", _0)]
+    Group(String),
+    #[fail(display = "Invalid SHA-256: {}", _0)]
+    Sha256(String),
+    #[fail(display = "Invalid schedule: {}", _0)]
+    Schedule(String),
+    #[fail(display = "Invalid expiry: {}", _0)]
+    Expiry(String),
 }
 
 impl From<AddrParseError> for InvalidQRule {
@@ -114,14 +302,142 @@ impl TryFrom<&QRule> for Rule {
             1 => Some(Proto::Tcp),
             2 => Some(Proto::Udp),
             3 => Some(Proto::UdpLite),
+            4 => Some(Proto::Sctp),
+            5 => Some(Proto::Dccp),
+            6 => Some(Proto::Icmp),
+            7 => Some(Proto::IcmpV6),
             _ => unreachable!(),
         };
+        let is_icmp = proto.map(|p| p.is_icmp()).unwrap_or(false);
         let exe = if !qrule.exe.to_slice().is_empty() {
             Some(String::from_utf16(qrule.exe.to_slice()).unwrap())
         } else {
             None
         };
+        let cmdline = if !qrule.cmdline.to_slice().is_empty() {
+            Some(String::from_utf16(qrule.cmdline.to_slice()).unwrap())
+        } else {
+            None
+        };
+        let parent = if !qrule.parent.to_slice().is_empty() {
+            Some(String::from_utf16(qrule.parent.to_slice()).unwrap())
+        } else {
+            None
+        };
+        let app = if !qrule.app.to_slice().is_empty() {
+            Some(String::from_utf16(qrule.app.to_slice()).unwrap())
+        } else {
+            None
+        };
+        let unit = if !qrule.unit.to_slice().is_empty() {
+            Some(String::from_utf16(qrule.unit.to_slice()).unwrap())
+        } else {
+            None
+        };
+        // names in DNS answers are lowercase, without the trailing dot
+        let domain = String::from_utf16_lossy(qrule.domain.to_slice());
+        let domain = match domain.trim().trim_end_matches('.') {
+            "" => None,
+            s => Some(s.to_lowercase()),
+        };
+        // minutes since midnight, 24:00 ends the day
+        let parse_time = |s: &str| {
+            let mut parts = s.trim().splitn(2, ':');
+            let hour: u16 = parts.next()?.parse().ok()?;
+            let minute: u16 = parts.next()?.parse().ok()?;
+            if (hour < 24 && minute < 60) || (hour == 24 && minute == 0) {
+                Some(hour * 60 + minute)
+            } else {
+                None
+            }
+        };
+        let times = String::from_utf16_lossy(qrule.schedule_times.to_slice());
+        let times = times
+            .split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(|range| {
+                let mut parts = range.splitn(2, '-');
+                match (
+                    parts.next().and_then(parse_time),
+                    parts.next().and_then(parse_time),
+                ) {
+                    (Some(start), Some(end)) => Ok((start, end)),
+                    _ => Err(InvalidQRule::Schedule(range.trim().into())),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let utc_offset = String::from_utf16_lossy(qrule.schedule_utc_offset.to_slice());
+        let utc_offset = match utc_offset.trim() {
+            "" => None,
+            s => {
+                let (sign, offset) = match s.as_bytes()[0] {
+                    b'-' => (-1, &s[1..]),
+                    b'+' => (1, &s[1..]),
+                    _ => (1, s),
+                };
+                let mut parts = offset.splitn(2, ':');
+                let hours = parts.next().and_then(|h| h.parse::<u8>().ok());
+                let minutes = parts.next().map_or(Some(0), |m| m.parse::<u8>().ok());
+                match (hours, minutes) {
+                    (Some(h), Some(m)) if h <= 14 && m < 60 => {
+                        Some(sign * (i32::from(h) * 60 + i32::from(m)))
+                    }
+                    _ => return Err(InvalidQRule::Schedule(s.into())),
+                }
+            }
+        };
+        let schedule = if qrule.schedule_days == 0 && times.is_empty() {
+            None
+        } else {
+            Some(Schedule {
+                days: qrule.schedule_days,
+                times,
+                utc_offset,
+            })
+        };
+        let expiry = String::from_utf16_lossy(qrule.expiry.to_slice());
+        let expiry = match expiry.trim() {
+            "" => None,
+            "restart" => Some(Expiry::Restart),
+            s if s.starts_with("pid ") => match s[4..].trim().parse() {
+                Ok(pid) => Some(Expiry::ProcessExit {
+                    pid,
+                    start_time: None,
+                }),
+                Err(_) => return Err(InvalidQRule::Expiry(s.into())),
+            },
+            s => {
+                let duration = match s.as_bytes()[s.len() - 1] {
+                    b's' => Some(1),
+                    b'm' => Some(60),
+                    b'h' => Some(60 * 60),
+                    b'd' => Some(24 * 60 * 60),
+                    _ => None,
+                }
+                .and_then(|unit| Some((s[..s.len() - 1].trim().parse::<u64>().ok()?, unit)))
+                .and_then(|(n, unit)| n.checked_mul(unit));
+                match duration {
+                    Some(secs) => Some(Expiry::For(secs)),
+                    None => NaiveDateTime::parse_from_str(s, EXPIRY_TIME_FORMAT)
+                        .ok()
+                        .and_then(|time| Local.from_local_datetime(&time).single())
+                        .map(|time| Expiry::At(time.timestamp() as u64))
+                        .ok_or_else(|| InvalidQRule::Expiry(s.into()))
+                        .map(Some)?,
+                }
+            }
+        };
+        let sha256 = String::from_utf16_lossy(qrule.sha256.to_slice());
+        let sha256 = match sha256.trim() {
+            "" => None,
+            s if s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Some(s.to_ascii_lowercase())
+            }
+            _ => return Err(InvalidQRule::Sha256(sha256)),
+        };
         let port = match (qrule.port_begin, qrule.port_end) {
+            // ICMP has no port, the fields are hidden
+            _ if is_icmp => None,
             (0, 0) => None,
             (port, 0) => Some(RangeInclusive::new(port, port)),
             (begin, end) if begin > end => {
@@ -136,17 +452,57 @@ impl TryFrom<&QRule> for Rule {
             let addr = String::from_utf16_lossy(qaddr).parse()?;
             Some((addr, qrule.mask))
         };
-        let target = match qrule.target {
-            0 => RuleTarget::Accept,
-            1 => RuleTarget::Drop,
-            n => RuleTarget::RateLimit(n - 2),
+        let target = index_to_target(qrule.target);
+        let parse_u8 = |s: &QString| {
+            let s = String::from_utf16_lossy(s.to_slice());
+            if s.is_empty() {
+                Ok(None)
+            } else {
+                s.parse().map(Some).map_err(|_| InvalidQRule::IcmpType(s))
+            }
+        };
+        let icmp = match parse_u8(&qrule.icmp_type)? {
+            Some(kind) if is_icmp => Some(IcmpType {
+                kind,
+                code: parse_u8(&qrule.icmp_code)?,
+            }),
+            _ => None,
+        };
+        let user = String::from_utf16_lossy(qrule.user.to_slice());
+        let user = match user.parse() {
+            _ if user.is_empty() => None,
+            Ok(uid) => Some(uid),
+            Err(_) => match User::from_name(&user) {
+                Ok(Some(r)) => Some(r.uid.as_raw()),
+                _ => return Err(InvalidQRule::User(user)),
+            },
+        };
+        let group = String::from_utf16_lossy(qrule.group.to_slice());
+        let group = match group.parse() {
+            _ if group.is_empty() => None,
+            Ok(gid) => Some(gid),
+            Err(_) => match Group::from_name(&group) {
+                Ok(Some(r)) => Some(r.gid.as_raw()),
+                _ => return Err(InvalidQRule::Group(group)),
+            },
         };
         Ok(Self {
             device,
             proto,
             exe,
+            sha256,
+            app,
+            cmdline,
+            user,
+            group,
+            parent,
+            unit,
             port,
             subnet,
+            domain,
+            icmp,
+            schedule,
+            expiry,
             target,
         })
     }
@@ -163,6 +519,20 @@ impl MutListItem for QRule {
             5 => QMetaType::to_qvariant(&self.addr),
             6 => QMetaType::to_qvariant(&self.mask),
             7 => QMetaType::to_qvariant(&self.target),
+            8 => QMetaType::to_qvariant(&self.icmp_type),
+            9 => QMetaType::to_qvariant(&self.icmp_code),
+            10 => QMetaType::to_qvariant(&self.cmdline),
+            11 => QMetaType::to_qvariant(&self.user),
+            12 => QMetaType::to_qvariant(&self.group),
+            13 => QMetaType::to_qvariant(&self.parent),
+            14 => QMetaType::to_qvariant(&self.sha256),
+            15 => QMetaType::to_qvariant(&self.app),
+            16 => QMetaType::to_qvariant(&self.unit),
+            17 => QMetaType::to_qvariant(&self.domain),
+            18 => QMetaType::to_qvariant(&self.schedule_days),
+            19 => QMetaType::to_qvariant(&self.schedule_times),
+            20 => QMetaType::to_qvariant(&self.schedule_utc_offset),
+            21 => QMetaType::to_qvariant(&self.expiry),
             _ => QVariant::default(),
         }
     }
@@ -176,6 +546,20 @@ impl MutListItem for QRule {
             5 => <_>::from_qvariant(value.clone()).map(|v| self.addr = v),
             6 => <_>::from_qvariant(value.clone()).map(|v| self.mask = v),
             7 => <_>::from_qvariant(value.clone()).map(|v| self.target = v),
+            8 => <_>::from_qvariant(value.clone()).map(|v| self.icmp_type = v),
+            9 => <_>::from_qvariant(value.clone()).map(|v| self.icmp_code = v),
+            10 => <_>::from_qvariant(value.clone()).map(|v| self.cmdline = v),
+            11 => <_>::from_qvariant(value.clone()).map(|v| self.user = v),
+            12 => <_>::from_qvariant(value.clone()).map(|v| self.group = v),
+            13 => <_>::from_qvariant(value.clone()).map(|v| self.parent = v),
+            14 => <_>::from_qvariant(value.clone()).map(|v| self.sha256 = v),
+            15 => <_>::from_qvariant(value.clone()).map(|v| self.app = v),
+            16 => <_>::from_qvariant(value.clone()).map(|v| self.unit = v),
+            17 => <_>::from_qvariant(value.clone()).map(|v| self.domain = v),
+            18 => <_>::from_qvariant(value.clone()).map(|v| self.schedule_days = v),
+            19 => <_>::from_qvariant(value.clone()).map(|v| self.schedule_times = v),
+            20 => <_>::from_qvariant(value.clone()).map(|v| self.schedule_utc_offset = v),
+            21 => <_>::from_qvariant(value.clone()).map(|v| self.expiry = v),
             _ => None,
         }
         .is_some()
@@ -190,6 +574,20 @@ impl MutListItem for QRule {
             QByteArray::from("addr"),
             QByteArray::from("mask"),
             QByteArray::from("target"),
+            QByteArray::from("icmpType"),
+            QByteArray::from("icmpCode"),
+            QByteArray::from("cmdline"),
+            QByteArray::from("user"),
+            QByteArray::from("group"),
+            QByteArray::from("parent"),
+            QByteArray::from("sha256"),
+            QByteArray::from("app"),
+            QByteArray::from("unit"),
+            QByteArray::from("domain"),
+            QByteArray::from("scheduleDays"),
+            QByteArray::from("scheduleTimes"),
+            QByteArray::from("scheduleUtcOffset"),
+            QByteArray::from("expiry"),
         ]
     }
 }
@@ -221,6 +619,26 @@ pub struct Backend {
     pub charts: qt_property!(QVariantList; NOTIFY charts_changed),
     pub charts_changed: qt_signal!(),
     pub chart_x_size: qt_property!(usize),
+    pub asking: qt_property!(bool; NOTIFY ask_changed),
+    pub ask_exe: qt_property!(QString; NOTIFY ask_changed),
+    pub ask_addr: qt_property!(QString; NOTIFY ask_changed),
+    pub ask_protocol: qt_property!(QString; NOTIFY ask_changed),
+    pub ask_input: qt_property!(bool; NOTIFY ask_changed),
+    pub ask_changed: qt_signal!(),
+    pub answer_ask: qt_method!(fn(&mut self, answer: usize)),
+    /// Empty if the file can't be read
+    pub hash_program: qt_method!(fn(&self, path: QString) -> QString),
+    pub approve_sha256: qt_method!(fn(&mut self, rule: usize, sha256: QString)),
+    /// Names of the profiles, sorted
+    pub profiles: qt_property!(QVariantList; NOTIFY profiles_changed),
+    pub active_profile: qt_property!(QString; NOTIFY profiles_changed),
+    pub profiles_changed: qt_signal!(),
+    /// Creates the profile from the current rules if it doesn't exist
+    pub activate_profile: qt_method!(fn(&mut self, name: QString)),
+    /// Renames the active profile
+    pub rename_profile: qt_method!(fn(&mut self, new_name: QString)),
+    pub profile_error: qt_signal!(error: QString),
+    pending_asks: VecDeque<(u64, PackageReport)>,
     current_traffic: HashMap<String, ProgramStatus>,
     traffic_history: HashMap<String, Vec<u32>>,
     // prev_proc_on_chart: Vec<String>,
@@ -264,6 +682,22 @@ impl Backend {
             charts: Default::default(),
             charts_changed: Default::default(),
             chart_x_size: 80,
+            asking: false,
+            ask_exe: Default::default(),
+            ask_addr: Default::default(),
+            ask_protocol: Default::default(),
+            ask_input: false,
+            ask_changed: Default::default(),
+            answer_ask: Default::default(),
+            hash_program: Default::default(),
+            approve_sha256: Default::default(),
+            profiles: Default::default(),
+            active_profile: Default::default(),
+            profiles_changed: Default::default(),
+            activate_profile: Default::default(),
+            rename_profile: Default::default(),
+            profile_error: Default::default(),
+            pending_asks: Default::default(),
             current_traffic: Default::default(),
             traffic_history: Default::default(),
             // prev_proc_on_chart: vec![String::default(); 5],
@@ -284,11 +718,7 @@ impl Backend {
         };
         let rate_rules = (&**self.rate_rules.borrow()).to_vec();
 
-        let default_target = match self.default_target {
-            0 => RuleTarget::Accept,
-            1 => RuleTarget::Drop,
-            n => RuleTarget::RateLimit(n - 2),
-        };
+        let default_target = index_to_target(self.default_target);
 
         let rules = Rules {
             rules,
@@ -380,9 +810,34 @@ impl Backend {
                     .expect("QObject doesn't exist");
             });
 
+            let ptr = QPointer::from(&*self);
+            let on_ask_callback = queued_callback(move |(id, report)| {
+                ptr.as_ref()
+                    .map(|p| {
+                        let mutp = unsafe { &mut *(p as *const _ as *mut implementation::Backend) };
+                        mutp.on_ask(id, report);
+                    })
+                    .expect("QObject doesn't exist");
+            });
+
+            let ptr = QPointer::from(&*self);
+            let on_profiles_updated_callback = queued_callback(move |profiles| {
+                ptr.as_ref()
+                    .map(|p| {
+                        let mutp = unsafe { &mut *(p as *const _ as *mut implementation::Backend) };
+                        mutp.on_profiles_updated(profiles);
+                    })
+                    .expect("QObject doesn't exist");
+            });
+
             thread::spawn(|| {
-                monitor::run(on_packages_callback, on_rules_updated_callback)
-                    .expect("Failed to start monitor");
+                monitor::run(
+                    on_packages_callback,
+                    on_rules_updated_callback,
+                    on_ask_callback,
+                    on_profiles_updated_callback,
+                )
+                .expect("Failed to start monitor");
             });
             while !monitor::MONITOR_RUNNING.load(Ordering::Acquire) {}
         }
@@ -458,24 +913,142 @@ impl Backend {
         // TODO: impl extend_from_slice for SimpleListModel
         for log in &logs {
             self_logs.push(log.into());
+            let program = program_name(log);
             let status = self
                 .current_traffic
-                .entry(log.exe.clone())
-                .or_insert_with(|| ProgramStatus::new(&log.exe));
+                .entry(program.to_owned())
+                .or_insert_with(|| ProgramStatus::new(program));
             *status += log;
         }
     }
     pub fn on_rules_updated(&mut self, rules: Rules) {
         let new_rules = rules.rules.iter().map(|rule| rule.into()).collect();
         self.rules.borrow_mut().reset_data(new_rules);
-        self.default_target = match rules.default_target {
-            RuleTarget::Accept => 0,
-            RuleTarget::Drop => 1,
-            RuleTarget::RateLimit(n) => n + 2,
-        };
+        self.default_target = target_to_index(rules.default_target);
         self.rate_rules.borrow_mut().reset_data(rules.rate_rules);
         self.default_target_changed();
     }
+    pub fn on_profiles_updated(&mut self, profiles: Profiles) {
+        self.profiles = QVariantList::from_iter(
+            profiles
+                .names
+                .iter()
+                .map(|name| QString::from(name.as_str())),
+        );
+        self.active_profile = profiles.active.into();
+        self.profiles_changed();
+    }
+    pub fn on_ask(&mut self, id: u64, report: PackageReport) {
+        self.pending_asks.push_back((id, report));
+        if !self.asking {
+            self.show_next_ask();
+        }
+    }
+    pub fn answer_ask(&mut self, answer: usize) {
+        let answer = match answer {
+            0 => AskAnswer::AllowOnce,
+            1 => AskAnswer::Allow,
+            2 => AskAnswer::Deny,
+            3 => AskAnswer::AllowForever,
+            _ => unreachable!(),
+        };
+        let (id, _) = match self.pending_asks.pop_front() {
+            Some(r) => r,
+            None => return,
+        };
+        if let Some(client) = self.client.as_mut() {
+            let r: Result<(), io::Error> = self.runtime.block_on(async {
+                if !client.unlock(tarpc::context::current()).await? {
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                client
+                    .answer_ask(tarpc::context::current(), id, answer)
+                    .await
+            });
+            if let Err(e) = r {
+                dbg!(e);
+            }
+        }
+        self.show_next_ask();
+    }
+    pub fn hash_program(&self, path: QString) -> QString {
+        gleipnir_interface::sha256_file(String::from_utf16_lossy(path.to_slice()))
+            .unwrap_or_default()
+            .into()
+    }
+    /// `rule` starts from 1, like `QPackageLog::broken_pin`
+    pub fn approve_sha256(&mut self, rule: usize, sha256: QString) {
+        let sha256 = String::from_utf16_lossy(sha256.to_slice());
+        if let Some(client) = self.client.as_mut() {
+            let r: Result<(), io::Error> = self.runtime.block_on(async {
+                if !client.unlock(tarpc::context::current()).await? {
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                client
+                    .approve_sha256(tarpc::context::current(), rule - 1, sha256)
+                    .await
+            });
+            if let Err(e) = r {
+                dbg!(e);
+            }
+        }
+    }
+    pub fn activate_profile(&mut self, name: QString) {
+        let name = String::from_utf16_lossy(name.to_slice()).trim().to_owned();
+        if let Some(client) = self.client.as_mut() {
+            let r: Result<bool, io::Error> = self.runtime.block_on(async {
+                if !client.unlock(tarpc::context::current()).await? {
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                client
+                    .activate_profile(tarpc::context::current(), name.clone())
+                    .await
+            });
+            match r {
+                Ok(true) => (),
+                Ok(false) => self.profile_error(format!("Can't activate {}", name).into()),
+                Err(e) => self.profile_error(e.to_string().into()),
+            }
+        }
+    }
+    pub fn rename_profile(&mut self, new_name: QString) {
+        let name = String::from_utf16_lossy(self.active_profile.to_slice());
+        let new_name = String::from_utf16_lossy(new_name.to_slice())
+            .trim()
+            .to_owned();
+        if let Some(client) = self.client.as_mut() {
+            let r: Result<bool, io::Error> = self.runtime.block_on(async {
+                if !client.unlock(tarpc::context::current()).await? {
+                    return Err(io::ErrorKind::PermissionDenied.into());
+                }
+                client
+                    .rename_profile(tarpc::context::current(), name, new_name.clone())
+                    .await
+            });
+            match r {
+                Ok(true) => (),
+                Ok(false) => self.profile_error(format!("Can't rename to {}", new_name).into()),
+                Err(e) => self.profile_error(e.to_string().into()),
+            }
+        }
+    }
+    fn show_next_ask(&mut self) {
+        self.asking = match self.pending_asks.front() {
+            Some((_, report)) => {
+                self.ask_exe = program_name(report).into();
+                self.ask_addr = match &report.domain {
+                    Some(domain) => format!("{} ({})", domain, report.addr),
+                    None => report.addr.to_string(),
+                }
+                .into();
+                self.ask_protocol = report.protocol.to_string().into();
+                self.ask_input = report.device.is_input();
+                true
+            }
+            None => false,
+        };
+        self.ask_changed();
+    }
 }
 
 #[derive(QGadget, Default, Clone)]
@@ -489,10 +1062,24 @@ pub struct QPackageLog {
     pub dropped: bool,
     pub input: bool,
     pub exe: QString,
+    /// Empty for programs not sandboxed or bundled
+    pub app: QString,
+    pub cmdline: QString,
+    pub user: QString,
+    /// The systemd unit, empty if it's in none
+    pub unit: QString,
+    /// Names of the ancestors, from the oldest one
+    pub ancestors: QString,
+    /// Empty if no rule pins the executable
+    pub sha256: QString,
     pub protocol: QString,
     pub addr: QString,
+    /// Empty if the name of the address is unknown
+    pub domain: QString,
     pub len: usize,
     pub matched_rule: usize,
+    /// 0 if the executable matches the rules pinning it
+    pub broken_pin: usize,
 }
 
 impl From<&'_ PackageReport> for QPackageLog {
@@ -501,10 +1088,25 @@ impl From<&'_ PackageReport> for QPackageLog {
             dropped: v.dropped,
             input: v.device.is_input(),
             exe: (&*v.exe).into(),
+            app: (&*v.app).into(),
+            cmdline: (&*v.cmdline).into(),
+            user: (&*v.user).into(),
+            unit: (&*v.unit).into(),
+            ancestors: v
+                .ancestors
+                .iter()
+                .rev()
+                .map(|exe| exe.rsplit('/').next().unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join(" › ")
+                .into(),
             protocol: v.protocol.to_string().into(),
             addr: v.addr.to_string().into(),
+            domain: v.domain.as_deref().unwrap_or_default().into(),
             len: v.len,
             matched_rule: v.matched_rule.map(|x| x + 1).unwrap_or(0),
+            sha256: v.sha256.as_deref().unwrap_or_default().into(),
+            broken_pin: v.broken_pin.map(|x| x + 1).unwrap_or(0),
         }
     }
 }
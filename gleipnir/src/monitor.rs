@@ -6,7 +6,7 @@ use futures::{
     future::{self, Ready},
     prelude::*,
 };
-use gleipnir_interface::{unixtransport, Monitor, PackageReport, Rules};
+use gleipnir_interface::{unixtransport, Monitor, PackageReport, Profiles, Rules};
 use tarpc::rpc::context::Context;
 use tarpc::server::Channel;
 use tokio_serde::formats::Bincode;
@@ -14,22 +14,30 @@ use tokio_serde::formats::Bincode;
 pub static MONITOR_RUNNING: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
-struct MyMonitor<F0, F1>
+struct MyMonitor<F0, F1, F2, F3>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn((u64, PackageReport)) + Send + Sync + Clone + 'static,
+    F3: Fn(Profiles) + Send + Sync + Clone + 'static,
 {
     on_packages: F0,
     on_rules_updated: F1,
+    on_ask: F2,
+    on_profiles_updated: F3,
 }
 
-impl<F0, F1> Monitor for MyMonitor<F0, F1>
+impl<F0, F1, F2, F3> Monitor for MyMonitor<F0, F1, F2, F3>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn((u64, PackageReport)) + Send + Sync + Clone + 'static,
+    F3: Fn(Profiles) + Send + Sync + Clone + 'static,
 {
     type OnPackagesFut = Ready<()>;
     type OnRulesUpdatedFut = Ready<()>;
+    type OnAskFut = Ready<()>;
+    type OnProfilesUpdatedFut = Ready<()>;
     fn on_packages(self, _: Context, logs: Vec<PackageReport>) -> Self::OnPackagesFut {
         (self.on_packages)(logs);
         future::ready(())
@@ -38,12 +46,27 @@ where
         (self.on_rules_updated)(rules);
         future::ready(())
     }
+    fn on_ask(self, _: Context, id: u64, report: PackageReport) -> Self::OnAskFut {
+        (self.on_ask)((id, report));
+        future::ready(())
+    }
+    fn on_profiles_updated(self, _: Context, profiles: Profiles) -> Self::OnProfilesUpdatedFut {
+        (self.on_profiles_updated)(profiles);
+        future::ready(())
+    }
 }
 
-pub fn run<F0, F1>(on_packages: F0, on_rules_updated: F1) -> Result<(), std::io::Error>
+pub fn run<F0, F1, F2, F3>(
+    on_packages: F0,
+    on_rules_updated: F1,
+    on_ask: F2,
+    on_profiles_updated: F3,
+) -> Result<(), std::io::Error>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn((u64, PackageReport)) + Send + Sync + Clone + 'static,
+    F3: Fn(Profiles) + Send + Sync + Clone + 'static,
 {
     let addr = std::path::PathBuf::from("/tmp/gleipnir");
     if addr.exists() {
@@ -64,6 +87,8 @@ where
                 let server = MyMonitor {
                     on_packages: on_packages.clone(),
                     on_rules_updated: on_rules_updated.clone(),
+                    on_ask: on_ask.clone(),
+                    on_profiles_updated: on_profiles_updated.clone(),
                 };
                 channel.respond_with(server.serve()).execute()
             })
@@ -0,0 +1,146 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use gleipnir_interface::{AskAnswer, PackageReport};
+
+/// Packets are dropped if nobody answers in time
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Later packets of the same question are dropped instead of being held
+const MAX_HELD_PACKETS: usize = 64;
+
+/// Question ids are unique between workers
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct Answer {
+    pub id: u64,
+    pub exe: String,
+    /// `None` if nobody answered
+    pub answer: Option<AskAnswer>,
+}
+
+/// Sends answers to every worker, `Allow` and `Deny` are remembered by all of them
+#[derive(Clone)]
+pub struct AnswerSender(Vec<Sender<Answer>>);
+
+impl AnswerSender {
+    pub fn new(workers: usize) -> (Self, Vec<Receiver<Answer>>) {
+        let (senders, receivers) = (0..workers).map(|_| crossbeam_channel::unbounded()).unzip();
+        (AnswerSender(senders), receivers)
+    }
+
+    pub fn send(&self, answer: Answer) {
+        for sender in &self.0[1..] {
+            sender.send(answer.clone()).expect("packets service dead");
+        }
+        self.0[0].send(answer).expect("packets service dead");
+    }
+}
+
+/// A new question for monitors
+pub struct Ask {
+    pub id: u64,
+    pub report: PackageReport,
+    /// Of the rules which matched the report, `None` if unknown
+    pub generation: Option<u32>,
+}
+
+/// Ids of questions not held by `PendingVerdicts`
+pub fn next_id() -> u64 {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct Question {
+    flow: u64,
+    pub report: PackageReport,
+    pub packets: Vec<nfq::Message>,
+    deadline: Instant,
+}
+
+pub enum Hold {
+    /// A new question, should be sent to monitors
+    New(u64, PackageReport),
+    /// Same flow as a pending question, the packet is waiting with it
+    Joined,
+    /// Too many packets are waiting, the caller should drop this one
+    Overflow(nfq::Message),
+}
+
+/// Packets waiting for the user to decide
+#[derive(Default)]
+pub struct PendingVerdicts {
+    questions: HashMap<u64, Question>,
+    flows: HashMap<u64, u64>,
+    /// Decisions made by `Allow` and `Deny`, indexed by exe
+    remembered: HashMap<String, bool>,
+}
+
+impl PendingVerdicts {
+    pub fn remembered(&self, exe: &str) -> Option<bool> {
+        self.remembered.get(exe).cloned()
+    }
+
+    pub fn hold(&mut self, msg: nfq::Message, report: PackageReport) -> Hold {
+        let mut hasher = DefaultHasher::new();
+        (report.device, report.protocol, report.addr, &report.exe).hash(&mut hasher);
+        let flow = hasher.finish();
+
+        if let Some(id) = self.flows.get(&flow) {
+            let question = self.questions.get_mut(id).expect("broken flow index");
+            if question.packets.len() >= MAX_HELD_PACKETS {
+                return Hold::Overflow(msg);
+            }
+            question.packets.push(msg);
+            return Hold::Joined;
+        }
+
+        let id = next_id();
+        self.flows.insert(flow, id);
+        self.questions.insert(
+            id,
+            Question {
+                flow,
+                report: report.clone(),
+                packets: vec![msg],
+                deadline: Instant::now() + ASK_TIMEOUT,
+            },
+        );
+        Hold::New(id, report)
+    }
+
+    pub fn resolve(&mut self, id: u64) -> Option<Question> {
+        let question = self.questions.remove(&id)?;
+        self.flows.remove(&question.flow);
+        Some(question)
+    }
+
+    /// Remember the decision for `exe`, returns all questions it answered
+    pub fn remember(&mut self, exe: String, accept: bool) -> Vec<Question> {
+        let ids: Vec<u64> = self
+            .questions
+            .iter()
+            .filter(|(_, q)| q.report.exe == exe)
+            .map(|(id, _)| *id)
+            .collect();
+        self.remembered.insert(exe, accept);
+        ids.into_iter().filter_map(|id| self.resolve(id)).collect()
+    }
+
+    pub fn expired(&mut self, now: Instant) -> Vec<Question> {
+        let ids: Vec<u64> = self
+            .questions
+            .iter()
+            .filter(|(_, q)| q.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter().filter_map(|id| self.resolve(id)).collect()
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.questions.values().map(|q| q.deadline).min()
+    }
+}
@@ -0,0 +1,451 @@
+//! A minimal bpf(2) client, programs are assembled by hand instead of compiled by clang
+//!
+//! Only the few commands the eBPF backend needs are supported
+
+use std::io;
+use std::mem::{self, MaybeUninit};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_long = 3;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_MAP_LOOKUP_AND_DELETE_ELEM: libc::c_long = 21;
+const BPF_LINK_CREATE: libc::c_long = 28;
+
+pub const BPF_MAP_TYPE_HASH: u32 = 1;
+pub const BPF_MAP_TYPE_ARRAY: u32 = 2;
+pub const BPF_MAP_TYPE_QUEUE: u32 = 22;
+
+pub const BPF_PROG_TYPE_CGROUP_SOCK_ADDR: u32 = 18;
+
+pub const BPF_CGROUP_INET4_CONNECT: u32 = 10;
+pub const BPF_CGROUP_INET6_CONNECT: u32 = 11;
+pub const BPF_CGROUP_UDP4_SENDMSG: u32 = 14;
+pub const BPF_CGROUP_UDP6_SENDMSG: u32 = 15;
+
+/// Replace the oldest element of a full queue map
+pub const BPF_EXIST: u64 = 2;
+
+pub const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+pub const BPF_FUNC_GET_CURRENT_PID_TGID: i32 = 14;
+pub const BPF_FUNC_GET_CURRENT_UID_GID: i32 = 15;
+pub const BPF_FUNC_MAP_PUSH_ELEM: i32 = 87;
+
+pub const R0: u8 = 0;
+pub const R1: u8 = 1;
+pub const R2: u8 = 2;
+pub const R3: u8 = 3;
+pub const R6: u8 = 6;
+pub const R7: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+/// Frame pointer, read only
+pub const R10: u8 = 10;
+
+const BPF_LD: u8 = 0x00;
+const BPF_LDX: u8 = 0x01;
+const BPF_ST: u8 = 0x02;
+const BPF_STX: u8 = 0x03;
+const BPF_ALU: u8 = 0x04;
+const BPF_JMP: u8 = 0x05;
+const BPF_ALU64: u8 = 0x07;
+
+pub const BPF_W: u8 = 0x00;
+pub const BPF_H: u8 = 0x08;
+pub const BPF_DW: u8 = 0x18;
+
+const BPF_IMM: u8 = 0x00;
+const BPF_MEM: u8 = 0x60;
+
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+
+pub const BPF_ADD: u8 = 0x00;
+pub const BPF_AND: u8 = 0x50;
+pub const BPF_RSH: u8 = 0x70;
+const BPF_MOV: u8 = 0xb0;
+const BPF_END: u8 = 0xd0;
+const BPF_TO_BE: u8 = 0x08;
+
+const BPF_JA: u8 = 0x00;
+pub const BPF_JEQ: u8 = 0x10;
+pub const BPF_JGT: u8 = 0x20;
+pub const BPF_JGE: u8 = 0x30;
+pub const BPF_JNE: u8 = 0x50;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+pub const BPF_JLT: u8 = 0xa0;
+
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+/// Size of the verifier log printed when a program is rejected
+const LOG_SIZE: usize = 64 * 1024;
+
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let r = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+/// Closed when dropped
+pub struct Fd(RawFd);
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Keys and values are copied as they are in memory, they must be `#[repr(C)]`
+pub struct Map {
+    fd: Fd,
+}
+
+impl Map {
+    pub fn new(
+        map_type: u32,
+        key_size: usize,
+        value_size: usize,
+        max_entries: u32,
+    ) -> io::Result<Map> {
+        let mut attr = MapCreateAttr {
+            map_type,
+            key_size: key_size as u32,
+            value_size: value_size as u32,
+            max_entries,
+            map_flags: 0,
+        };
+        let fd = sys_bpf(BPF_MAP_CREATE, &mut attr)?;
+        Ok(Map {
+            fd: Fd(fd as RawFd),
+        })
+    }
+
+    pub fn update<K: Copy, V: Copy>(&self, key: &K, value: &V) -> io::Result<()> {
+        let mut attr = MapElemAttr {
+            map_fd: self.fd.0 as u32,
+            _pad: 0,
+            key: key as *const K as u64,
+            value: value as *const V as u64,
+            flags: 0,
+        };
+        sys_bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(drop)
+    }
+
+    pub fn delete<K: Copy>(&self, key: &K) -> io::Result<()> {
+        let mut attr = MapElemAttr {
+            map_fd: self.fd.0 as u32,
+            _pad: 0,
+            key: key as *const K as u64,
+            value: 0,
+            flags: 0,
+        };
+        sys_bpf(BPF_MAP_DELETE_ELEM, &mut attr).map(drop)
+    }
+
+    /// Takes the oldest element of a queue map, `None` if it's empty
+    pub fn pop<V: Copy>(&self) -> io::Result<Option<V>> {
+        let mut value = MaybeUninit::<V>::uninit();
+        let mut attr = MapElemAttr {
+            map_fd: self.fd.0 as u32,
+            _pad: 0,
+            key: 0,
+            value: value.as_mut_ptr() as u64,
+            flags: 0,
+        };
+        match sys_bpf(BPF_MAP_LOOKUP_AND_DELETE_ELEM, &mut attr) {
+            Ok(_) => Ok(Some(unsafe { value.assume_init() })),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsRawFd for Map {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.0
+    }
+}
+
+pub struct Program {
+    fd: Fd,
+}
+
+impl Program {
+    /// The verifier log is printed if the program is rejected
+    pub fn load(
+        prog_type: u32,
+        expected_attach_type: u32,
+        name: &str,
+        insns: &[Insn],
+    ) -> io::Result<Program> {
+        let mut log = vec![0u8; LOG_SIZE];
+        let mut prog_name = [0u8; 16];
+        // the last byte is the terminator
+        let len = name.len().min(prog_name.len() - 1);
+        prog_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        let mut attr = ProgLoadAttr {
+            prog_type,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: b"GPL\0".as_ptr() as u64,
+            log_level: 0,
+            log_size: 0,
+            log_buf: 0,
+            kern_version: 0,
+            prog_flags: 0,
+            prog_name,
+            prog_ifindex: 0,
+            expected_attach_type,
+        };
+        match sys_bpf(BPF_PROG_LOAD, &mut attr) {
+            Ok(fd) => Ok(Program {
+                fd: Fd(fd as RawFd),
+            }),
+            Err(e) => {
+                // load it again to see why
+                attr.log_level = 1;
+                attr.log_size = log.len() as u32;
+                attr.log_buf = log.as_mut_ptr() as u64;
+                if sys_bpf(BPF_PROG_LOAD, &mut attr).is_err() {
+                    let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+                    eprintln!("{}", String::from_utf8_lossy(&log[..end]));
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// The program is detached when the link is dropped, even if we are killed
+    pub fn attach(&self, target: &impl AsRawFd, attach_type: u32) -> io::Result<Fd> {
+        let mut attr = LinkCreateAttr {
+            prog_fd: self.fd.0 as u32,
+            target_fd: target.as_raw_fd() as u32,
+            attach_type,
+            flags: 0,
+        };
+        let fd = sys_bpf(BPF_LINK_CREATE, &mut attr)?;
+        Ok(Fd(fd as RawFd))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Insn {
+    code: u8,
+    /// Source register in the high 4 bits, destination in the low 4 bits
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl Insn {
+    fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Insn {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Label(usize);
+
+/// Builds a program, jumps go to labels bound before or after them
+#[derive(Default)]
+pub struct Asm {
+    insns: Vec<Insn>,
+    labels: Vec<Option<usize>>,
+    /// Jump instructions and the labels they go to
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Asm {
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// The label points to the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.insns.len());
+    }
+
+    fn jump_to(&mut self, insn: Insn, label: Label) {
+        self.fixups.push((self.insns.len(), label));
+        self.insns.push(insn);
+    }
+
+    pub fn mov64_imm(&mut self, dst: u8, imm: i32) {
+        self.insns
+            .push(Insn::new(BPF_ALU64 | BPF_MOV | BPF_K, dst, 0, 0, imm));
+    }
+
+    pub fn mov64_reg(&mut self, dst: u8, src: u8) {
+        self.insns
+            .push(Insn::new(BPF_ALU64 | BPF_MOV | BPF_X, dst, src, 0, 0));
+    }
+
+    pub fn alu64_imm(&mut self, op: u8, dst: u8, imm: i32) {
+        self.insns
+            .push(Insn::new(BPF_ALU64 | op | BPF_K, dst, 0, 0, imm));
+    }
+
+    pub fn alu64_reg(&mut self, op: u8, dst: u8, src: u8) {
+        self.insns
+            .push(Insn::new(BPF_ALU64 | op | BPF_X, dst, src, 0, 0));
+    }
+
+    /// Converts the low `bits` of `dst` from host to network byte order
+    pub fn host_to_be(&mut self, dst: u8, bits: i32) {
+        self.insns
+            .push(Insn::new(BPF_ALU | BPF_END | BPF_TO_BE, dst, 0, 0, bits));
+    }
+
+    /// `dst = *(size *)(src + off)`
+    pub fn load(&mut self, size: u8, dst: u8, src: u8, off: i16) {
+        self.insns
+            .push(Insn::new(BPF_LDX | size | BPF_MEM, dst, src, off, 0));
+    }
+
+    /// `*(size *)(dst + off) = src`
+    pub fn store(&mut self, size: u8, dst: u8, off: i16, src: u8) {
+        self.insns
+            .push(Insn::new(BPF_STX | size | BPF_MEM, dst, src, off, 0));
+    }
+
+    /// `*(size *)(dst + off) = imm`
+    pub fn store_imm(&mut self, size: u8, dst: u8, off: i16, imm: i32) {
+        self.insns
+            .push(Insn::new(BPF_ST | size | BPF_MEM, dst, 0, off, imm));
+    }
+
+    /// Loads the address of a map, takes two instructions
+    pub fn load_map(&mut self, dst: u8, map: &Map) {
+        self.insns.push(Insn::new(
+            BPF_LD | BPF_DW | BPF_IMM,
+            dst,
+            BPF_PSEUDO_MAP_FD,
+            0,
+            map.as_raw_fd(),
+        ));
+        self.insns.push(Insn::new(0, 0, 0, 0, 0));
+    }
+
+    pub fn jump(&mut self, label: Label) {
+        self.jump_to(Insn::new(BPF_JMP | BPF_JA, 0, 0, 0, 0), label);
+    }
+
+    /// Jumps if `dst op imm`
+    pub fn jump_imm(&mut self, op: u8, dst: u8, imm: i32, label: Label) {
+        self.jump_to(Insn::new(BPF_JMP | op | BPF_K, dst, 0, 0, imm), label);
+    }
+
+    /// Jumps if `dst op src`
+    pub fn jump_reg(&mut self, op: u8, dst: u8, src: u8, label: Label) {
+        self.jump_to(Insn::new(BPF_JMP | op | BPF_X, dst, src, 0, 0), label);
+    }
+
+    pub fn call(&mut self, helper: i32) {
+        self.insns
+            .push(Insn::new(BPF_JMP | BPF_CALL, 0, 0, 0, helper));
+    }
+
+    pub fn exit(&mut self) {
+        self.insns.push(Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+    }
+
+    pub fn finish(mut self) -> Vec<Insn> {
+        for (at, label) in self.fixups {
+            let target = self.labels[label.0].expect("jump to unbound label");
+            // relative to the next instruction
+            self.insns[at].off = (target as isize - at as isize - 1) as i16;
+        }
+        self.insns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn jump_offsets() {
+        let mut asm = Asm::default();
+        let (back, forward) = (asm.label(), asm.label());
+        asm.bind(back);
+        asm.mov64_imm(R0, 0);
+        asm.jump_imm(BPF_JEQ, R0, 1, forward);
+        asm.jump(back);
+        asm.bind(forward);
+        asm.exit();
+        let insns = asm.finish();
+        assert_eq!(insns[1].off, 1);
+        assert_eq!(insns[2].off, -3);
+        assert_eq!(insns[1].regs, R0);
+        assert_eq!(insns[3], Insn::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0));
+    }
+}
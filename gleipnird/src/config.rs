@@ -1,11 +1,15 @@
-use std::fs::{create_dir_all, File};
+use std::fs::{self, create_dir_all, File};
+use std::net::IpAddr;
 use std::path::PathBuf;
 
 use failure;
 use gleipnir_interface::{RuleTarget, Rules};
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use serde_json;
 
+use crate::expiry;
+
 lazy_static! {
     static ref CONFIG_DIR: PathBuf = {
         let dir = option_env!("GLEIPNIRD_CONFIG_DIR")
@@ -14,11 +18,91 @@ lazy_static! {
         create_dir_all(&dir).expect("Failed to create config directory");
         dir
     };
+    /// One file of rules for each profile
+    static ref PROFILES_DIR: PathBuf = {
+        let dir = CONFIG_DIR.join("profiles");
+        if !dir.exists() {
+            create_dir_all(&dir).expect("Failed to create profiles directory");
+            // rules from before profiles
+            let rules = CONFIG_DIR.join("rules.json");
+            if rules.exists() {
+                fs::rename(rules, dir.join(format!("{}.json", DEFAULT_PROFILE)))
+                    .expect("Failed to move rules to the default profile");
+            }
+        }
+        dir
+    };
+}
+
+/// Each one takes a queue after the first one, and a thread
+const MAX_WORKERS: u16 = 64;
+
+/// The profile when none was activated
+const DEFAULT_PROFILE: &str = "Default";
+
+/// Names are file names, without `/` or a leading `.`
+pub fn is_valid_profile_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && !name.contains(|c: char| c == '/' || c.is_control())
+}
+
+fn profile_path(name: &str) -> PathBuf {
+    PROFILES_DIR.join(format!("{}.json", name))
+}
+
+pub fn profile_exists(name: &str) -> bool {
+    profile_path(name).exists()
+}
+
+/// Sorted, profiles are listed once their rules are saved
+pub fn list_profiles() -> Result<Vec<String>, failure::Error> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&*PROFILES_DIR)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "json") {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// The last activated profile
+pub fn load_active_profile() -> String {
+    fs::read_to_string(CONFIG_DIR.join("profile"))
+        .ok()
+        .map(|name| name.trim().to_owned())
+        .filter(|name| is_valid_profile_name(name))
+        .unwrap_or_else(|| DEFAULT_PROFILE.to_owned())
+}
+
+pub fn save_active_profile(name: &str) {
+    if let Err(e) = fs::write(CONFIG_DIR.join("profile"), name) {
+        dbg!(e);
+    }
+}
+
+pub fn rename_profile(name: &str, new_name: &str) -> Result<(), failure::Error> {
+    if !is_valid_profile_name(name) || !is_valid_profile_name(new_name) {
+        return Err(failure::err_msg("invalid profile name"));
+    }
+    if profile_exists(new_name) {
+        return Err(failure::err_msg("profile already exists"));
+    }
+    fs::rename(profile_path(name), profile_path(new_name))?;
+    Ok(())
 }
 
-pub fn save_rules(rules: &Rules) {
+/// Rules lasting until the daemon restarts or a process exits are left out
+pub fn save_rules(profile: &str, rules: &Rules) {
     let r: Result<(), failure::Error> = try {
-        let f = File::create(CONFIG_DIR.join("rules.json"))?;
+        let mut rules = rules.clone();
+        expiry::remove_volatile(&mut rules.rules);
+        let f = File::create(profile_path(profile))?;
         serde_json::to_writer(f, &rules)?;
     };
     if let Err(e) = r {
@@ -26,8 +110,9 @@ pub fn save_rules(rules: &Rules) {
     }
 }
 
-pub fn load_rules() -> Result<Rules, failure::Error> {
-    let path = CONFIG_DIR.join("rules.json");
+/// Empty rules if the profile was never saved
+pub fn load_rules(profile: &str) -> Result<Rules, failure::Error> {
+    let path = profile_path(profile);
     if !path.exists() {
         return Ok(Rules {
             default_target: RuleTarget::Accept,
@@ -38,3 +123,103 @@ pub fn load_rules() -> Result<Rules, failure::Error> {
     let f = File::open(path)?;
     Ok(serde_json::from_reader(f)?)
 }
+
+/// What happens to the packets when the daemon is not running
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailPolicy {
+    /// Hooks bypass the queue and are removed on exit, everything is accepted
+    Open,
+    /// Hooks stay installed without bypass, everything is dropped
+    Closed,
+}
+
+impl Default for FailPolicy {
+    fn default() -> Self {
+        FailPolicy::Open
+    }
+}
+
+/// How packets are caught and checked
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// Netfilter hooks send every packet to the queue, sockets are looked up for them
+    Nfqueue,
+    /// Outgoing connections are checked in the kernel by cgroup hooks, which know the
+    /// process. Incoming traffic is not filtered
+    Ebpf,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Nfqueue
+    }
+}
+
+/// Daemon settings, edited by hand in `config.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub backend: Backend,
+    pub fail_policy: FailPolicy,
+    /// Subnets never sent to the queue, keeps the system administrable when fail-closed
+    pub allowlist: Vec<(IpAddr, u8)>,
+    /// Only queue the first packets of a connection, accepted ones are remembered in the
+    /// connmark. Requires nftables
+    pub conntrack: bool,
+    /// Number of threads processing packets, each one has its own queue
+    pub workers: u16,
+    /// Also queue forwarded packets, like the traffic of containers behind a bridge. Their
+    /// sockets are looked up in every network namespace
+    pub forward: bool,
+    /// Read server names from the TLS ClientHello, QUIC Initial or HTTP request starting a
+    /// connection. Whole packets are copied to the daemon, best with `conntrack`
+    pub hostnames: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            backend: Default::default(),
+            fail_policy: Default::default(),
+            allowlist: Default::default(),
+            conntrack: false,
+            workers: 1,
+            forward: false,
+            hostnames: false,
+        }
+    }
+}
+
+pub fn load_config() -> Result<Config, failure::Error> {
+    let path = CONFIG_DIR.join("config.json");
+    if !path.exists() {
+        return Ok(Default::default());
+    }
+    let f = File::open(path)?;
+    let config: Config = serde_json::from_reader(f)?;
+    if config.workers == 0 || config.workers > MAX_WORKERS {
+        return Err(failure::format_err!(
+            "workers must be between 1 and {}",
+            MAX_WORKERS
+        ));
+    }
+    // the hooks are detached when the daemon exits
+    if config.backend == Backend::Ebpf && config.fail_policy == FailPolicy::Closed {
+        return Err(failure::err_msg(
+            "the ebpf backend only supports fail_policy open",
+        ));
+    }
+    // containers are in our cgroup tree, the hooks see them anyway
+    if config.backend == Backend::Ebpf && config.forward {
+        return Err(failure::err_msg("forward is only for the nfqueue backend"));
+    }
+    // the hooks only see addresses
+    if config.backend == Backend::Ebpf && config.hostnames {
+        return Err(failure::err_msg(
+            "hostnames is only for the nfqueue backend",
+        ));
+    }
+    Ok(config)
+}
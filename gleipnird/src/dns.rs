@@ -0,0 +1,383 @@
+//! Names of remote addresses, learned from the DNS answers going through the queue
+//!
+//! Only answers to queries seen going out are learned, from the server asked, and only the
+//! records the question leads to. Names asked by system services like a local caching
+//! resolver are for every process, the rest only for the process which asked
+
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use gleipnir_interface::Proto;
+
+use crate::proc;
+
+pub const PORT: u16 = 53;
+
+/// Programs and stub resolvers keep answers longer than their TTLs say
+const MIN_TTL: Duration = Duration::from_secs(5 * 60);
+/// How often expired names and queries are dropped
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+/// Resolvers give up and ask again long before
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_QUERIES: usize = 4096;
+/// Names of one address, a CDN serves many
+const MAX_NAMES: usize = 8;
+/// Compressed names may point to each other forever
+const MAX_POINTERS: usize = 16;
+const MAX_ALIASES: usize = 16;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_MASK: u16 = 0x000f;
+
+/// Who can use the names from an answer
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Scope {
+    /// Asked by a system service, which may be resolving for anyone
+    System,
+    Process(usize),
+}
+
+impl Scope {
+    /// Containers are scopes in the system slice, not services
+    pub fn of(pid: usize, cgroup: &str) -> Self {
+        if cgroup.starts_with("/system.slice/") && proc::unit_of(cgroup).ends_with(".service") {
+            Scope::System
+        } else {
+            Scope::Process(pid)
+        }
+    }
+}
+
+/// The protocol, the local and the server address, and the ID of a query
+type QueryKey = (Proto, SocketAddr, SocketAddr, u16);
+
+/// Shared by all workers
+pub struct Cache {
+    /// The question of queries not answered yet, who asked it and when it expires
+    queries: HashMap<QueryKey, (String, Scope, Instant)>,
+    /// With the time they expire, in the order they were learned
+    names: HashMap<(Scope, IpAddr), Vec<(String, Instant)>>,
+    purged: Instant,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            queries: HashMap::new(),
+            names: HashMap::new(),
+            purged: Instant::now(),
+        }
+    }
+}
+
+impl Cache {
+    fn purge(&mut self, now: Instant) {
+        if now.duration_since(self.purged) <= PURGE_INTERVAL {
+            return;
+        }
+        self.names.retain(|_, names| {
+            names.retain(|(_, expiry)| *expiry > now);
+            !names.is_empty()
+        });
+        self.queries.retain(|_, (_, _, expiry)| *expiry > now);
+        self.purged = now;
+    }
+
+    /// `msg` sent from `local` to `server`, answers to it are learned for `scope`
+    pub fn insert_query(
+        &mut self,
+        protocol: Proto,
+        local: SocketAddr,
+        server: SocketAddr,
+        msg: &[u8],
+        scope: Scope,
+    ) {
+        let now = Instant::now();
+        self.purge(now);
+        let (id, question) = match parse_query(msg) {
+            Some(r) => r,
+            None => return,
+        };
+        if self.queries.len() >= MAX_QUERIES {
+            return;
+        }
+        let key = (protocol, local, server, id);
+        self.queries
+            .insert(key, (question, scope, now + QUERY_TIMEOUT));
+    }
+
+    /// `msg` received by `local` from `server`. It may be truncated, the records before the
+    /// end are still learned
+    pub fn insert_response(
+        &mut self,
+        protocol: Proto,
+        local: SocketAddr,
+        server: SocketAddr,
+        msg: &[u8],
+    ) {
+        let now = Instant::now();
+        self.purge(now);
+        let (id, question, records) = match parse_response(msg) {
+            Some(r) => r,
+            None => return,
+        };
+        let scope = match self.queries.remove(&(protocol, local, server, id)) {
+            Some((asked, scope, expiry)) if asked == question && expiry > now => scope,
+            // spoofed, or not asked by anyone we know
+            _ => return,
+        };
+        for (addr, name, ttl) in records {
+            let expiry = now + cmp::max(Duration::from_secs(ttl.into()), MIN_TTL);
+            let names = self.names.entry((scope, addr)).or_default();
+            if let Some(known) = names.iter_mut().find(|(known, _)| *known == name) {
+                known.1 = expiry;
+            } else if names.len() < MAX_NAMES {
+                names.push((name, expiry));
+            } else {
+                let oldest = names.iter_mut().min_by_key(|(_, expiry)| *expiry).unwrap();
+                *oldest = (name, expiry);
+            }
+        }
+    }
+
+    /// Names `addr` was resolved from for the process and are not expired yet, the ones it
+    /// asked itself first, then the one asked for first
+    pub fn names(&self, addr: IpAddr, pid: usize) -> Vec<String> {
+        let now = Instant::now();
+        let mut r: Vec<String> = Vec::new();
+        for &scope in &[Scope::Process(pid), Scope::System] {
+            let names = self.names.get(&(scope, addr)).into_iter().flatten();
+            for (name, expiry) in names {
+                if *expiry > now && !r.contains(name) {
+                    r.push(name.clone());
+                }
+            }
+        }
+        r
+    }
+}
+
+/// The ID and the question of a query with one question
+fn parse_query(msg: &[u8]) -> Option<(u16, String)> {
+    let u16_at = |i: usize| msg.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let (id, flags, questions) = (u16_at(0)?, u16_at(2)?, u16_at(4)?);
+    if flags & FLAG_RESPONSE != 0 || questions != 1 {
+        return None;
+    }
+    let (question, _) = read_name(msg, 12)?;
+    Some((id, question))
+}
+
+/// The ID, the question, and the addresses in the answers with the names they were resolved
+/// from and their TTLs.
+///
+/// Only records reachable from the question through aliases are returned, the name asked for
+/// comes first, followed by the aliases leading to the address
+fn parse_response(msg: &[u8]) -> Option<(u16, String, Vec<(IpAddr, String, u32)>)> {
+    let u16_at = |i: usize| msg.get(i..i + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+    let u32_at = |i: usize| {
+        msg.get(i..i + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let (id, flags, questions, answers) = (u16_at(0)?, u16_at(2)?, u16_at(4)?, u16_at(6)?);
+    if flags & FLAG_RESPONSE == 0 || flags & RCODE_MASK != 0 || questions != 1 {
+        return None;
+    }
+
+    // type and class
+    let (question, mut pos) = read_name(msg, 12).map(|(name, next)| (name, next + 4))?;
+    // by the name they point from
+    let mut targets = HashMap::new();
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        let (name, next) = match read_name(msg, pos) {
+            Some(r) => r,
+            None => break,
+        };
+        let (kind, class, ttl, len) = match (
+            u16_at(next),
+            u16_at(next + 2),
+            u32_at(next + 4),
+            u16_at(next + 8),
+        ) {
+            (Some(kind), Some(class), Some(ttl), Some(len)) => (kind, class, ttl, len as usize),
+            _ => break,
+        };
+        pos = next + 10;
+        let data = match msg.get(pos..pos + len) {
+            Some(r) => r,
+            None => break,
+        };
+        match (kind, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let addr = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+                addrs.push((addr.into(), name, ttl));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(data);
+                addrs.push((Ipv6Addr::from(octets).into(), name, ttl));
+            }
+            (TYPE_CNAME, CLASS_IN, _) => {
+                if let Some((target, _)) = read_name(msg, pos) {
+                    targets.entry(name).or_insert(target);
+                }
+            }
+            _ => (),
+        }
+        pos += len;
+    }
+
+    // the question and the aliases it leads to, records of other names were not asked for
+    let mut chain = vec![question.clone()];
+    while let Some(target) = targets.get(chain.last().unwrap()) {
+        if chain.len() > MAX_ALIASES || chain.contains(target) {
+            break;
+        }
+        chain.push(target.clone());
+    }
+    let mut records = Vec::new();
+    for (addr, name, ttl) in addrs {
+        if let Some(i) = chain.iter().position(|alias| *alias == name) {
+            records.extend(chain[..=i].iter().map(|name| (addr, name.clone(), ttl)));
+        }
+    }
+    Some((id, question, records))
+}
+
+/// Lowercase without the trailing dot, and the position after the name
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => break,
+            // the rest is somewhere before
+            len if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (len & 0x3f) << 8 | *msg.get(pos + 1)? as usize;
+            }
+            len if len & 0xc0 != 0 => return None,
+            len => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                pos += 1 + len;
+            }
+        }
+    }
+    Some((name, end.unwrap_or(pos + 1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `www.example.com` is an alias of `cdn.net`, which has an IPv4 and an IPv6 address
+    fn response() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0];
+        // at 12
+        msg.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 44, 0, 9]);
+        // at 45
+        msg.extend_from_slice(b"\x03cdn\x03net\x00");
+        msg.extend_from_slice(&[0xc0, 45, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        msg.extend_from_slice(&[0xc0, 45, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        msg.extend_from_slice(&[0x26, 0x06, 0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        msg
+    }
+
+    /// The question of `response`
+    fn query() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        msg
+    }
+
+    #[test]
+    fn parse() {
+        let v4: IpAddr = [93, 184, 216, 34].into();
+        let v6: IpAddr = "2606:2800::1".parse().unwrap();
+        let question = "www.example.com".to_owned();
+        assert_eq!(
+            parse_response(&response()),
+            Some((
+                0x1234,
+                question.clone(),
+                vec![
+                    (v4, "www.example.com".into(), 60),
+                    (v4, "cdn.net".into(), 60),
+                    (v6, "www.example.com".into(), 60),
+                    (v6, "cdn.net".into(), 60),
+                ]
+            ))
+        );
+        assert_eq!(parse_query(&query()), Some((0x1234, question.clone())));
+        assert_eq!(parse_query(&response()), None);
+        // cut in the middle of the IPv6 address
+        let msg = response();
+        assert_eq!(parse_response(&msg[..msg.len() - 8]).unwrap().2.len(), 2);
+        // a query
+        let mut msg = response();
+        msg[2] = 0x01;
+        assert_eq!(parse_response(&msg), None);
+        // the alias is of another name, nothing leads to the addresses from the question
+        let mut msg = response();
+        msg[34] = 45;
+        assert_eq!(parse_response(&msg), Some((0x1234, question, vec![])));
+        // pointing to itself
+        assert_eq!(read_name(&[0xc0, 0], 0), None);
+    }
+
+    #[test]
+    fn cache() {
+        let local: SocketAddr = ([192, 168, 1, 2], 40000).into();
+        let server: SocketAddr = ([192, 168, 1, 1], 53).into();
+        let addr: IpAddr = [93, 184, 216, 34].into();
+        let names = vec!["www.example.com".to_owned(), "cdn.net".to_owned()];
+
+        let mut cache = Cache::default();
+        // not asked
+        cache.insert_response(Proto::Udp, local, server, &response());
+        assert!(cache.names(addr, 1).is_empty());
+        // from another server
+        cache.insert_query(Proto::Udp, local, server, &query(), Scope::Process(1));
+        let spoofed: SocketAddr = ([6, 6, 6, 6], 53).into();
+        cache.insert_response(Proto::Udp, local, spoofed, &response());
+        assert!(cache.names(addr, 1).is_empty());
+        // only for the process which asked
+        cache.insert_response(Proto::Udp, local, server, &response());
+        assert_eq!(cache.names(addr, 1), names);
+        assert!(cache.names(addr, 2).is_empty());
+        assert!(cache.names([1, 1, 1, 1].into(), 1).is_empty());
+        // answered already
+        let mut cache = Cache::default();
+        cache.insert_query(Proto::Udp, local, server, &query(), Scope::System);
+        cache.insert_response(Proto::Udp, local, server, &response());
+        assert_eq!(cache.names(addr, 2), names);
+    }
+
+    #[test]
+    fn scope() {
+        let resolved = "/system.slice/systemd-resolved.service";
+        assert_eq!(Scope::of(1, resolved), Scope::System);
+        let container = "/system.slice/docker-4f8c.scope";
+        assert_eq!(Scope::of(1, container), Scope::Process(1));
+        let user = "/user.slice/user-1000.slice/user@1000.service/app.slice/dnsmasq.service";
+        assert_eq!(Scope::of(1, user), Scope::Process(1));
+    }
+}
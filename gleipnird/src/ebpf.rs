@@ -0,0 +1,903 @@
+//! The eBPF backend, decides outgoing connections in cgroup `connect` and `sendmsg` hooks
+//!
+//! The hooks run in the process that is connecting, so there is no socket to look up.
+//! Rules are compiled into a map and checked in the kernel, userspace only keeps the
+//! executable of every process in another map, and collects the reports.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender};
+use gleipnir_interface::{AskAnswer, Device, PackageReport, Proto, Rule, RuleTarget};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::ask::{self, Answer, Ask, ASK_TIMEOUT};
+use crate::bpf::{self, Map, Program};
+use crate::config::Config;
+use crate::lrlock;
+use crate::proc;
+use crate::proc_events::{ProcEvent, ProcEvents};
+use crate::rules::IndexedRules;
+
+/// Each half of the rules map can hold this many compiled rules
+const MAX_RULES: u32 = 512;
+const MAX_PROCS: u32 = 65536;
+/// Reports waiting for us, the oldest ones are overwritten
+const MAX_EVENTS: u32 = 4096;
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Without the proc connector, processes can exec another executable, or exit and leave
+/// their PID to others without us knowing
+const PROC_SCAN_INTERVAL: Duration = Duration::from_secs(5);
+const CGROUP2_SUPER_MAGIC: i64 = 0x6367_7270;
+
+const TARGET_DROP: u32 = 0;
+const TARGET_ACCEPT: u32 = 1;
+/// Refuses the connection and asks the user about the next ones
+const TARGET_ASK: u32 = 2;
+
+/// Matched the default target, or a remembered answer
+const NO_RULE: u32 = u32::MAX;
+/// Matched the allowlist, not reported
+const ALLOWLIST: u32 = u32::MAX - 1;
+
+/// Offsets in `struct bpf_sock_addr`
+const CTX_USER_IP4: i16 = 4;
+const CTX_USER_IP6: i16 = 8;
+const CTX_USER_PORT: i16 = 24;
+const CTX_PROTOCOL: i16 = 36;
+
+/// Stack of the hooks
+const STACK_EVENT: i16 = -48;
+const STACK_KEY: i16 = -52;
+
+/// Attach type, program name and if it's for IPv6
+const HOOKS: [(u32, &str, bool); 4] = [
+    (bpf::BPF_CGROUP_INET4_CONNECT, "gleipnir_conn4", false),
+    (bpf::BPF_CGROUP_INET6_CONNECT, "gleipnir_conn6", true),
+    (bpf::BPF_CGROUP_UDP4_SENDMSG, "gleipnir_send4", false),
+    (bpf::BPF_CGROUP_UDP6_SENDMSG, "gleipnir_send6", true),
+];
+
+/// A rule as the hooks see it, zero means any
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+struct BpfRule {
+    exe: u32,
+    proto: u32,
+    family: u32,
+    port_start: u16,
+    port_end: u16,
+    /// Every 32 bits are in network byte order, already masked
+    addr: [u32; 4],
+    mask: [u32; 4],
+    target: u32,
+    /// Index in the raw rules
+    id: u32,
+    /// UID in the lower 32 bits and GID in the upper ones, checked as `ids & ids_mask`
+    ids: u64,
+    ids_mask: u64,
+}
+
+const RULE_EXE: i16 = 0;
+const RULE_PROTO: i16 = 4;
+const RULE_FAMILY: i16 = 8;
+const RULE_PORT_START: i16 = 12;
+const RULE_PORT_END: i16 = 14;
+const RULE_ADDR: i16 = 16;
+const RULE_MASK: i16 = 32;
+const RULE_TARGET: i16 = 48;
+const RULE_ID: i16 = 52;
+const RULE_IDS: i16 = 56;
+const RULE_IDS_MASK: i16 = 64;
+
+/// Pushed by the hooks for every connection they decided
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Event {
+    tgid: u32,
+    rule: u32,
+    target: u32,
+    proto: u32,
+    port: u32,
+    family: u32,
+    addr: [u32; 4],
+    /// Same as `BpfRule::ids`
+    ids: u64,
+}
+
+const EVENT_TGID: i16 = 0;
+const EVENT_RULE: i16 = 4;
+const EVENT_TARGET: i16 = 8;
+const EVENT_PROTO: i16 = 12;
+const EVENT_PORT: i16 = 16;
+const EVENT_FAMILY: i16 = 20;
+const EVENT_ADDR: i16 = 24;
+const EVENT_IDS: i16 = 40;
+
+struct Maps {
+    /// TGID to executable id, only executables mentioned by the rules are in it
+    procs: Map,
+    /// The half of `rules` in use
+    active: Map,
+    /// Two halves, one is rewritten while the hooks read the other
+    rules: Map,
+    events: Map,
+}
+
+pub struct Filter {
+    maps: Maps,
+    /// The programs are detached when they are dropped
+    _links: Vec<bpf::Fd>,
+    /// Ids are never reused, so `procs` stays valid while rules change
+    exes: HashMap<String, u32>,
+    /// Same as the `procs` map
+    procs: HashMap<u32, u32>,
+    proc_events: Option<ProcEvents>,
+    active: u32,
+    /// Rules generation in the map, `None` if it needs to be compiled again
+    generation: Option<u32>,
+    /// Decisions made by `Allow` and `Deny`, indexed by exe
+    remembered: HashMap<String, bool>,
+    /// Executables with a question waiting for the user
+    asking: HashMap<String, Instant>,
+    pkt_logs: Sender<PackageReport>,
+    asks: Sender<Ask>,
+}
+
+impl Filter {
+    pub fn load(
+        config: &Config,
+        rules: &IndexedRules,
+        pkt_logs: Sender<PackageReport>,
+        asks: Sender<Ask>,
+    ) -> io::Result<Filter> {
+        let maps = Maps {
+            procs: Map::new(bpf::BPF_MAP_TYPE_HASH, 4, 4, MAX_PROCS)?,
+            active: Map::new(bpf::BPF_MAP_TYPE_ARRAY, 4, 4, 1)?,
+            rules: Map::new(
+                bpf::BPF_MAP_TYPE_ARRAY,
+                4,
+                mem::size_of::<BpfRule>(),
+                MAX_RULES * 2,
+            )?,
+            events: Map::new(
+                bpf::BPF_MAP_TYPE_QUEUE,
+                0,
+                mem::size_of::<Event>(),
+                MAX_EVENTS,
+            )?,
+        };
+        let mut filter = Filter {
+            maps,
+            _links: Vec::new(),
+            exes: HashMap::new(),
+            procs: HashMap::new(),
+            // before scanning, so nothing is missed between them
+            proc_events: ProcEvents::subscribe()
+                .map_err(|e| eprintln!("Proc connector unavailable ({}), scan /proc instead", e))
+                .ok(),
+            active: 0,
+            generation: None,
+            remembered: HashMap::new(),
+            asking: HashMap::new(),
+            pkt_logs,
+            asks,
+        };
+        // the empty map would drop everything to port 0
+        filter.update(rules, &config.allowlist);
+
+        let cgroup = cgroup_root()?;
+        for &(attach_type, name, v6) in &HOOKS {
+            let insns = program(&filter.maps, v6);
+            let prog = Program::load(
+                bpf::BPF_PROG_TYPE_CGROUP_SOCK_ADDR,
+                attach_type,
+                name,
+                &insns,
+            )?;
+            filter._links.push(prog.attach(&cgroup, attach_type)?);
+        }
+        Ok(filter)
+    }
+
+    fn update(&mut self, rules: &IndexedRules, allowlist: &[(IpAddr, u8)]) {
+        let (mut compiled, skipped) = compile(
+            rules.raw(),
+            rules.default_target(),
+            allowlist,
+            &self.remembered,
+            &mut self.exes,
+        );
+        for i in skipped {
+            // nfqueue checks them after a restart
+            eprintln!("Rule {} can't be enforced by the eBPF backend, skipped", i);
+        }
+        if compiled.len() > MAX_RULES as usize {
+            eprintln!("Too many rules for the eBPF backend, the rest are ignored");
+            let default = compiled.pop().expect("no default rule");
+            compiled.truncate(MAX_RULES as usize - 1);
+            compiled.push(default);
+        }
+        // processes of new executables must be known before the rules using them
+        self.scan_procs();
+
+        // a hook that read `active` before the last switch may still be in this half,
+        // but updates are far apart compared to the hooks
+        let base = if self.active == 0 { MAX_RULES } else { 0 };
+        let written: io::Result<()> = try {
+            for (i, rule) in compiled.iter().enumerate() {
+                self.maps.rules.update(&(base + i as u32), rule)?;
+            }
+            self.maps.active.update(&0u32, &base)?;
+        };
+        if let Err(e) = written {
+            // the old rules stay active, this generation is tried again
+            eprintln!("Failed to update the rules map: {}", e);
+            return;
+        }
+        self.active = base;
+        self.generation = Some(rules.generation());
+    }
+
+    /// Rebuilds the `procs` map from `/proc`
+    fn scan_procs(&mut self) {
+        let mut procs = HashMap::new();
+        for entry in fs::read_dir("/proc").expect("open /proc") {
+            let tgid = match entry.map(|e| e.file_name().to_str().map(str::parse::<u32>)) {
+                Ok(Some(Ok(r))) => r,
+                _ => continue,
+            };
+            if let Some(&id) = self.exes.get(&proc::get_exe(tgid as usize)) {
+                procs.insert(tgid, id);
+            }
+        }
+        for tgid in self.procs.keys().filter(|tgid| !procs.contains_key(*tgid)) {
+            // may be gone already if the same PID was reported
+            let _ = self.maps.procs.delete(tgid);
+        }
+        // the map is full with too many processes, the ones left out are checked as any other
+        procs.retain(|tgid, id| {
+            if self.procs.get(tgid) == Some(id) {
+                return true;
+            }
+            let updated = self.maps.procs.update(tgid, id);
+            if let Err(e) = &updated {
+                eprintln!("Failed to update the procs map: {}", e);
+                let _ = self.maps.procs.delete(tgid);
+            }
+            updated.is_ok()
+        });
+        self.procs = procs;
+    }
+
+    /// Follows new processes and exec as they happen, returns true if some events are lost
+    fn apply_proc_events(&mut self) -> bool {
+        loop {
+            let event = match self.proc_events.as_mut().map(ProcEvents::recv) {
+                Some(Ok(Some(r))) => r,
+                None | Some(Ok(None)) => return false,
+                Some(Err(e)) => {
+                    eprintln!("Lost proc events: {}", e);
+                    return true;
+                }
+            };
+            match event {
+                // a child runs the same executable
+                ProcEvent::Fork { parent, child } => {
+                    if let Some(&id) = self.procs.get(&parent) {
+                        self.set_proc(child, id);
+                    }
+                }
+                ProcEvent::Exec(tgid) => self.track(tgid, &proc::get_exe(tgid as usize)),
+                // the hooks know the IDs by themselves
+                ProcEvent::Id(_) => (),
+                ProcEvent::Exit(tgid) => {
+                    if self.procs.remove(&tgid).is_some() {
+                        let _ = self.maps.procs.delete(&tgid);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Corrects the map for a process the hooks just saw
+    fn track(&mut self, tgid: u32, exe: &str) {
+        match self.exes.get(exe) {
+            Some(&id) if self.procs.get(&tgid) != Some(&id) => self.set_proc(tgid, id),
+            Some(_) => (),
+            None => {
+                if self.procs.remove(&tgid).is_some() {
+                    let _ = self.maps.procs.delete(&tgid);
+                }
+            }
+        }
+    }
+
+    fn set_proc(&mut self, tgid: u32, id: u32) {
+        match self.maps.procs.update(&tgid, &id) {
+            Ok(()) => {
+                self.procs.insert(tgid, id);
+            }
+            Err(e) => {
+                eprintln!("Failed to update the procs map: {}", e);
+                // an old entry would tell another executable
+                if self.procs.remove(&tgid).is_some() {
+                    let _ = self.maps.procs.delete(&tgid);
+                }
+            }
+        }
+    }
+
+    fn on_event(&mut self, event: Event) {
+        if event.rule == ALLOWLIST {
+            return;
+        }
+        let protocol = match proto_from_u32(event.proto) {
+            Some(r) => r,
+            None => return,
+        };
+        let exe = proc::get_exe(event.tgid as usize);
+        let cgroup = proc::get_cgroup(event.tgid as usize);
+        let app = proc::get_app(event.tgid as usize, &exe, &cgroup);
+        let cmdline = proc::get_cmdline(event.tgid as usize, &exe);
+        self.track(event.tgid, &exe);
+
+        let ip: IpAddr = if event.family == libc::AF_INET as u32 {
+            Ipv4Addr::from(event.addr[0].to_ne_bytes()).into()
+        } else {
+            let mut octets = [0u8; 16];
+            for (i, word) in event.addr.iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+            }
+            Ipv6Addr::from(octets).into()
+        };
+        let report = PackageReport {
+            device: Device::Output,
+            protocol,
+            addr: SocketAddr::new(ip, event.port as u16),
+            domain: None,
+            // connections are reported instead of packets
+            len: 0,
+            exe,
+            app,
+            sha256: None,
+            cmdline,
+            uid: event.ids as u32,
+            gid: (event.ids >> 32) as u32,
+            user: proc::user_name(event.ids as u32),
+            ancestors: proc::get_ancestors(event.tgid as usize),
+            unit: proc::unit_of(&cgroup).to_owned(),
+            cgroup,
+            dropped: event.target != TARGET_ACCEPT,
+            matched_rule: if event.rule == NO_RULE {
+                None
+            } else {
+                Some(event.rule as usize)
+            },
+            broken_pin: None,
+        };
+        if event.target == TARGET_ASK && !self.asking.contains_key(&report.exe) {
+            self.asking.insert(report.exe.clone(), Instant::now());
+            self.asks
+                .try_send(Ask {
+                    id: ask::next_id(),
+                    report: report.clone(),
+                    generation: self.generation,
+                })
+                .expect("ask service dead");
+        }
+        self.pkt_logs.try_send(report).expect("logs service dead");
+    }
+
+    /// The connection that asked is already refused, answers apply to the next ones
+    fn on_answer(&mut self, answer: Answer) {
+        self.asking.remove(&answer.exe);
+        let accept = match answer.answer {
+            Some(AskAnswer::Allow) => true,
+            Some(AskAnswer::Deny) => false,
+            // `AllowForever` changes the rules by itself
+            _ => return,
+        };
+        self.remembered.insert(answer.exe, accept);
+        self.generation = None;
+    }
+}
+
+/// Every worker of the nfqueue backend is replaced by it
+pub fn run(
+    config: &Config,
+    rules: lrlock::Reader<IndexedRules>,
+    pkt_logs: Sender<PackageReport>,
+    asks: Sender<Ask>,
+    answers: Receiver<Answer>,
+) -> ! {
+    let mut filter =
+        Filter::load(config, &rules.read(), pkt_logs, asks).expect("Failed to load eBPF programs");
+    let mut last_scan = Instant::now();
+
+    loop {
+        for answer in answers.try_iter() {
+            filter.on_answer(answer);
+        }
+        filter
+            .asking
+            .retain(|_, since| since.elapsed() < ASK_TIMEOUT);
+
+        {
+            let rules = rules.read();
+            if filter.generation != Some(rules.generation()) {
+                filter.update(&rules, &config.allowlist);
+                last_scan = Instant::now();
+            }
+        }
+        let lost = filter.apply_proc_events();
+        if lost || (filter.proc_events.is_none() && last_scan.elapsed() >= PROC_SCAN_INTERVAL) {
+            filter.scan_procs();
+            last_scan = Instant::now();
+        }
+
+        while let Some(event) = filter.maps.events.pop().expect("pop events map") {
+            filter.on_event(event);
+        }
+        // wake up for new processes, so they are known before their first connection
+        match &filter.proc_events {
+            Some(events) => {
+                let fd = PollFd::new(events.as_raw_fd(), PollFlags::POLLIN);
+                match poll(&mut [fd], EVENT_POLL_INTERVAL.as_millis() as libc::c_int) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                    Err(e) => panic!("poll proc events: {}", e),
+                }
+            }
+            None => thread::sleep(EVENT_POLL_INTERVAL),
+        }
+    }
+}
+
+/// The cgroup v2 root, hooks attached to it see every process
+fn cgroup_root() -> io::Result<File> {
+    // hybrid hierarchies mount it on `unified`
+    for path in &["/sys/fs/cgroup", "/sys/fs/cgroup/unified"] {
+        let f = match File::open(path) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        let mut st: libc::statfs = unsafe { mem::zeroed() };
+        if unsafe { libc::fstatfs(f.as_raw_fd(), &mut st) } == 0
+            && st.f_type as i64 == CGROUP2_SUPER_MAGIC
+        {
+            return Ok(f);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "cgroup v2 is not mounted",
+    ))
+}
+
+fn proto_from_u32(proto: u32) -> Option<Proto> {
+    Some(match proto as libc::c_int {
+        libc::IPPROTO_TCP => Proto::Tcp,
+        libc::IPPROTO_UDP => Proto::Udp,
+        libc::IPPROTO_UDPLITE => Proto::UdpLite,
+        libc::IPPROTO_SCTP => Proto::Sctp,
+        libc::IPPROTO_DCCP => Proto::Dccp,
+        libc::IPPROTO_ICMP => Proto::Icmp,
+        libc::IPPROTO_ICMPV6 => Proto::IcmpV6,
+        _ => return None,
+    })
+}
+
+/// Family, address and mask in the layout of `BpfRule`
+fn compile_subnet(subnet: Option<(IpAddr, u8)>) -> (u32, [u32; 4], [u32; 4]) {
+    let (family, octets, prefix) = match subnet {
+        Some((IpAddr::V4(ip), prefix)) => (libc::AF_INET, ip.octets().to_vec(), prefix),
+        Some((IpAddr::V6(ip), prefix)) => (libc::AF_INET6, ip.octets().to_vec(), prefix),
+        None => return (0, [0; 4], [0; 4]),
+    };
+    let (mut addr, mut mask) = ([0; 4], [0; 4]);
+    for (i, chunk) in octets.chunks(4).enumerate() {
+        let bits = u32::from(prefix).saturating_sub(i as u32 * 32).min(32);
+        mask[i] = u32::from_ne_bytes(u32::MAX.checked_shl(32 - bits).unwrap_or(0).to_be_bytes());
+        addr[i] = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) & mask[i];
+    }
+    (family as u32, addr, mask)
+}
+
+fn compile_target(target: RuleTarget) -> u32 {
+    match target {
+        RuleTarget::Accept => TARGET_ACCEPT,
+        // the caller of `connect` gets `EPERM` either way
+        RuleTarget::Drop | RuleTarget::Reject => TARGET_DROP,
+        RuleTarget::Ask => TARGET_ASK,
+        // there are no packets to count, only the default gets here
+        RuleTarget::RateLimit(_) => TARGET_ACCEPT,
+    }
+}
+
+fn intern(exes: &mut HashMap<String, u32>, exe: &str) -> u32 {
+    let next = exes.len() as u32 + 1;
+    *exes.entry(exe.to_owned()).or_insert(next)
+}
+
+/// `None` if the hooks can't check what the rule matches
+fn compile_rule(rule: &Rule, id: u32, exes: &mut HashMap<String, u32>) -> Option<BpfRule> {
+    // there are no packets to count
+    if let RuleTarget::RateLimit(_) = rule.target {
+        return None;
+    }
+    // outgoing connections only, they have no ICMP type
+    if rule.device == Some(Device::Input) || rule.icmp.is_some() {
+        return None;
+    }
+    // only paths of executables are tracked, not their content, apps, arguments, parents or
+    // cgroups
+    if rule.sha256.is_some()
+        || rule.app.is_some()
+        || rule.cmdline.is_some()
+        || rule.parent.is_some()
+        || rule.unit.is_some()
+    {
+        return None;
+    }
+    // a process has one ID, it can't be in several patterns
+    if rule.exe.as_ref().map_or(false, |exe| exe.contains('*')) {
+        return None;
+    }
+    if rule.port.is_some() && rule.proto.map_or(false, |p| p.is_icmp()) {
+        return None;
+    }
+    // DNS answers are not seen by the hooks
+    if rule.domain.is_some() {
+        return None;
+    }
+    // the compiled rules don't change with the time
+    if rule.schedule.is_some() {
+        return None;
+    }
+    let (family, addr, mask) = compile_subnet(rule.subnet);
+    let (port_start, port_end) = match &rule.port {
+        Some(range) => (*range.start(), *range.end()),
+        None => (0, u16::MAX),
+    };
+    Some(BpfRule {
+        exe: rule.exe.as_ref().map_or(0, |exe| intern(exes, exe)),
+        proto: rule.proto.map_or(0, |p| p as u32),
+        family,
+        port_start,
+        port_end,
+        addr,
+        mask,
+        target: compile_target(rule.target),
+        id,
+        ids: u64::from(rule.group.unwrap_or(0)) << 32 | u64::from(rule.user.unwrap_or(0)),
+        ids_mask: rule.group.map_or(0, |_| 0xffff_ffff << 32)
+            | rule.user.map_or(0, |_| 0xffff_ffff),
+    })
+}
+
+/// Indices of the rules the hooks can't check, which would let through what they refuse
+pub fn unenforced(rules: &[Rule]) -> Vec<usize> {
+    rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.target != RuleTarget::Accept)
+        .filter(|(_, rule)| compile_rule(rule, 0, &mut HashMap::new()).is_none())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Rules in the order the hooks check them, the last one matches everything. Also returns the
+/// indices of the rules which are skipped
+fn compile(
+    rules: &[Rule],
+    default_target: RuleTarget,
+    allowlist: &[(IpAddr, u8)],
+    remembered: &HashMap<String, bool>,
+    exes: &mut HashMap<String, u32>,
+) -> (Vec<BpfRule>, Vec<usize>) {
+    let any = BpfRule {
+        port_end: u16::MAX,
+        ..Default::default()
+    };
+    let mut compiled: Vec<BpfRule> = allowlist
+        .iter()
+        .map(|&subnet| {
+            let (family, addr, mask) = compile_subnet(Some(subnet));
+            BpfRule {
+                family,
+                addr,
+                mask,
+                target: TARGET_ACCEPT,
+                id: ALLOWLIST,
+                ..any
+            }
+        })
+        .collect();
+
+    // remembered answers replace `Ask` for their executables
+    let answered = |rule: BpfRule, exes: &mut HashMap<String, u32>| {
+        let mut r: Vec<_> = remembered
+            .iter()
+            .map(|(exe, &accept)| BpfRule {
+                exe: intern(exes, exe),
+                target: if accept { TARGET_ACCEPT } else { TARGET_DROP },
+                ..rule
+            })
+            .filter(|r| rule.exe == 0 || r.exe == rule.exe)
+            .collect();
+        r.push(rule);
+        r
+    };
+
+    let mut skipped = Vec::new();
+    for (id, rule) in rules.iter().enumerate() {
+        let rule = match compile_rule(rule, id as u32, exes) {
+            Some(r) => r,
+            None => {
+                skipped.push(id);
+                continue;
+            }
+        };
+        if rule.target == TARGET_ASK {
+            compiled.extend(answered(rule, exes));
+        } else {
+            compiled.push(rule);
+        }
+    }
+    let default = BpfRule {
+        target: compile_target(default_target),
+        id: NO_RULE,
+        ..any
+    };
+    if default.target == TARGET_ASK {
+        compiled.extend(answered(default, exes));
+    } else {
+        compiled.push(default);
+    }
+    (compiled, skipped)
+}
+
+/// Returns 1 to allow the connection, 0 to refuse it with `EPERM`
+fn program(maps: &Maps, v6: bool) -> Vec<bpf::Insn> {
+    use bpf::*;
+
+    let family = if v6 { libc::AF_INET6 } else { libc::AF_INET };
+    let mut asm = Asm::default();
+    let (lookup, next, allow, exit) = (asm.label(), asm.label(), asm.label(), asm.label());
+    asm.mov64_reg(R6, R1);
+
+    // the process that is connecting
+    asm.call(BPF_FUNC_GET_CURRENT_PID_TGID);
+    asm.alu64_imm(BPF_RSH, R0, 32);
+    asm.store(BPF_W, R10, STACK_EVENT + EVENT_TGID, R0);
+    asm.store(BPF_W, R10, STACK_KEY, R0);
+    asm.load_map(R1, &maps.procs);
+    asm.mov64_reg(R2, R10);
+    asm.alu64_imm(BPF_ADD, R2, STACK_KEY.into());
+    asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    // R7 is the executable, 0 if no rule cares about it
+    asm.mov64_imm(R7, 0);
+    let unknown = asm.label();
+    asm.jump_imm(BPF_JEQ, R0, 0, unknown);
+    asm.load(BPF_W, R7, R0, 0);
+    asm.bind(unknown);
+
+    asm.call(BPF_FUNC_GET_CURRENT_UID_GID);
+    asm.store(BPF_DW, R10, STACK_EVENT + EVENT_IDS, R0);
+
+    asm.load(BPF_W, R1, R6, CTX_PROTOCOL);
+    asm.store(BPF_W, R10, STACK_EVENT + EVENT_PROTO, R1);
+    asm.load(BPF_W, R1, R6, CTX_USER_PORT);
+    asm.host_to_be(R1, 16);
+    asm.store(BPF_W, R10, STACK_EVENT + EVENT_PORT, R1);
+    asm.store_imm(BPF_W, R10, STACK_EVENT + EVENT_FAMILY, family);
+    for i in 0..4 {
+        let off = STACK_EVENT + EVENT_ADDR + i * 4;
+        if v6 {
+            asm.load(BPF_W, R1, R6, CTX_USER_IP6 + i * 4);
+            asm.store(BPF_W, R10, off, R1);
+        } else if i == 0 {
+            asm.load(BPF_W, R1, R6, CTX_USER_IP4);
+            asm.store(BPF_W, R10, off, R1);
+        } else {
+            asm.store_imm(BPF_W, R10, off, 0);
+        }
+    }
+
+    // R9 is the active half
+    asm.store_imm(BPF_W, R10, STACK_KEY, 0);
+    asm.load_map(R1, &maps.active);
+    asm.mov64_reg(R2, R10);
+    asm.alu64_imm(BPF_ADD, R2, STACK_KEY.into());
+    asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    asm.jump_imm(BPF_JEQ, R0, 0, allow);
+    asm.load(BPF_W, R9, R0, 0);
+    // R8 is the index in it
+    asm.mov64_imm(R8, 0);
+
+    asm.bind(lookup);
+    asm.jump_imm(BPF_JGE, R8, MAX_RULES as i32, allow);
+    asm.mov64_reg(R1, R9);
+    asm.alu64_reg(BPF_ADD, R1, R8);
+    asm.store(BPF_W, R10, STACK_KEY, R1);
+    asm.load_map(R1, &maps.rules);
+    asm.mov64_reg(R2, R10);
+    asm.alu64_imm(BPF_ADD, R2, STACK_KEY.into());
+    asm.call(BPF_FUNC_MAP_LOOKUP_ELEM);
+    asm.jump_imm(BPF_JEQ, R0, 0, allow);
+
+    let any_exe = asm.label();
+    asm.load(BPF_W, R1, R0, RULE_EXE);
+    asm.jump_imm(BPF_JEQ, R1, 0, any_exe);
+    asm.jump_reg(BPF_JNE, R1, R7, next);
+    asm.bind(any_exe);
+
+    let any_proto = asm.label();
+    asm.load(BPF_W, R1, R0, RULE_PROTO);
+    asm.jump_imm(BPF_JEQ, R1, 0, any_proto);
+    asm.load(BPF_W, R2, R10, STACK_EVENT + EVENT_PROTO);
+    asm.jump_reg(BPF_JNE, R1, R2, next);
+    asm.bind(any_proto);
+
+    let any_family = asm.label();
+    asm.load(BPF_W, R1, R0, RULE_FAMILY);
+    asm.jump_imm(BPF_JEQ, R1, 0, any_family);
+    asm.jump_imm(BPF_JNE, R1, family, next);
+    asm.bind(any_family);
+
+    // the verifier walks every round of the loop, a jump less here is 512 less there
+    asm.load(BPF_DW, R1, R10, STACK_EVENT + EVENT_IDS);
+    asm.load(BPF_DW, R2, R0, RULE_IDS_MASK);
+    asm.alu64_reg(BPF_AND, R1, R2);
+    asm.load(BPF_DW, R2, R0, RULE_IDS);
+    asm.jump_reg(BPF_JNE, R1, R2, next);
+
+    asm.load(BPF_W, R2, R10, STACK_EVENT + EVENT_PORT);
+    asm.load(BPF_H, R1, R0, RULE_PORT_START);
+    asm.jump_reg(BPF_JLT, R2, R1, next);
+    asm.load(BPF_H, R1, R0, RULE_PORT_END);
+    asm.jump_reg(BPF_JGT, R2, R1, next);
+
+    // the mask of an unused word is 0
+    for i in 0..4 {
+        asm.load(BPF_W, R1, R10, STACK_EVENT + EVENT_ADDR + i * 4);
+        asm.load(BPF_W, R2, R0, RULE_MASK + i * 4);
+        asm.alu64_reg(BPF_AND, R1, R2);
+        asm.load(BPF_W, R2, R0, RULE_ADDR + i * 4);
+        asm.jump_reg(BPF_JNE, R1, R2, next);
+    }
+
+    // matched, report it
+    asm.load(BPF_W, R1, R0, RULE_ID);
+    asm.store(BPF_W, R10, STACK_EVENT + EVENT_RULE, R1);
+    asm.load(BPF_W, R7, R0, RULE_TARGET);
+    asm.store(BPF_W, R10, STACK_EVENT + EVENT_TARGET, R7);
+    asm.load_map(R1, &maps.events);
+    asm.mov64_reg(R2, R10);
+    asm.alu64_imm(BPF_ADD, R2, STACK_EVENT.into());
+    asm.mov64_imm(R3, BPF_EXIST as i32);
+    asm.call(BPF_FUNC_MAP_PUSH_ELEM);
+    asm.mov64_imm(R0, 0);
+    asm.jump_imm(BPF_JNE, R7, TARGET_ACCEPT as i32, exit);
+    asm.mov64_imm(R0, 1);
+    asm.bind(exit);
+    asm.exit();
+
+    asm.bind(next);
+    asm.alu64_imm(BPF_ADD, R8, 1);
+    asm.jump(lookup);
+
+    // nothing to decide with
+    asm.bind(allow);
+    asm.mov64_imm(R0, 1);
+    asm.exit();
+
+    asm.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compile_rules() {
+        let rules = vec![
+            Rule {
+                device: Some(Device::Input),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                proto: Some(Proto::Tcp),
+                exe: Some("/usr/bin/curl".into()),
+                port: Some(80..=443),
+                subnet: Some(([10, 1, 2, 3].into(), 8)),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+            Rule {
+                user: Some(0),
+                target: RuleTarget::Ask,
+                ..Default::default()
+            },
+        ];
+        let mut remembered = HashMap::new();
+        remembered.insert("/usr/bin/wget".to_owned(), true);
+        let mut exes = HashMap::new();
+        let allowlist = [("::1".parse().unwrap(), 128)];
+        let (compiled, skipped) = compile(
+            &rules,
+            RuleTarget::Accept,
+            &allowlist,
+            &remembered,
+            &mut exes,
+        );
+        assert_eq!(exes.len(), 2);
+        assert_eq!(skipped, vec![0]);
+        assert_eq!(unenforced(&rules), Vec::<usize>::new());
+
+        assert_eq!(compiled.len(), 5);
+        assert_eq!(compiled[0].id, ALLOWLIST);
+        assert_eq!(compiled[0].family, libc::AF_INET6 as u32);
+        assert_eq!(compiled[0].mask, [u32::MAX; 4]);
+        assert_eq!(compiled[0].target, TARGET_ACCEPT);
+
+        let curl = compiled[1];
+        assert_eq!(curl.id, 1);
+        assert_eq!(curl.exe, exes["/usr/bin/curl"]);
+        assert_eq!(curl.proto, libc::IPPROTO_TCP as u32);
+        assert_eq!((curl.port_start, curl.port_end), (80, 443));
+        assert_eq!(curl.addr[0].to_ne_bytes(), [10, 0, 0, 0]);
+        assert_eq!(curl.mask[0].to_ne_bytes(), [255, 0, 0, 0]);
+        assert_eq!(curl.mask[1], 0);
+        assert_eq!(curl.target, TARGET_DROP);
+
+        // the answer goes before the rule that asks
+        assert_eq!(compiled[2].exe, exes["/usr/bin/wget"]);
+        assert_eq!((compiled[2].id, compiled[2].target), (2, TARGET_ACCEPT));
+        assert_eq!((compiled[3].exe, compiled[3].target), (0, TARGET_ASK));
+        assert_eq!((compiled[2].ids, compiled[3].ids), (0, 0));
+        assert_eq!(compiled[3].ids_mask, 0xffff_ffff);
+        assert_eq!(compiled[4].ids_mask, 0);
+
+        assert_eq!(compiled[4].id, NO_RULE);
+        assert_eq!(
+            (compiled[4].port_start, compiled[4].port_end),
+            (0, u16::MAX)
+        );
+    }
+
+    #[test]
+    fn unenforced_rules() {
+        let rules = vec![
+            Rule {
+                domain: Some("example.com".into()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                domain: Some("example.com".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                target: RuleTarget::RateLimit(0),
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/curl".into()),
+                target: RuleTarget::Ask,
+                ..Default::default()
+            },
+        ];
+        // skipping an accepting rule refuses more, not less
+        assert_eq!(unenforced(&rules), vec![1, 2]);
+    }
+}
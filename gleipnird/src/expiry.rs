@@ -0,0 +1,144 @@
+//! Temporary rules, removed once they expire
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use gleipnir_interface::{Expiry, Rule};
+
+use crate::proc;
+
+/// How often rules are checked, they still apply until then
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Turns `Expiry::For` into `Expiry::At` and records the start times of `Expiry::ProcessExit`,
+/// returns if any was
+pub fn normalize(rules: &mut [Rule]) -> bool {
+    let now = now();
+    let mut changed = false;
+    for rule in rules {
+        rule.expiry = match rule.expiry {
+            Some(Expiry::For(secs)) => Some(Expiry::At(now + secs)),
+            Some(Expiry::ProcessExit {
+                pid,
+                start_time: None,
+            }) => match proc::get_start_time(pid as usize) {
+                Some(start_time) => Some(Expiry::ProcessExit {
+                    pid,
+                    start_time: Some(start_time),
+                }),
+                // gone already, the rule is expired
+                None => continue,
+            },
+            _ => continue,
+        };
+        changed = true;
+    }
+    changed
+}
+
+/// Returns if any rule was removed, `rules` have to be normalized
+pub fn remove_expired(rules: &mut Vec<Rule>) -> bool {
+    let now = now();
+    let len = rules.len();
+    rules.retain(|rule| match rule.expiry {
+        Some(expiry) => !is_expired(expiry, now),
+        None => true,
+    });
+    rules.len() != len
+}
+
+fn is_expired(expiry: Expiry, now: u64) -> bool {
+    match expiry {
+        Expiry::At(time) => time <= now,
+        Expiry::For(_) | Expiry::Restart => false,
+        // the PID may be reused once the process exits
+        Expiry::ProcessExit { pid, start_time } => {
+            start_time.is_none() || proc::get_start_time(pid as usize) != start_time
+        }
+    }
+}
+
+/// Removes the rules lasting until the daemon restarts or a process exits, which are not saved
+pub fn remove_volatile(rules: &mut Vec<Rule>) {
+    rules.retain(|rule| !rule.expiry.map_or(false, |e| e.is_volatile()));
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn remove_expired() {
+        let rule = |expiry| Rule {
+            expiry,
+            ..Default::default()
+        };
+        let exit = |pid| Expiry::ProcessExit {
+            pid,
+            start_time: None,
+        };
+        let mut rules = vec![
+            rule(None),
+            rule(Some(Expiry::At(0))),
+            rule(Some(Expiry::For(60))),
+            rule(Some(Expiry::Restart)),
+            rule(Some(exit(process::id()))),
+            // above `pid_max`
+            rule(Some(exit(u32::MAX))),
+        ];
+        let start = now();
+        assert!(normalize(&mut rules));
+        assert!(!normalize(&mut rules));
+        assert!(super::remove_expired(&mut rules));
+        assert_eq!(rules.len(), 4);
+        match rules[1].expiry {
+            Some(Expiry::At(time)) => assert!(time >= start + 60 && time <= now() + 60),
+            _ => panic!(),
+        }
+        let start_time = proc::get_start_time(process::id() as usize);
+        assert!(start_time.is_some());
+        assert_eq!(
+            rules[3].expiry,
+            Some(Expiry::ProcessExit {
+                pid: process::id(),
+                start_time
+            })
+        );
+        assert!(!super::remove_expired(&mut rules));
+
+        // another process got the PID
+        rules[3].expiry = Some(Expiry::ProcessExit {
+            pid: process::id(),
+            start_time: start_time.map(|t| t + 1),
+        });
+        assert!(super::remove_expired(&mut rules));
+        assert_eq!(rules.len(), 3);
+    }
+
+    #[test]
+    fn remove_volatile() {
+        let rule = |expiry| Rule {
+            expiry,
+            ..Default::default()
+        };
+        let mut rules = vec![
+            rule(None),
+            rule(Some(Expiry::At(0))),
+            rule(Some(Expiry::Restart)),
+            rule(Some(Expiry::ProcessExit {
+                pid: process::id(),
+                start_time: None,
+            })),
+        ];
+        // what a new profile starts from
+        super::remove_volatile(&mut rules);
+        assert_eq!(rules, vec![rule(None), rule(Some(Expiry::At(0)))]);
+    }
+}
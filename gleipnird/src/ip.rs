@@ -0,0 +1,192 @@
+use std::net::IpAddr;
+
+use pnet::packet::{
+    ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    ipv4::{Ipv4Flags, Ipv4Packet},
+    ipv6::Ipv6Packet,
+};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fragment {
+    /// Not fragmented
+    Whole,
+    /// The first fragment, has the transport header
+    First(u32),
+    /// No transport header, should inherit the verdict of the first one
+    Rest(u32),
+}
+
+/// Identifies all fragments of a packet. Without the protocol, only the first fragment
+/// knows the one after the IPv6 extension headers following the Fragment header
+pub type FragmentKey = (IpAddr, IpAddr, u32);
+
+pub struct IpHeader<'a> {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// The protocol after all IPv6 extension headers
+    pub protocol: IpNextHeaderProtocol,
+    pub payload: &'a [u8],
+    pub fragment: Fragment,
+}
+
+impl<'a> IpHeader<'a> {
+    pub fn fragment_key(&self) -> Option<FragmentKey> {
+        match self.fragment {
+            Fragment::Whole => None,
+            Fragment::First(id) | Fragment::Rest(id) => Some((self.src, self.dst, id)),
+        }
+    }
+}
+
+/// Returns `None` if the headers are longer than the part copied to us
+pub fn parse(packet: &[u8]) -> Option<IpHeader> {
+    match packet[0] >> 4 {
+        4 => {
+            let pkt = Ipv4Packet::new(packet).expect("Ipv4Packet");
+            // in 32 bit words, options come after the fixed part
+            let header_len = pkt.get_header_length() as usize * 4;
+            let more = pkt.get_flags() & Ipv4Flags::MoreFragments != 0;
+            let id = pkt.get_identification().into();
+            let fragment = match (pkt.get_fragment_offset(), more) {
+                (0, false) => Fragment::Whole,
+                (0, true) => Fragment::First(id),
+                _ => Fragment::Rest(id),
+            };
+            Some(IpHeader {
+                src: pkt.get_source().into(),
+                dst: pkt.get_destination().into(),
+                protocol: pkt.get_next_level_protocol(),
+                payload: packet.get(header_len..)?,
+                fragment,
+            })
+        }
+        6 => {
+            let pkt = Ipv6Packet::new(packet).expect("Ipv6Packet");
+            let (protocol, payload, fragment) = skip_extension_headers(
+                pkt.get_next_header(),
+                &packet[Ipv6Packet::minimum_packet_size()..],
+            )?;
+            Some(IpHeader {
+                src: pkt.get_source().into(),
+                dst: pkt.get_destination().into(),
+                protocol,
+                payload,
+                fragment,
+            })
+        }
+        _ => unreachable!("package is neither IPv4 nor IPv6"),
+    }
+}
+
+/// The source and destination ports at the beginning of SCTP and DCCP headers
+pub fn ports(payload: &[u8]) -> Option<(u16, u16)> {
+    let header = payload.get(..4)?;
+    Some((
+        u16::from_be_bytes([header[0], header[1]]),
+        u16::from_be_bytes([header[2], header[3]]),
+    ))
+}
+
+fn skip_extension_headers(
+    mut next: IpNextHeaderProtocol,
+    mut payload: &[u8],
+) -> Option<(IpNextHeaderProtocol, &[u8], Fragment)> {
+    let mut fragment = Fragment::Whole;
+    loop {
+        let len = match next {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts => (*payload.get(1)? as usize + 1) * 8,
+            IpNextHeaderProtocols::Ah => (*payload.get(1)? as usize + 2) * 4,
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let header = payload.get(..8)?;
+                let offset = u16::from_be_bytes([header[2], header[3]]) >> 3;
+                let more = header[3] & 1 != 0;
+                let id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+                fragment = match (offset, more) {
+                    (0, false) => Fragment::Whole,
+                    (0, true) => Fragment::First(id),
+                    _ => Fragment::Rest(id),
+                };
+                8
+            }
+            _ => return Some((next, payload, fragment)),
+        };
+        let header = payload.get(..len)?;
+        next = IpNextHeaderProtocol(header[0]);
+        payload = &payload[len..];
+        if let Fragment::Rest(_) = fragment {
+            // the rest is a piece of the next header
+            return Some((next, payload, fragment));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ipv4_options() {
+        let mut packet = vec![0u8; 24 + 4];
+        packet[0] = 0x46; // version 4, 6 words
+        packet[9] = IpNextHeaderProtocols::Udp.0;
+        packet[24..28].copy_from_slice(&[0, 53, 0, 80]);
+        let header = parse(&packet).unwrap();
+        assert_eq!(header.protocol, IpNextHeaderProtocols::Udp);
+        assert_eq!(header.payload, &[0, 53, 0, 80]);
+        assert_eq!(header.fragment, Fragment::Whole);
+
+        // offset 8, no more fragments
+        packet[6..8].copy_from_slice(&[0, 1]);
+        assert_eq!(parse(&packet).unwrap().fragment, Fragment::Rest(0));
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        let mut packet = vec![0u8; 40 + 8 + 8 + 4];
+        packet[0] = 0x60;
+        packet[6] = IpNextHeaderProtocols::Hopopt.0;
+        // hop-by-hop, 8 bytes
+        packet[40] = IpNextHeaderProtocols::Ipv6Frag.0;
+        // fragment, first one with more fragments
+        packet[48] = IpNextHeaderProtocols::Tcp.0;
+        packet[51] = 1;
+        packet[52..56].copy_from_slice(&[0, 0, 0, 7]);
+        packet[56..60].copy_from_slice(&[0, 22, 0, 80]);
+        let header = parse(&packet).unwrap();
+        assert_eq!(header.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(header.payload, &[0, 22, 0, 80]);
+        assert_eq!(header.fragment, Fragment::First(7));
+
+        // truncated
+        assert!(parse(&packet[..44]).is_none());
+
+        // destination options after the fragment header
+        let mut first = vec![0u8; 40 + 8 + 8 + 4];
+        first[0] = 0x60;
+        first[6] = IpNextHeaderProtocols::Ipv6Frag.0;
+        first[40] = IpNextHeaderProtocols::Ipv6Opts.0;
+        first[43] = 1;
+        first[44..48].copy_from_slice(&[0, 0, 0, 7]);
+        first[48] = IpNextHeaderProtocols::Udp.0;
+        let first = parse(&first).unwrap();
+        assert_eq!(first.protocol, IpNextHeaderProtocols::Udp);
+        let mut rest = vec![0u8; 40 + 8 + 4];
+        rest[0] = 0x60;
+        rest[6] = IpNextHeaderProtocols::Ipv6Frag.0;
+        rest[40] = IpNextHeaderProtocols::Ipv6Opts.0;
+        // offset 8, no more fragments
+        rest[42..44].copy_from_slice(&[0, 8]);
+        rest[44..48].copy_from_slice(&[0, 0, 0, 7]);
+        let rest = parse(&rest).unwrap();
+        assert_eq!(rest.fragment, Fragment::Rest(7));
+        assert_eq!(first.fragment_key(), rest.fragment_key());
+    }
+
+    #[test]
+    fn short_ports() {
+        assert_eq!(ports(&[0, 22, 0, 80, 1]), Some((22, 80)));
+        assert_eq!(ports(&[0, 22]), None);
+    }
+}
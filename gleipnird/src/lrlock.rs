@@ -1,73 +1,84 @@
-use std::marker::PhantomData;
+use std::cell::UnsafeCell;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread::panicking;
+use std::thread;
 
-/// A wait-free read lock
+/// A lock-free read lock, readers never wait for the setter or each other
 pub struct LeftRightLock<T> {
-    left: T,
-    right: Option<T>,
-    state: State,
+    values: [UnsafeCell<Option<T>>; 2],
+    /// The side new readers go to
+    side: AtomicBool,
+    readers: [AtomicUsize; 2],
 }
 
 impl<T> LeftRightLock<T> {
     pub fn new(v: T) -> (Reader<T>, Setter<T>) {
         let inner = LeftRightLock {
-            left: v,
-            right: None,
-            state: State::new(true),
+            values: [UnsafeCell::new(Some(v)), UnsafeCell::new(None)],
+            side: AtomicBool::new(false),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
         };
         let inner = Arc::new(inner);
-        (
-            Reader(inner.clone(), PhantomData),
-            Setter(inner, PhantomData),
-        )
+        (Reader(inner.clone()), Setter(inner))
     }
 }
 
-pub struct Reader<T>(Arc<LeftRightLock<T>>, PhantomData<*const ()>);
-unsafe impl<T: Send> Send for Reader<T> {}
+/// Can be cloned to every thread that needs to read
+pub struct Reader<T>(Arc<LeftRightLock<T>>);
+unsafe impl<T: Send + Sync> Send for Reader<T> {}
 
-pub struct Setter<T>(Arc<LeftRightLock<T>>, PhantomData<*const ()>);
-unsafe impl<T: Send> Send for Setter<T> {}
+impl<T> Clone for Reader<T> {
+    fn clone(&self) -> Self {
+        Reader(self.0.clone())
+    }
+}
+
+/// There is only one setter, it is not `Clone`
+pub struct Setter<T>(Arc<LeftRightLock<T>>);
+unsafe impl<T: Send + Sync> Send for Setter<T> {}
 
 impl<T> Reader<T> {
     pub fn read(&self) -> ReadGuard<T> {
-        let side = unsafe { self.0.state.set_read() };
+        let lock = &*self.0;
+        let side = loop {
+            let side = lock.side.load(Ordering::SeqCst);
+            lock.readers[side as usize].fetch_add(1, Ordering::SeqCst);
+            // the setter may have swapped and waited for this side before we were counted
+            if lock.side.load(Ordering::SeqCst) == side {
+                break side as usize;
+            }
+            lock.readers[side as usize].fetch_sub(1, Ordering::SeqCst);
+        };
 
         ReadGuard {
-            value: if side {
-                &self.0.left
-            } else {
-                self.0
-                    .right
-                    .as_ref()
-                    .expect("unreachable LeftRightLock state")
-            },
-            state: &self.0.state,
+            value: unsafe { &*lock.values[side].get() }
+                .as_ref()
+                .expect("unreachable LeftRightLock state"),
+            readers: &lock.readers[side],
         }
     }
 }
 
 impl<T> Setter<T> {
     pub fn set(&self, value: T) {
+        let lock = &*self.0;
+        let current = lock.side.load(Ordering::SeqCst);
+        let next = !current;
+        // nobody is reading the other side, the last `set` waited for them
         unsafe {
-            self.0.state.swap_side(|current_side_a| {
-                let ptr = (&*self.0) as *const LeftRightLock<T> as *mut LeftRightLock<T>;
-                if current_side_a {
-                    (*ptr).right = Some(value);
-                } else {
-                    (*ptr).left = value;
-                };
-            });
+            *lock.values[next as usize].get() = Some(value);
+        }
+        lock.side.store(next, Ordering::SeqCst);
+        while lock.readers[current as usize].load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
         }
     }
 }
 
 pub struct ReadGuard<'a, T> {
     value: &'a T,
-    state: &'a State,
+    readers: &'a AtomicUsize,
 }
 
 impl<'a, T> Deref for ReadGuard<'a, T> {
@@ -79,49 +90,7 @@ impl<'a, T> Deref for ReadGuard<'a, T> {
 
 impl<'a, T> Drop for ReadGuard<'a, T> {
     fn drop(&mut self) {
-        if !panicking() {
-            unsafe { self.state.unset_read() }
-        }
-    }
-}
-
-struct State {
-    state: AtomicUsize,
-}
-
-impl State {
-    fn new(side: bool) -> Self {
-        State {
-            state: AtomicUsize::new(if side { 0b000 } else { 0b100 }),
-        }
-    }
-    unsafe fn set_read(&self) -> bool {
-        match self.state.fetch_add(1, Ordering::AcqRel) {
-            0b000 => true,
-            0b100 => false,
-            0b001 | 0b101 => panic!("LeftRightLock can only have one reader"),
-            _ => unreachable!(),
-        }
-    }
-    unsafe fn unset_read(&self) {
-        match self.state.fetch_sub(1, Ordering::AcqRel) {
-            0b001 | 0b101 => (),
-            _ => unreachable!(),
-        }
-    }
-    unsafe fn swap_side<F: FnOnce(bool)>(&self, f: F) {
-        // Apply the mask to get a "not reading" value
-        let current_side = self.state.load(Ordering::Acquire) & 0b100;
-        let next_side = current_side ^ 0b100;
-
-        f(current_side == 0);
-
-        // swap to next side when current side is not reading
-        while self
-            .state
-            .compare_and_swap(current_side, next_side, Ordering::AcqRel)
-            != current_side
-        {}
+        self.readers.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
@@ -138,10 +107,30 @@ mod test {
         assert_eq!(*r.read(), 2);
     }
     #[test]
-    #[should_panic(expected = "LeftRightLock can only have one reader")]
-    fn two_reader() {
-        let (r, _s) = LeftRightLock::new(0);
-        let _a = r.read();
-        r.read();
+    fn many_readers() {
+        let (r, s) = LeftRightLock::new(0);
+        let a = r.read();
+        let b = r.clone();
+        assert_eq!(*b.read(), 0);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let r = r.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while last < 100 {
+                        let v = *r.read();
+                        assert!(v >= last);
+                        last = v;
+                    }
+                })
+            })
+            .collect();
+        drop(a);
+        for i in 1..=100 {
+            s.set(i);
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
     }
 }
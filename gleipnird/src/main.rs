@@ -5,53 +5,123 @@
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+use std::cmp;
 use std::collections::hash_map::DefaultHasher;
+use std::env;
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crossbeam_channel;
-use gleipnir_interface::{Device, PackageReport, Proto};
+use gleipnir_interface::{AskAnswer, Device, PackageReport, ProcessInfo, Proto};
 use lru_time_cache::LruCache;
 use nfq;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::unistd::Uid;
 use pnet::packet::{
-    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket,
+    icmp::IcmpPacket,
+    ip::IpNextHeaderProtocols,
+    tcp::{TcpFlags, TcpPacket},
+    udp::UdpPacket,
 };
 
 #[macro_use]
 mod utils;
+mod ask;
+mod bpf;
 mod config;
+mod dns;
+mod ebpf;
+mod expiry;
+mod ip;
 mod lrlock;
 mod netfilter;
 mod netlink;
+mod netns;
+mod nftables;
 mod polkit;
 mod proc;
+mod proc_events;
+mod quic;
 pub mod rpc_server;
 mod rules;
+mod server_name;
 
-use rules::IndexedRules;
+use ask::{Answer, AnswerSender, Ask, Hold, PendingVerdicts, Question};
+use config::{Backend, FailPolicy};
+use ip::{Fragment, FragmentKey};
+use rules::{IndexedRules, MatchCache, Verdict};
 
+/// The first queue, every worker has one after it
 const QUEUE_ID: u16 = 786;
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+const ECHOV6_REQUEST: u8 = 128;
+const ECHOV6_REPLY: u8 = 129;
+/// Headers with a DNS message of 512 bytes
+const COPY_RANGE: u16 = 640;
+/// Same as the default `ipfrag_time`
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Names told by clients are kept while their flows are active
+const FLOW_NAME_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How often to check for answers while some packets are waiting for them
+const ANSWER_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 struct State {
     diag: netlink::SockDiag,
+    /// Of containers, for forwarded packets
+    netns: netns::Namespaces,
     rules: lrlock::Reader<IndexedRules>,
     pkt_logs: crossbeam_channel::Sender<PackageReport>,
+    asks: crossbeam_channel::Sender<Ask>,
+    pending: PendingVerdicts,
     cache: LruCache<u64, proc::Process>,
+    matches: MatchCache,
+    dns: Arc<RwLock<dns::Cache>>,
+    /// Read server names from the first data of connections
+    hostnames: bool,
+    /// Names told by clients, by protocol, local and remote address. `None` if the first data
+    /// of the client had none
+    flow_names: LruCache<(Proto, SocketAddr, SocketAddr), Option<String>>,
+    /// Reports of first fragments
+    fragments: LruCache<FragmentKey, PackageReport>,
+    hooks: Option<Arc<netfilter::NfqueueGuard>>,
+    fail_policy: FailPolicy,
 }
 
 impl State {
+    /// For packets we can't parse, the same as when the daemon is not running
+    fn fail_verdict(&self) -> nfq::Verdict {
+        match self.fail_policy {
+            FailPolicy::Open => nfq::Verdict::Accept,
+            FailPolicy::Closed => nfq::Verdict::Drop,
+        }
+    }
+    /// Mark for accepted connections, so the rest of them skip the queue
+    fn ct_accept_mark(&self) -> Option<u32> {
+        let generation = self.rules.read().generation();
+        self.hooks.as_ref()?.ct_accept_mark(generation)
+    }
+    /// Mark for dropped connections, so the rest of them are dropped in the kernel
+    fn ct_drop_mark(&self) -> Option<u32> {
+        let generation = self.rules.read().generation();
+        self.hooks.as_ref()?.ct_drop_mark(generation)
+    }
     fn query_process_cached(
         &mut self,
         device: Device,
         protocol: Proto,
         src: SocketAddr,
         dst: SocketAddr,
+        forwarded: bool,
     ) -> Result<proc::Process, io::Error> {
         let mut hasher = DefaultHasher::new();
-        (device, protocol, src, dst).hash(&mut hasher);
+        (device, protocol, src, dst, forwarded).hash(&mut hasher);
         let lru_index = hasher.finish();
 
         self.cache
@@ -59,22 +129,24 @@ impl State {
             .cloned()
             .map(Ok)
             .unwrap_or_else(|| {
-                let result = self.query_process(device, protocol, src, dst)?;
+                let result = self.query_process(device, protocol, src, dst, forwarded)?;
                 self.cache.insert(lru_index, result.clone());
                 Ok(result)
             })
     }
+    /// Forwarded packets belong to sockets in other network namespaces
     fn query_process(
         &mut self,
         device: Device,
         protocol: Proto,
         src: SocketAddr,
         dst: SocketAddr,
+        forwarded: bool,
     ) -> Result<proc::Process, io::Error> {
         let mut possible_sockets: [Option<(_, _)>; 3] = [None; 3];
 
         match protocol {
-            Proto::Tcp => {
+            Proto::Tcp | Proto::Dccp => {
                 if device.is_input() {
                     // for INPUT, dst is loacal address, src is remote address
                     possible_sockets[0] = Some((dst, src));
@@ -82,7 +154,9 @@ impl State {
                     possible_sockets[0] = Some((src, dst));
                 }
             }
-            Proto::Udp | Proto::UdpLite => {
+            // one-to-many SCTP sockets are like UDP
+            // ping sockets are bound like UDP, the echo identifier is the port
+            Proto::Udp | Proto::UdpLite | Proto::Sctp | Proto::Icmp | Proto::IcmpV6 => {
                 // for UDP listener, the remote address is unspecified
                 let unspecified_addr = if src.is_ipv4() {
                     Ipv4Addr::UNSPECIFIED.into()
@@ -114,7 +188,12 @@ impl State {
             .take_while(|x| Option::is_some(x))
             .map(|x| x.as_ref().unwrap())
         {
-            match self.diag.query(protocol, local_address, remote_address) {
+            let result = if forwarded {
+                self.netns.query(protocol, local_address, remote_address)
+            } else {
+                self.diag.query(protocol, local_address, remote_address)
+            };
+            match result {
                 Ok(r) => diag_msg = Some(r),
                 Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
                 Err(e) => return Err(e),
@@ -122,81 +201,173 @@ impl State {
             break;
         }
 
-        let diag_msg = match diag_msg {
-            Some(r) => r,
+        let (inode, uid) = match diag_msg {
+            Some(r) => (r.idiag_inode, Some(r.idiag_uid)),
+            // raw sockets, and ping sockets if the kernel can't diag them
+            None if protocol.is_icmp() && !forwarded => {
+                let (local, remote) = possible_sockets[0].expect("no possible socket");
+                let (ping, raw) = if local.is_ipv4() {
+                    ("icmp", "raw")
+                } else {
+                    ("icmp6", "raw6")
+                };
+                let raw_local = SocketAddr::new(local.ip(), protocol as u16);
+                let inode = match proc::find_socket_inode(ping, local, remote.ip())? {
+                    Some(r) => r,
+                    None => proc::find_socket_inode(raw, raw_local, remote.ip())?
+                        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?,
+                };
+                (inode, None)
+            }
             None => return Err(io::ErrorKind::NotFound.into()),
         };
 
-        proc::get_proc_by_inode(diag_msg.idiag_inode).ok_or_else(|| io::ErrorKind::NotFound.into())
+        proc::get_proc_by_inode(inode, uid).ok_or_else(|| io::ErrorKind::NotFound.into())
     }
 }
 
-fn queue_callback(msg: &mut nfq::Message, state: &mut State) {
-    let device = if msg.get_indev() != 0 {
-        Device::Input
-    } else if msg.get_outdev() != 0 {
-        Device::Output
-    } else {
-        unreachable!("package is from neither INPUT nor OUTPUT");
+/// Returns the report of the packet if it needs to be held until the user answers, with the
+/// generation of the rules which asked
+fn queue_callback(msg: &mut nfq::Message, state: &mut State) -> Option<(PackageReport, u32)> {
+    let (mut device, forwarded) = match (msg.get_indev() != 0, msg.get_outdev() != 0) {
+        // sent or received by a container, tried in both directions
+        (true, true) => (Device::Output, true),
+        (true, false) => (Device::Input, false),
+        (false, true) => (Device::Output, false),
+        (false, false) => unreachable!("package is from neither INPUT nor OUTPUT"),
     };
 
     let payload = msg.get_payload();
-    let (saddr, daddr, protocol, ip_payload) = match payload[0] >> 4 {
-        4 => {
-            let pkt = Ipv4Packet::new(payload).expect("Ipv4Packet");
-            let src: IpAddr = pkt.get_source().into();
-            let dst: IpAddr = pkt.get_destination().into();
-            (
-                src,
-                dst,
-                pkt.get_next_level_protocol(),
-                &payload[Ipv4Packet::minimum_packet_size()..],
-            )
-        }
-        6 => {
-            let pkt = Ipv6Packet::new(payload).expect("Ipv6Packet");
-            let src: IpAddr = pkt.get_source().into();
-            let dst: IpAddr = pkt.get_destination().into();
-            (
-                src,
-                dst,
-                pkt.get_next_header(),
-                &payload[Ipv6Packet::minimum_packet_size()..],
-            )
+    let ip = match ip::parse(payload) {
+        Some(r) => r,
+        None => {
+            // too many extension headers
+            msg.set_verdict(state.fail_verdict());
+            return None;
         }
-        _ => unreachable!("package is neither IPv4 nor IPv6"),
     };
+    let fragment_key = ip.fragment_key();
+    if let Fragment::Rest(_) = ip.fragment {
+        let key = fragment_key.expect("fragment without key");
+        inherit_fragment_verdict(msg, state, key);
+        return None;
+    }
+    let (saddr, daddr, protocol, ip_payload) = (ip.src, ip.dst, ip.protocol, ip.payload);
 
-    let (protocol, sport, dport) = match protocol {
+    // `data` is the payload of TCP and UDP
+    let (protocol, sport, dport, icmp, data) = match protocol {
         IpNextHeaderProtocols::Tcp => {
-            let pkt = TcpPacket::new(ip_payload).expect("TcpPacket");
+            // first fragments may be cut anywhere
+            let pkt = match TcpPacket::new(ip_payload) {
+                Some(r) => r,
+                None => {
+                    msg.set_verdict(state.fail_verdict());
+                    return None;
+                }
+            };
             let (sport, dport) = (pkt.get_source(), pkt.get_destination());
-            (Proto::Tcp, sport, dport)
+            let data = ip_payload
+                .get(pkt.get_data_offset() as usize * 4..)
+                .unwrap_or_default();
+            (Proto::Tcp, sport, dport, None, data)
         }
         IpNextHeaderProtocols::Udp | IpNextHeaderProtocols::UdpLite => {
-            let pkt = UdpPacket::new(ip_payload).expect("UdpPacket");
+            let pkt = match UdpPacket::new(ip_payload) {
+                Some(r) => r,
+                None => {
+                    msg.set_verdict(state.fail_verdict());
+                    return None;
+                }
+            };
             let (sport, dport) = (pkt.get_source(), pkt.get_destination());
             let p = if protocol == IpNextHeaderProtocols::Udp {
                 Proto::Udp
             } else {
                 Proto::UdpLite
             };
-            (p, sport, dport)
+            let data = ip_payload.get(8..).unwrap_or_default();
+            (p, sport, dport, None, data)
+        }
+        IpNextHeaderProtocols::Sctp | IpNextHeaderProtocols::Dccp => {
+            let (sport, dport) = match ip::ports(ip_payload) {
+                Some(r) => r,
+                None => {
+                    msg.set_verdict(state.fail_verdict());
+                    return None;
+                }
+            };
+            let p = if protocol == IpNextHeaderProtocols::Sctp {
+                Proto::Sctp
+            } else {
+                Proto::Dccp
+            };
+            (p, sport, dport, None, &[][..])
+        }
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+            let pkt = match IcmpPacket::new(ip_payload) {
+                Some(r) => r,
+                None => {
+                    msg.set_verdict(state.fail_verdict());
+                    return None;
+                }
+            };
+            let (kind, code) = (pkt.get_icmp_type().0, pkt.get_icmp_code().0);
+            let (p, echo) = if protocol == IpNextHeaderProtocols::Icmp {
+                (Proto::Icmp, [ECHO_REQUEST, ECHO_REPLY])
+            } else {
+                (Proto::IcmpV6, [ECHOV6_REQUEST, ECHOV6_REPLY])
+            };
+            // in echo messages of both versions, the identifier follows the checksum
+            let id = if echo.contains(&kind) {
+                match ip_payload.get(4..6) {
+                    Some(id) => u16::from_be_bytes([id[0], id[1]]),
+                    None => {
+                        msg.set_verdict(state.fail_verdict());
+                        return None;
+                    }
+                }
+            } else {
+                0
+            };
+            // the local side is bound to the identifier, the remote port is always 0
+            let (sport, dport) = if device.is_input() { (0, id) } else { (id, 0) };
+            (p, sport, dport, Some((kind, code)), &[][..])
         }
         _ => {
             // ignore other protocol
             msg.set_verdict(nfq::Verdict::Accept);
-            return;
+            return None;
         }
     };
-    let (src, dst) = (SocketAddr::new(saddr, sport), SocketAddr::new(daddr, dport));
+    let (mut src, mut dst) = (SocketAddr::new(saddr, sport), SocketAddr::new(daddr, dport));
+    // matched with the query before the process is known, it may be gone already
+    if sport == dns::PORT && (device.is_input() || forwarded) {
+        if let Some(answer) = dns_message(protocol, data) {
+            let mut dns = state.dns.write().unwrap();
+            dns.insert_response(protocol, dst, src, answer);
+        }
+    }
 
-    let proc = match state.query_process_cached(device, protocol, src, dst) {
+    let mut result = state.query_process_cached(device, protocol, src, dst, forwarded);
+    match result {
+        Err(ref e) if forwarded && e.kind() == io::ErrorKind::NotFound => {
+            // not sent by a container, maybe received by one
+            device = Device::Input;
+            if protocol.is_icmp() {
+                // the echo identifier is the local port
+                src.set_port(dport);
+                dst.set_port(sport);
+            }
+            result = state.query_process_cached(device, protocol, src, dst, forwarded);
+        }
+        _ => (),
+    }
+    let proc = match result {
         Ok(r) => r,
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
             eprintln!("NOT FOUND: {:?},\t{},\t{},\t{}", device, protocol, src, dst);
             msg.set_verdict(nfq::Verdict::Accept);
-            return;
+            return None;
         }
         Err(e) => {
             eprintln!(
@@ -204,69 +375,435 @@ fn queue_callback(msg: &mut nfq::Message, state: &mut State) {
                 e, device, protocol, src, dst
             );
             msg.set_verdict(nfq::Verdict::Accept);
-            return;
+            return None;
         }
     };
 
+    if dport == dns::PORT && sport != dns::PORT && !device.is_input() {
+        if let Some(query) = dns_message(protocol, data) {
+            let scope = dns::Scope::of(proc.pid, &proc.cgroup);
+            let mut dns = state.dns.write().unwrap();
+            dns.insert_query(protocol, src, dst, query, scope);
+        }
+    }
+
+    // from the side of the local socket
+    let flow = if device.is_input() {
+        (protocol, dst, src)
+    } else {
+        (protocol, src, dst)
+    };
+    if state.hostnames
+        && !device.is_input()
+        && !data.is_empty()
+        && !state.flow_names.contains_key(&flow)
+    {
+        // claimed by the client, nothing proves the server has the name
+        let name = server_name::parse(protocol, data);
+        state.flow_names.insert(flow, name);
+    }
+    // TCP connections are followed until the first data of the client
+    let awaiting_name = state.hostnames
+        && protocol == Proto::Tcp
+        && data.is_empty()
+        && !state.flow_names.contains_key(&flow);
+    // Rules of the name can't be checked before it's told, the handshake of connections we
+    // open is let through until then. SYN-ACKs are the only packets received in it
+    let deferred = awaiting_name
+        && (!device.is_input()
+            || TcpPacket::new(ip_payload).map_or(false, |pkt| {
+                let syn_ack = TcpFlags::SYN | TcpFlags::ACK;
+                pkt.get_flags() & syn_ack == syn_ack
+            }));
+
     let rule_addr = if device.is_input() { src } else { dst };
+    let mut domains = state.dns.read().unwrap().names(rule_addr.ip(), proc.pid);
+    // told by the program itself, it may resolve names without us seeing it
+    if let Some(Some(name)) = state.flow_names.get(&flow) {
+        domains.retain(|domain| domain != name);
+        domains.insert(0, name.clone());
+    }
     let rules = state.rules.read();
-    let (rule_id, accept) =
-        rules.is_acceptable(device, protocol, rule_addr, payload.len(), &proc.exe);
+    let generation = rules.generation();
+    // hashes are cached, but a stat is still more than most packets deserve
+    let sha256 = if rules.is_pinned(&proc.exe) {
+        proc::get_exe_sha256(proc.pid)
+    } else {
+        None
+    };
+    let broken_pin = sha256
+        .as_deref()
+        .and_then(|sha256| rules.broken_pin(&proc.exe, sha256));
+    let (rule_id, verdict) = rules.is_acceptable(
+        &mut state.matches,
+        device,
+        protocol,
+        rule_addr,
+        &domains,
+        icmp,
+        payload.len(),
+        ProcessInfo {
+            exe: &proc.exe,
+            sha256: sha256.as_deref(),
+            app: &proc.app,
+            cmdline: &proc.cmdline,
+            uid: proc.uid,
+            gid: proc.gid,
+            ancestors: &proc.ancestors,
+            cgroup: &proc.cgroup,
+            unit: &proc.unit,
+        },
+    );
+    let rate_limited = rules.is_rate_limited(rule_id);
+    drop(rules);
+    let verdict = match verdict {
+        Verdict::Ask => match state.pending.remembered(&proc.exe) {
+            Some(true) => Verdict::Accept,
+            Some(false) => Verdict::Drop,
+            None => Verdict::Ask,
+        },
+        v => v,
+    };
+    // never remembered by conntrack, the first data is judged again
+    let verdict = if deferred { Verdict::Accept } else { verdict };
 
     let log = PackageReport {
         device,
         protocol,
         addr: rule_addr,
+        domain: domains.into_iter().next(),
         len: msg.get_original_len(),
         exe: proc.exe,
-        dropped: !accept,
+        app: proc.app,
+        sha256,
+        cmdline: proc.cmdline,
+        uid: proc.uid,
+        gid: proc.gid,
+        user: proc.user,
+        ancestors: proc.ancestors,
+        cgroup: proc.cgroup,
+        unit: proc.unit,
+        dropped: verdict != Verdict::Accept,
         matched_rule: rule_id,
+        broken_pin,
     };
+    // held ones are not remembered, the rest will be accepted and wait for it
+    match (fragment_key, verdict) {
+        (_, Verdict::Ask) | (None, _) => (),
+        (Some(key), _) => {
+            state.fragments.insert(key, log.clone());
+        }
+    }
 
-    if accept {
-        msg.set_verdict(nfq::Verdict::Accept);
-    } else {
-        msg.set_verdict(nfq::Verdict::Drop);
+    // rate limits count every packet
+    let remember = !rate_limited && !awaiting_name;
+    match verdict {
+        Verdict::Accept => {
+            if let Some(mark) = state.ct_accept_mark().filter(|_| remember) {
+                set_mark(msg, mark);
+            }
+            msg.set_verdict(nfq::Verdict::Accept)
+        }
+        Verdict::Drop => match state.ct_drop_mark().filter(|_| remember) {
+            // dropped by our hooks, after the connection remembers it
+            Some(mark) => {
+                set_mark(msg, mark);
+                msg.set_verdict(nfq::Verdict::Accept)
+            }
+            None => msg.set_verdict(nfq::Verdict::Drop),
+        },
+        // the reject rules are installed with our hooks
+        Verdict::Reject if state.hooks.is_some() => {
+            set_mark(msg, netfilter::REJECT_MARK);
+            msg.set_verdict(nfq::Verdict::Accept)
+        }
+        Verdict::Reject => msg.set_verdict(nfq::Verdict::Drop),
+        Verdict::Ask => return Some((log, generation)),
     }
 
     state.pkt_logs.try_send(log).expect("logs service dead");
+    None
+}
+
+/// DNS over TCP is prefixed by the length, only messages at the beginning of a segment are seen
+fn dns_message(protocol: Proto, data: &[u8]) -> Option<&[u8]> {
+    match protocol {
+        Proto::Udp => Some(data),
+        Proto::Tcp => data.get(2..),
+        _ => None,
+    }
+}
+
+/// Replaces our bits of the packet mark, the rest may be used for routing
+fn set_mark(msg: &mut nfq::Message, mark: u32) {
+    msg.set_nfmark(msg.get_nfmark() & !netfilter::MARK_MASK | mark);
+}
+
+/// Fragments after the first one have no transport header, they follow the first one
+fn inherit_fragment_verdict(msg: &mut nfq::Message, state: &mut State, key: FragmentKey) {
+    let report = match state.fragments.get(&key) {
+        Some(r) => r.clone(),
+        None => {
+            // the first one is not checked yet or was held, nothing tells what they belong to
+            msg.set_verdict(state.fail_verdict());
+            return;
+        }
+    };
+    if report.dropped {
+        msg.set_verdict(nfq::Verdict::Drop);
+    } else {
+        msg.set_verdict(nfq::Verdict::Accept);
+    }
+    let log = PackageReport {
+        len: msg.get_original_len(),
+        ..report
+    };
+    state.pkt_logs.try_send(log).expect("logs service dead");
+}
+
+fn on_answer(q: &mut nfq::Queue, state: &mut State, answer: Answer) {
+    let accept = match answer.answer {
+        Some(AskAnswer::AllowOnce) | Some(AskAnswer::Allow) | Some(AskAnswer::AllowForever) => true,
+        // timeout or no monitor available
+        Some(AskAnswer::Deny) | None => false,
+    };
+    // every worker remembers it, even if the question belongs to another one
+    let mut questions = match answer.answer {
+        Some(AskAnswer::Allow) | Some(AskAnswer::Deny) => {
+            state.pending.remember(answer.exe, accept)
+        }
+        _ => Vec::new(),
+    };
+    // None if expired, answered by other monitor, or held by other worker
+    questions.extend(state.pending.resolve(answer.id));
+    for question in questions {
+        finish_question(q, state, question, accept);
+    }
+}
+
+fn finish_question(q: &mut nfq::Queue, state: &mut State, question: Question, accept: bool) {
+    let mark = if accept { state.ct_accept_mark() } else { None };
+    for mut msg in question.packets {
+        let log = PackageReport {
+            len: msg.get_original_len(),
+            dropped: !accept,
+            ..question.report.clone()
+        };
+        if accept {
+            if let Some(mark) = mark {
+                set_mark(&mut msg, mark);
+            }
+            msg.set_verdict(nfq::Verdict::Accept);
+        } else {
+            msg.set_verdict(nfq::Verdict::Drop);
+        }
+        q.verdict(msg).expect("");
+        state.pkt_logs.try_send(log).expect("logs service dead");
+    }
+}
+
+fn run_worker(
+    mut q: nfq::Queue,
+    mut state: State,
+    answers: crossbeam_channel::Receiver<Answer>,
+) -> ! {
+    let fd = q.as_raw_fd();
+
+    loop {
+        let timeout = match state.pending.next_deadline() {
+            Some(deadline) => cmp::min(
+                deadline.saturating_duration_since(Instant::now()),
+                ANSWER_POLL_INTERVAL,
+            )
+            .as_millis() as libc::c_int,
+            None => -1,
+        };
+        match poll(&mut [PollFd::new(fd, PollFlags::POLLIN)], timeout) {
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(e) => panic!("poll nfqueue: {}", e),
+        }
+
+        for answer in answers.try_iter() {
+            on_answer(&mut q, &mut state, answer);
+        }
+        for question in state.pending.expired(Instant::now()) {
+            finish_question(&mut q, &mut state, question, false);
+        }
+
+        loop {
+            let mut msg = match q.recv() {
+                Ok(r) => r,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => panic!("recv nfqueue: {}", e),
+            };
+            let (report, generation) = match queue_callback(&mut msg, &mut state) {
+                Some(r) => r,
+                None => {
+                    q.verdict(msg).expect("");
+                    continue;
+                }
+            };
+            match state.pending.hold(msg, report) {
+                Hold::New(id, report) => state
+                    .asks
+                    .try_send(Ask {
+                        id,
+                        report,
+                        generation: Some(generation),
+                    })
+                    .expect("ask service dead"),
+                Hold::Joined => (),
+                Hold::Overflow(mut msg) => {
+                    msg.set_verdict(nfq::Verdict::Drop);
+                    q.verdict(msg).expect("");
+                }
+            }
+        }
+    }
 }
 
 // TODO: expect messages
 fn main() {
-    let rules = config::load_rules().expect("Failed to load rules");
+    let mut config = config::load_config().expect("Failed to load config");
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--cleanup") {
+        // for package maintainer scripts, remove hooks left by a dead daemon
+        let force = args.iter().any(|arg| arg == "--force");
+        if config.fail_policy == FailPolicy::Closed && !force {
+            println!("Fail policy is closed, keep firewall hooks (use --force to remove them)");
+        } else if netfilter::cleanup(QUEUE_ID) {
+            println!("Firewall hooks removed");
+        }
+        return;
+    }
+
+    let profile = config::load_active_profile();
+    let mut rules = config::load_rules(&profile).expect("Failed to load rules");
+    // durations written by hand start from now
+    if expiry::normalize(&mut rules.rules) {
+        config::save_rules(&profile, &rules);
+    }
+    if config.backend == Backend::Ebpf {
+        let unenforced = ebpf::unenforced(&rules.rules);
+        if !unenforced.is_empty() {
+            eprintln!(
+                "Rules {:?} can't be enforced by the eBPF backend, use nfqueue instead",
+                unenforced
+            );
+            config.backend = Backend::Nfqueue;
+        }
+    }
 
     let (rules_reader, rules_setter) =
         lrlock::LeftRightLock::new(IndexedRules::from(rules.clone()));
     let (sender, receiver) = crossbeam_channel::unbounded();
-    let mut state = State {
-        diag: netlink::SockDiag::new().expect(""),
-        rules: rules_reader,
-        pkt_logs: sender,
-        cache: LruCache::with_capacity(2048),
+    let (asks_sender, asks_receiver) = crossbeam_channel::unbounded();
+    // the eBPF backend has only one thread
+    let workers = match config.backend {
+        Backend::Nfqueue => config.workers,
+        Backend::Ebpf => 1,
     };
-    let mut q = nfq::Queue::open().expect("");
+    let (answers_sender, answers_receivers) = AnswerSender::new(workers.into());
 
-    thread::spawn(|| {
-        if let Err(e) = rpc_server::run(rules, rules_setter, receiver) {
-            dbg!(e);
-            std::process::exit(1);
-        }
-    });
+    let generation = rules_reader.read().generation();
+    // after the hooks, new rules reinstall them
+    let serve = |hooks| {
+        thread::spawn(move || {
+            if let Err(e) = rpc_server::run(
+                profile,
+                rules,
+                rules_setter,
+                generation,
+                hooks,
+                receiver,
+                asks_receiver,
+                answers_sender,
+            ) {
+                dbg!(e);
+                netfilter::unregister_nfqueue(QUEUE_ID);
+                std::process::exit(1);
+            }
+        });
+    };
+
+    if config.backend == Backend::Ebpf {
+        serve(None);
+        let answers = answers_receivers
+            .into_iter()
+            .next()
+            .expect("no answers receiver");
+        ebpf::run(&config, rules_reader, sender, asks_sender, answers)
+    }
 
-    q.bind(QUEUE_ID).expect("");
-    // The max size of IPv4 + TCP is (20 + 40 optional) + (20 + 40 optional) = 120
-    q.set_copy_range(QUEUE_ID, 128).expect("");
+    // packets are balanced between the queues by flow
+    let mut queues: Vec<nfq::Queue> = (QUEUE_ID..QUEUE_ID + config.workers)
+        .map(|num| {
+            let mut q = nfq::Queue::open().expect("");
+            q.bind(num).expect("");
+            // The max size of IPv4 + TCP is (20 + 40 optional) + (20 + 40 optional) = 120,
+            // IPv6 extension headers can be longer, those packets are accepted.
+            // DNS answers up to the classic 512 bytes fit too, longer ones are cut
+            q.set_copy_range(num, COPY_RANGE).expect("");
+            // accept or drop when the queue is full
+            q.set_fail_open(num, config.fail_policy == FailPolicy::Open)
+                .expect("");
+            // held packets can only be released between two recv
+            q.set_nonblocking(true);
+            q
+        })
+        .collect();
 
-    if Uid::current().is_root() {
-        netfilter::register_nfqueue(QUEUE_ID);
+    let dns = Arc::new(RwLock::new(dns::Cache::default()));
+    let hooks = if Uid::current().is_root() {
+        Some(Arc::new(netfilter::register_nfqueue(
+            QUEUE_ID, &config, generation,
+        )))
+    } else {
+        None
+    };
+    serve(hooks.clone());
+    // Server names need whole packets, only the first ones of connections come here with
+    // conntrack. Without it, names are read from the beginning of the packets only
+    if config.hostnames && hooks.as_ref().map_or(false, |hooks| hooks.conntrack()) {
+        for (num, q) in (QUEUE_ID..).zip(&mut queues) {
+            q.set_copy_range(num, u16::MAX).expect("");
+        }
     }
 
-    loop {
-        let mut msg = q.recv().expect("");
-        queue_callback(&mut msg, &mut state);
-        q.verdict(msg).expect("");
+    let mut workers: Vec<_> = queues
+        .into_iter()
+        .zip(answers_receivers)
+        .map(|(q, answers)| {
+            let state = State {
+                diag: netlink::SockDiag::new().expect(""),
+                netns: netns::Namespaces::new().expect(""),
+                rules: rules_reader.clone(),
+                pkt_logs: sender.clone(),
+                asks: asks_sender.clone(),
+                pending: Default::default(),
+                cache: LruCache::with_capacity(2048),
+                matches: Default::default(),
+                dns: dns.clone(),
+                hostnames: config.hostnames,
+                flow_names: LruCache::with_expiry_duration_and_capacity(FLOW_NAME_TIMEOUT, 4096),
+                fragments: LruCache::with_expiry_duration_and_capacity(FRAGMENT_TIMEOUT, 1024),
+                hooks: hooks.clone(),
+                fail_policy: config.fail_policy,
+            };
+            (q, state, answers)
+        })
+        .collect();
+
+    // the main thread is the first worker
+    let (q, state, answers) = workers.remove(0);
+    for (i, (q, state, answers)) in workers.into_iter().enumerate() {
+        thread::Builder::new()
+            .name(format!("{}{}", netfilter::WORKER_THREAD_PREFIX, i + 1))
+            .spawn(move || run_worker(q, state, answers))
+            .expect("Failed to spawn packets thread");
     }
+    run_worker(q, state, answers)
 }
 
 #[allow(unused)]
@@ -1,61 +1,382 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::panic;
 use std::process::{exit, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 use ctrlc;
 
-pub fn register_nfqueue(num: u16) {
-    if nft_exists() {
+use crate::config::{Config, FailPolicy};
+use crate::nftables;
+
+/// Only set when the hooks should be removed on exit
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Packets threads other than main are named with it
+pub const WORKER_THREAD_PREFIX: &str = "packets-";
+
+/// Bits of the packet mark and the connmark we own, the rest belong to routing and others.
+///
+/// Inside, the connmark of a judged connection is the rules generation shifted by one, plus
+/// one if it was dropped. With the generation 0, that is the mark of packets to reject
+pub const MARK_MASK: u32 = 0xff00_0000;
+const MARK_SHIFT: u32 = 24;
+
+/// Packets accepted by the queue with it are rejected by the next table
+pub const REJECT_MARK: u32 = 1 << MARK_SHIFT;
+
+/// Chains in the mangle table sending packets to the queue, `FORWARD` is optional
+const IPTABLES_CHAINS: [(&str, &str); 3] = [
+    ("INPUT", "gleipnir-input"),
+    ("OUTPUT", "gleipnir-output"),
+    ("FORWARD", "gleipnir-forward"),
+];
+/// `REJECT` only works in the filter table
+const IPTABLES_REJECT_CHAIN: &str = "gleipnir-reject";
+/// Table, parent chain and our chain
+const IPTABLES_JUMPS: [(&str, &str, &str); 6] = [
+    ("mangle", "INPUT", "gleipnir-input"),
+    ("mangle", "OUTPUT", "gleipnir-output"),
+    ("mangle", "FORWARD", "gleipnir-forward"),
+    ("filter", "INPUT", IPTABLES_REJECT_CHAIN),
+    ("filter", "OUTPUT", IPTABLES_REJECT_CHAIN),
+    ("filter", "FORWARD", IPTABLES_REJECT_CHAIN),
+];
+
+/// Removes the hooks when dropped, including unwinding from a panic.
+///
+/// Shared by all workers
+pub struct NfqueueGuard {
+    num: u16,
+    config: Config,
+    conntrack: bool,
+    /// Rules generation in the installed hooks, locked while reinstalling them
+    ct_generation: Mutex<u32>,
+}
+
+impl NfqueueGuard {
+    /// Returns the mark of connections accepted by rules of `generation`, `None` if not in
+    /// conntrack mode
+    pub fn ct_accept_mark(&self, generation: u32) -> Option<u32> {
+        if self.conntrack {
+            Some(ct_mark(generation, false))
+        } else {
+            None
+        }
+    }
+
+    /// Same as `ct_accept_mark`, for dropped connections. Packets with it are accepted by the
+    /// queue, and dropped after the connection remembers it
+    pub fn ct_drop_mark(&self, generation: u32) -> Option<u32> {
+        if self.conntrack {
+            Some(ct_mark(generation, true))
+        } else {
+            None
+        }
+    }
+
+    /// False if conntrack mode is disabled, or unavailable with iptables
+    pub fn conntrack(&self) -> bool {
+        self.conntrack
+    }
+
+    /// Reinstalls the hooks when rules changed, so old verdicts are forgotten. Until then,
+    /// connections accepted by the new rules still go to the queue
+    pub fn set_ct_generation(&self, generation: u32) {
+        if !self.conntrack {
+            return;
+        }
+        let mut ct_generation = self.ct_generation.lock().unwrap();
+        if *ct_generation == generation {
+            return;
+        }
+        let opts = nftables_options(self.num, &self.config, Some(generation));
+        if let Err(e) = nftables::insert_nfqueue(&opts) {
+            eprintln!("Failed to update nftables table: {}", e);
+            return;
+        }
+        *ct_generation = generation;
+    }
+}
+
+/// Never 0 or `REJECT_MARK`, generations repeat after 127 changes of the rules
+fn ct_mark(generation: u32, drop: bool) -> u32 {
+    let generation = generation % 127 + 1;
+    (generation << 1 | drop as u32) << MARK_SHIFT
+}
+
+fn nftables_options(num: u16, config: &Config, ct_generation: Option<u32>) -> nftables::Options {
+    nftables::Options {
+        queue_num: num,
+        queue_total: config.workers,
+        bypass: config.fail_policy == FailPolicy::Open,
+        allowlist: &config.allowlist,
+        ct_accept_mark: ct_generation.map(|generation| ct_mark(generation, false)),
+        ct_drop_mark: ct_generation.map(|generation| ct_mark(generation, true)),
+        mark_mask: MARK_MASK,
+        reject_mark: REJECT_MARK,
+        forward: config.forward,
+    }
+}
+
+impl Drop for NfqueueGuard {
+    fn drop(&mut self) {
+        unregister_nfqueue(self.num);
+    }
+}
+
+pub fn register_nfqueue(num: u16, config: &Config, rules_generation: u32) -> NfqueueGuard {
+    let fail_open = config.fail_policy == FailPolicy::Open;
+    let mut conntrack = config.conntrack;
+    let ct_generation = if conntrack {
+        Some(rules_generation)
     } else {
-        iptables_insert_nfqueue(num);
-        ctrlc::set_handler(move || {
-            iptables_remove_nfqueue(num);
-            exit(0);
-        })
-        .expect("Error setting Ctrl-C handler");
+        None
+    };
+
+    // Both backends replace old hooks in place, there is no moment without them
+    let stale = match nftables::insert_nfqueue(&nftables_options(num, config, ct_generation)) {
+        Ok(stale) => iptables_remove_nfqueue(num) || stale,
+        Err(e) => {
+            eprintln!("nftables unavailable ({}), fallback to iptables", e);
+            if conntrack {
+                eprintln!("Conntrack mode requires nftables, disabled");
+                conntrack = false;
+            }
+            let stale = iptables_remove_legacy(num);
+            iptables_insert_nfqueue(num, config, fail_open) || stale
+        }
+    };
+    if stale {
+        eprintln!("Replaced firewall hooks left by a previous instance");
+    }
+    // when fail-closed, the hooks must outlive us
+    REGISTERED.store(fail_open, Ordering::Release);
+
+    ctrlc::set_handler(move || {
+        unregister_nfqueue(num);
+        exit(0);
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    // The guard won't run if the packets thread aborts, or panics while panicking
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        match thread::current().name() {
+            Some("main") => unregister_nfqueue(num),
+            // nobody would answer the packets balanced to its queue
+            Some(name) if name.starts_with(WORKER_THREAD_PREFIX) => {
+                unregister_nfqueue(num);
+                exit(1);
+            }
+            _ => (),
+        }
+    }));
+
+    NfqueueGuard {
+        num,
+        config: config.clone(),
+        conntrack,
+        ct_generation: Mutex::new(rules_generation),
     }
 }
 
-// TODO: or just iptables-nft?
-fn nft_exists() -> bool {
-    false
+/// Removes hooks installed by `register_nfqueue`, can be called multiple times.
+///
+/// Does nothing if the fail policy is closed
+pub fn unregister_nfqueue(num: u16) {
+    if REGISTERED.swap(false, Ordering::AcqRel) {
+        cleanup(num);
+    }
 }
 
-fn iptables(v4: bool, output: bool, cmd: &str, queue_num: u16) -> Command {
+/// Removes our hooks from both nftables and iptables, no matter who installed them.
+///
+/// Returns true if anything was removed
+pub fn cleanup(num: u16) -> bool {
+    let nft_removed = match nftables::remove_nfqueue() {
+        Ok(()) => true,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            eprintln!("Failed to remove nftables table: {}", e);
+            false
+        }
+    };
+    let iptables_removed = iptables_remove_nfqueue(num);
+    nft_removed || iptables_removed
+}
+
+fn iptables(v4: bool, table: &str) -> Command {
     let mut c = Command::new(if v4 { "iptables" } else { "ip6tables" });
     c.stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .arg(format!("-{}", cmd))
-        .arg(if output { "OUTPUT" } else { "INPUT" })
-        .arg("!")
-        .arg(if output { "-o" } else { "-i" })
-        .arg("lo")
+        .arg("-w")
         .arg("-t")
-        .arg("mangle")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg(queue_num.to_string())
-        .arg("--queue-bypass");
+        .arg(table);
     c
 }
 
-fn iptables_insert_nfqueue(num: u16) {
-    fn insert_if_not_exists(v4: bool, output: bool, num: u16) {
-        let rule_existed = iptables(v4, output, "C", num).status().unwrap().success();
-        if !rule_existed {
-            iptables(v4, output, "I", num).status().unwrap().success();
+/// Errors are ignored, iptables may not even be installed
+fn run(cmd: &mut Command) -> bool {
+    cmd.status().map(|s| s.success()).unwrap_or(false)
+}
+
+/// Replaces the content of our chains in one transaction
+fn iptables_restore(v4: bool, rules: &str) -> bool {
+    let child = Command::new(if v4 {
+        "iptables-restore"
+    } else {
+        "ip6tables-restore"
+    })
+    .arg("--noflush")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::null())
+    .stderr(Stdio::null())
+    .spawn();
+    let mut child = match child {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+    let written = child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(rules.as_bytes())
+        .is_ok();
+    child.wait().map(|s| s.success()).unwrap_or(false) && written
+}
+
+/// Returns true if our chains were already hooked
+fn iptables_insert_nfqueue(num: u16, config: &Config, bypass: bool) -> bool {
+    let total = config.workers;
+    let chains = if config.forward {
+        &IPTABLES_CHAINS[..]
+    } else {
+        &IPTABLES_CHAINS[..2]
+    };
+    let mut stale = false;
+    for &v4 in &[false, true] {
+        let mut rules = String::from("*mangle\n");
+        for (_, chain) in chains {
+            // with --noflush, declaring a chain flushes only that chain
+            writeln!(rules, ":{} - [0:0]", chain).unwrap();
+        }
+        for &(parent, chain) in chains {
+            // the remote addresses, both sides of forwarded packets are
+            let (iface, addrs): (_, &[_]) = match parent {
+                "OUTPUT" => ("-o", &["-d"]),
+                "INPUT" => ("-i", &["-s"]),
+                _ => ("-i", &["-s", "-d"]),
+            };
+            writeln!(rules, "-A {} {} lo -j RETURN", chain, iface).unwrap();
+            let allowlist = config.allowlist.iter().filter(|(ip, _)| ip.is_ipv4() == v4);
+            for (subnet, mask) in allowlist {
+                for addr in addrs {
+                    writeln!(rules, "-A {} {} {}/{} -j RETURN", chain, addr, subnet, mask).unwrap();
+                }
+            }
+            if total > 1 {
+                write!(
+                    rules,
+                    "-A {} -j NFQUEUE --queue-balance {}:{}",
+                    chain,
+                    num,
+                    num + total - 1
+                )
+            } else {
+                write!(rules, "-A {} -j NFQUEUE --queue-num {}", chain, num)
+            }
+            .unwrap();
+            writeln!(rules, "{}", if bypass { " --queue-bypass" } else { "" }).unwrap();
+        }
+        rules.push_str("COMMIT\n*filter\n");
+        writeln!(rules, ":{} - [0:0]", IPTABLES_REJECT_CHAIN).unwrap();
+        writeln!(
+            rules,
+            "-A {} -m mark --mark {:#x}/{:#x} -p tcp -j REJECT --reject-with tcp-reset",
+            IPTABLES_REJECT_CHAIN, REJECT_MARK, MARK_MASK
+        )
+        .unwrap();
+        writeln!(
+            rules,
+            "-A {} -m mark --mark {:#x}/{:#x} -j REJECT --reject-with {}",
+            IPTABLES_REJECT_CHAIN,
+            REJECT_MARK,
+            MARK_MASK,
+            if v4 {
+                "icmp-port-unreachable"
+            } else {
+                "icmp6-port-unreachable"
+            }
+        )
+        .unwrap();
+        rules.push_str("COMMIT\n");
+        if !iptables_restore(v4, &rules) {
+            eprintln!(
+                "Failed to install {} hooks",
+                if v4 { "iptables" } else { "ip6tables" }
+            );
+            continue;
+        }
+
+        let jumps = IPTABLES_JUMPS
+            .iter()
+            .filter(|(_, parent, _)| config.forward || *parent != "FORWARD");
+        for &(table, parent, chain) in jumps {
+            if run(iptables(v4, table).args(&["-C", parent, "-j", chain])) {
+                stale = true;
+            } else {
+                run(iptables(v4, table).args(&["-I", parent, "-j", chain]));
+            }
         }
     }
+    stale
+}
 
-    insert_if_not_exists(false, false, num);
-    insert_if_not_exists(true, false, num);
-    insert_if_not_exists(false, true, num);
-    insert_if_not_exists(true, true, num);
+fn iptables_remove_nfqueue(num: u16) -> bool {
+    let mut removed = iptables_remove_legacy(num);
+    for &v4 in &[false, true] {
+        for &(table, parent, chain) in &IPTABLES_JUMPS {
+            // a crashed instance may have left duplicated jumps
+            while run(iptables(v4, table).args(&["-D", parent, "-j", chain])) {
+                removed = true;
+            }
+        }
+        for &(table, _, chain) in &IPTABLES_JUMPS {
+            run(iptables(v4, table).args(&["-F", chain]));
+            run(iptables(v4, table).args(&["-X", chain]));
+        }
+    }
+    removed
 }
 
-fn iptables_remove_nfqueue(num: u16) {
-    iptables(false, false, "D", num).status().unwrap().success();
-    iptables(true, false, "D", num).status().unwrap().success();
-    iptables(false, true, "D", num).status().unwrap().success();
-    iptables(true, true, "D", num).status().unwrap().success();
+/// Older versions put the NFQUEUE target in INPUT and OUTPUT directly
+fn iptables_remove_legacy(num: u16) -> bool {
+    let mut removed = false;
+    for &v4 in &[false, true] {
+        // there was no forward hook
+        for &(parent, _) in &IPTABLES_CHAINS[..2] {
+            let iface = if parent == "OUTPUT" { "-o" } else { "-i" };
+            let num = num.to_string();
+            let rule = [
+                parent,
+                "!",
+                iface,
+                "lo",
+                "-j",
+                "NFQUEUE",
+                "--queue-num",
+                num.as_str(),
+                "--queue-bypass",
+            ];
+            while run(iptables(v4, "mangle").arg("-D").args(&rule)) {
+                removed = true;
+            }
+        }
+    }
+    removed
 }
@@ -1,6 +1,9 @@
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
 use std::{cmp, fmt, io, mem, net};
 
 use gleipnir_interface::Proto;
+use nix::sched::{setns, CloneFlags};
 use pnet_macros_support::packet::{Packet, PacketSize};
 use pnetlink::{
     packet::netlink::{NetlinkMsgFlags, NetlinkReader, NetlinkRequestBuilder},
@@ -17,6 +20,18 @@ impl SockDiag {
         Ok(SockDiag { socket })
     }
 
+    /// Queries sockets in the network namespace `ns`, like `/proc/<pid>/ns/net`.
+    ///
+    /// A socket belongs to the namespace it was created in, so the thread visits it
+    pub fn in_netns(ns: &File) -> io::Result<SockDiag> {
+        let own = File::open("/proc/thread-self/ns/net")?;
+        setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWNET).map_err(nix_to_io)?;
+        let diag = SockDiag::new();
+        setns(own.as_raw_fd(), CloneFlags::CLONE_NEWNET)
+            .expect("Failed to return to our network namespace");
+        diag
+    }
+
     pub fn query<'a>(
         &'a mut self,
         protocol: Proto,
@@ -49,9 +64,11 @@ impl SockDiag {
         };
 
         let mut flags = NetlinkMsgFlags::NLM_F_REQUEST;
-        if protocol != Proto::Tcp {
+        match protocol {
+            // connection oriented, can be looked up directly
+            Proto::Tcp | Proto::Dccp => (),
             // TODO: do we really need this for UDP?
-            flags.insert(NetlinkMsgFlags::NLM_F_MATCH);
+            _ => flags.insert(NetlinkMsgFlags::NLM_F_MATCH),
         }
 
         let req = NetlinkRequestBuilder::new(SOCK_DIAG_BY_FAMILY, flags)
@@ -102,6 +119,13 @@ impl SockDiag {
     }
 }
 
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => errno.into(),
+        e => io::Error::new(io::ErrorKind::Other, e),
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct InetDiagReqV2 {
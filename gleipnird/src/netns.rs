@@ -0,0 +1,140 @@
+//! Sockets in the network namespaces of containers, which our sock_diag can't see
+//!
+//! Their packets are forwarded to us, the namespaces of every process are queried for them
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant};
+
+use gleipnir_interface::Proto;
+
+use crate::netlink::{InetDiagMsg, SockDiag};
+
+/// Namespaces are listed again at most this often, when a socket is found in none of them
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Every network namespace but ours
+pub struct Namespaces {
+    own: u64,
+    /// By the inode of the namespace. Our socket keeps it alive, so it's dropped once it
+    /// has no process
+    diags: HashMap<u64, SockDiag>,
+    scanned: Option<Instant>,
+}
+
+impl Namespaces {
+    pub fn new() -> io::Result<Namespaces> {
+        Ok(Namespaces {
+            own: fs::metadata("/proc/self/ns/net")?.ino(),
+            diags: HashMap::new(),
+            scanned: None,
+        })
+    }
+
+    /// Same as `SockDiag::query`, in any namespace
+    pub fn query(
+        &mut self,
+        protocol: Proto,
+        local_address: SocketAddr,
+        remote_address: SocketAddr,
+    ) -> Result<InetDiagMsg, io::Error> {
+        match query_any(
+            self.diags.values_mut(),
+            protocol,
+            local_address,
+            remote_address,
+        ) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            r => return r,
+        }
+        if let Some(scanned) = self.scanned {
+            if scanned.elapsed() < RESCAN_INTERVAL {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        let new = self.rescan();
+        let diags = self
+            .diags
+            .iter_mut()
+            .filter(|(ns, _)| new.contains(ns))
+            .map(|(_, diag)| diag);
+        query_any(diags, protocol, local_address, remote_address)
+    }
+
+    /// Returns the namespaces seen for the first time
+    fn rescan(&mut self) -> Vec<u64> {
+        self.scanned = Some(Instant::now());
+        let alive = list_namespaces();
+        self.diags.retain(|ns, _| alive.contains_key(ns));
+        let mut new = Vec::new();
+        for (ns, pid) in alive {
+            if ns == self.own || self.diags.contains_key(&ns) {
+                continue;
+            }
+            let diag = File::open(format!("/proc/{}/ns/net", pid))
+                .and_then(|file| SockDiag::in_netns(&file));
+            match diag {
+                Ok(diag) => {
+                    self.diags.insert(ns, diag);
+                    new.push(ns);
+                }
+                // the process is gone
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => eprintln!("Failed to enter network namespace of {}: {}", pid, e),
+            }
+        }
+        new
+    }
+}
+
+fn query_any<'a>(
+    diags: impl Iterator<Item = &'a mut SockDiag>,
+    protocol: Proto,
+    local_address: SocketAddr,
+    remote_address: SocketAddr,
+) -> Result<InetDiagMsg, io::Error> {
+    for diag in diags {
+        match diag.query(protocol, local_address, remote_address) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            r => return r,
+        }
+    }
+    Err(io::ErrorKind::NotFound.into())
+}
+
+/// The inode of every network namespace, with a process in it
+fn list_namespaces() -> HashMap<u64, usize> {
+    let mut namespaces = HashMap::new();
+    let dir = match fs::read_dir("/proc") {
+        Ok(r) => r,
+        Err(_) => return namespaces,
+    };
+    for entry in dir.filter_map(Result::ok) {
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(r) => r,
+            None => continue,
+        };
+        // the link is readable only if we can ptrace the process, root can
+        if let Ok(meta) = fs::metadata(format!("/proc/{}/ns/net", pid)) {
+            namespaces.entry(meta.ino()).or_insert(pid);
+        }
+    }
+    namespaces
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn own_namespace() {
+        let own = fs::metadata("/proc/self/ns/net").unwrap().ino();
+        // any process in it, maybe us
+        let pid = list_namespaces()[&own];
+        let ns = fs::metadata(format!("/proc/{}/ns/net", pid)).unwrap();
+        assert_eq!(ns.ino(), own);
+    }
+}
@@ -0,0 +1,614 @@
+//! A minimal nf_tables client, speaks netlink directly instead of calling `nft`
+//!
+//! Everything lives in a dedicated `inet gleipnir` table, so the whole setup
+//! can be replaced or removed atomically in a single batch.
+
+use std::io;
+use std::net::IpAddr;
+
+use pnetlink::socket::{NetlinkProtocol, NetlinkSocket};
+
+pub const TABLE: &str = "gleipnir";
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NFPROTO_UNSPEC: u8 = 0;
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16 = 2;
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16 = 2;
+const NFTA_CMP_DATA: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+const NFTA_IMMEDIATE_DREG: u16 = 1;
+const NFTA_IMMEDIATE_DATA: u16 = 2;
+const NFTA_PAYLOAD_DREG: u16 = 1;
+const NFTA_PAYLOAD_BASE: u16 = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16 = 4;
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16 = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16 = 5;
+const NFTA_CT_DREG: u16 = 1;
+const NFTA_CT_KEY: u16 = 2;
+const NFTA_CT_SREG: u16 = 4;
+const NFTA_QUEUE_NUM: u16 = 1;
+const NFTA_QUEUE_TOTAL: u16 = 2;
+const NFTA_QUEUE_FLAGS: u16 = 3;
+const NFTA_REJECT_TYPE: u16 = 1;
+const NFTA_REJECT_ICMP_CODE: u16 = 2;
+
+const NF_INET_LOCAL_IN: u32 = 1;
+const NF_INET_FORWARD: u32 = 2;
+const NF_INET_LOCAL_OUT: u32 = 3;
+/// Same as the iptables mangle table
+const NF_IP_PRI_MANGLE: i32 = -150;
+/// Same as the iptables filter table
+const NF_IP_PRI_FILTER: i32 = 0;
+
+const NFT_META_MARK: u32 = 3;
+const NFT_META_IIFNAME: u32 = 6;
+const NFT_META_OIFNAME: u32 = 7;
+const NFT_META_NFPROTO: u32 = 15;
+const NFT_META_L4PROTO: u32 = 16;
+const NFT_CT_MARK: u32 = 3;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_TRANSPORT_HEADER: u32 = 2;
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+const NFT_CMP_EQ: u32 = 0;
+const NFT_CMP_NEQ: u32 = 1;
+const NF_DROP: u32 = 0;
+const NF_ACCEPT: u32 = 1;
+const NFT_QUEUE_FLAG_BYPASS: u16 = 0x01;
+const NFT_REJECT_TCP_RST: u32 = 1;
+const NFT_REJECT_ICMPX_UNREACH: u32 = 2;
+const NFT_REJECT_ICMPX_PORT_UNREACH: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const DNS_PORT: u16 = 53;
+
+const IFNAMSIZ: usize = 16;
+
+/// Netlink attributes, in host byte order unless noted
+#[derive(Default)]
+struct Attrs(Vec<u8>);
+
+impl Attrs {
+    fn new() -> Self {
+        Default::default()
+    }
+
+    fn bytes(mut self, kind: u16, data: &[u8]) -> Self {
+        let len = 4 + data.len();
+        self.0.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.0.extend_from_slice(&kind.to_ne_bytes());
+        self.0.extend_from_slice(data);
+        self.0.resize(align(self.0.len()), 0);
+        self
+    }
+
+    fn str(self, kind: u16, s: &str) -> Self {
+        let mut data = s.as_bytes().to_vec();
+        data.push(0);
+        self.bytes(kind, &data)
+    }
+
+    /// nf_tables wants integers in network byte order
+    fn be32(self, kind: u16, v: u32) -> Self {
+        self.bytes(kind, &v.to_be_bytes())
+    }
+
+    fn be16(self, kind: u16, v: u16) -> Self {
+        self.bytes(kind, &v.to_be_bytes())
+    }
+
+    fn nested(self, kind: u16, attrs: Attrs) -> Self {
+        self.bytes(kind | NLA_F_NESTED, &attrs.0)
+    }
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct Batch {
+    buf: Vec<u8>,
+    seq: u32,
+    messages: usize,
+}
+
+impl Batch {
+    fn new() -> Self {
+        let mut batch = Batch {
+            buf: Vec::new(),
+            seq: 0,
+            messages: 0,
+        };
+        batch.push(
+            NFNL_MSG_BATCH_BEGIN,
+            NLM_F_REQUEST,
+            NFPROTO_UNSPEC,
+            Attrs::new(),
+        );
+        batch
+    }
+
+    fn push(&mut self, kind: u16, flags: u16, family: u8, attrs: Attrs) {
+        // nlmsghdr + nfgenmsg
+        let len = 16 + 4 + attrs.0.len();
+        let res_id = if kind == NFNL_MSG_BATCH_BEGIN || kind == NFNL_MSG_BATCH_END {
+            NFNL_SUBSYS_NFTABLES
+        } else {
+            0
+        };
+        self.buf.extend_from_slice(&(len as u32).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&self.seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes()); // pid
+        self.buf.push(family);
+        self.buf.push(0); // NFNETLINK_V0
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+        self.buf.extend_from_slice(&attrs.0);
+        self.seq += 1;
+    }
+
+    fn add(&mut self, msg: u16, flags: u16, attrs: Attrs) {
+        let kind = (NFNL_SUBSYS_NFTABLES << 8) | msg;
+        self.push(kind, NLM_F_REQUEST | NLM_F_ACK | flags, NFPROTO_INET, attrs);
+        self.messages += 1;
+    }
+
+    fn send(mut self) -> io::Result<()> {
+        self.push(
+            NFNL_MSG_BATCH_END,
+            NLM_F_REQUEST,
+            NFPROTO_UNSPEC,
+            Attrs::new(),
+        );
+        let mut socket = NetlinkSocket::bind(NetlinkProtocol::Netfilter, 0)?;
+        socket.send(&self.buf)?;
+
+        let mut acked = 0;
+        let mut buf = vec![0u8; 8192];
+        while acked < self.messages {
+            let n = socket.recv(&mut buf)?;
+            let mut msgs = &buf[..n];
+            while msgs.len() >= 16 {
+                let len = u32::from_ne_bytes([msgs[0], msgs[1], msgs[2], msgs[3]]) as usize;
+                let kind = u16::from_ne_bytes([msgs[4], msgs[5]]);
+                if len < 16 || len > msgs.len() {
+                    return Err(io::ErrorKind::InvalidData.into());
+                }
+                if kind == NLMSG_ERROR {
+                    let code = i32::from_ne_bytes([msgs[16], msgs[17], msgs[18], msgs[19]]);
+                    if code != 0 {
+                        return Err(io::Error::from_raw_os_error(-code));
+                    }
+                    acked += 1;
+                }
+                msgs = &msgs[align(len).min(msgs.len())..];
+            }
+        }
+        Ok(())
+    }
+}
+
+fn chain(name: &str, hook: u32, priority: i32) -> Attrs {
+    let hook = Attrs::new()
+        .be32(NFTA_HOOK_HOOKNUM, hook)
+        .be32(NFTA_HOOK_PRIORITY, priority as u32);
+    Attrs::new()
+        .str(NFTA_CHAIN_TABLE, TABLE)
+        .str(NFTA_CHAIN_NAME, name)
+        .nested(NFTA_CHAIN_HOOK, hook)
+        .str(NFTA_CHAIN_TYPE, "filter")
+}
+
+fn expr(name: &str, data: Attrs) -> Attrs {
+    Attrs::new()
+        .str(NFTA_EXPR_NAME, name)
+        .nested(NFTA_EXPR_DATA, data)
+}
+
+fn meta(key: u32) -> Attrs {
+    expr(
+        "meta",
+        Attrs::new()
+            .be32(NFTA_META_KEY, key)
+            .be32(NFTA_META_DREG, NFT_REG_1),
+    )
+}
+
+/// Loads the conntrack `key` to register 1 with `NFTA_CT_DREG`, or sets it with `NFTA_CT_SREG`
+fn ct(reg: u16, key: u32) -> Attrs {
+    expr(
+        "ct",
+        Attrs::new().be32(NFTA_CT_KEY, key).be32(reg, NFT_REG_1),
+    )
+}
+
+fn cmp(op: u32, data: &[u8]) -> Attrs {
+    expr(
+        "cmp",
+        Attrs::new()
+            .be32(NFTA_CMP_SREG, NFT_REG_1)
+            .be32(NFTA_CMP_OP, op)
+            .nested(NFTA_CMP_DATA, Attrs::new().bytes(NFTA_DATA_VALUE, data)),
+    )
+}
+
+/// Loads `len` bytes of the network or transport header
+fn payload(base: u32, offset: u32, len: u32) -> Attrs {
+    expr(
+        "payload",
+        Attrs::new()
+            .be32(NFTA_PAYLOAD_DREG, NFT_REG_1)
+            .be32(NFTA_PAYLOAD_BASE, base)
+            .be32(NFTA_PAYLOAD_OFFSET, offset)
+            .be32(NFTA_PAYLOAD_LEN, len),
+    )
+}
+
+/// `reg1 = reg1 & mask ^ xor`
+fn bitwise(mask: &[u8], xor: &[u8]) -> Attrs {
+    expr(
+        "bitwise",
+        Attrs::new()
+            .be32(NFTA_BITWISE_SREG, NFT_REG_1)
+            .be32(NFTA_BITWISE_DREG, NFT_REG_1)
+            .be32(NFTA_BITWISE_LEN, mask.len() as u32)
+            .nested(NFTA_BITWISE_MASK, Attrs::new().bytes(NFTA_DATA_VALUE, mask))
+            .nested(NFTA_BITWISE_XOR, Attrs::new().bytes(NFTA_DATA_VALUE, xor)),
+    )
+}
+
+/// `<mark> & <mask> == <value>`, `mark` is loaded to register 1 already
+fn masked_mark_eq(mask: u32, value: u32) -> Vec<Attrs> {
+    vec![
+        bitwise(&mask.to_ne_bytes(), &[0; 4]),
+        cmp(NFT_CMP_EQ, &value.to_ne_bytes()),
+    ]
+}
+
+/// `ct mark set ct mark & ~<mask> | <value>`, other bits of the connmark are kept
+fn set_ct_mark(mask: u32, value: u32) -> Vec<Attrs> {
+    vec![
+        ct(NFTA_CT_DREG, NFT_CT_MARK),
+        bitwise(&(!mask).to_ne_bytes(), &value.to_ne_bytes()),
+        ct(NFTA_CT_SREG, NFT_CT_MARK),
+    ]
+}
+
+fn accept() -> Attrs {
+    immediate_verdict(NF_ACCEPT)
+}
+
+fn drop() -> Attrs {
+    immediate_verdict(NF_DROP)
+}
+
+fn immediate_verdict(code: u32) -> Attrs {
+    let verdict = Attrs::new().be32(NFTA_VERDICT_CODE, code);
+    expr(
+        "immediate",
+        Attrs::new()
+            .be32(NFTA_IMMEDIATE_DREG, NFT_REG_VERDICT)
+            .nested(
+                NFTA_IMMEDIATE_DATA,
+                Attrs::new().nested(NFTA_DATA_VERDICT, verdict),
+            ),
+    )
+}
+
+/// Packets are balanced between `total` queues by flow
+fn queue(num: u16, total: u16, flags: u16) -> Attrs {
+    expr(
+        "queue",
+        Attrs::new()
+            .be16(NFTA_QUEUE_NUM, num)
+            .be16(NFTA_QUEUE_TOTAL, total)
+            .be16(NFTA_QUEUE_FLAGS, flags),
+    )
+}
+
+/// `code` is only used by ICMP rejects
+fn reject(kind: u32, code: u8) -> Attrs {
+    expr(
+        "reject",
+        Attrs::new()
+            .be32(NFTA_REJECT_TYPE, kind)
+            .bytes(NFTA_REJECT_ICMP_CODE, &[code]),
+    )
+}
+
+fn rule(chain: &str, exprs: Vec<Attrs>) -> Attrs {
+    let list = exprs
+        .into_iter()
+        .fold(Attrs::new(), |list, e| list.nested(NFTA_LIST_ELEM, e));
+    Attrs::new()
+        .str(NFTA_RULE_TABLE, TABLE)
+        .str(NFTA_RULE_CHAIN, chain)
+        .nested(NFTA_RULE_EXPRESSIONS, list)
+}
+
+/// `[i|o]ifname != "lo" queue num <num>-<num + total - 1> [bypass]`
+fn queue_rule(chain: &str, ifname_key: u32, num: u16, total: u16, bypass: bool) -> Attrs {
+    let mut lo = [0u8; IFNAMSIZ];
+    lo[..2].copy_from_slice(b"lo");
+    let flags = if bypass { NFT_QUEUE_FLAG_BYPASS } else { 0 };
+    rule(
+        chain,
+        vec![
+            meta(ifname_key),
+            cmp(NFT_CMP_NEQ, &lo),
+            queue(num, total, flags),
+        ],
+    )
+}
+
+/// `ip[6] [s|d]addr <subnet> accept`
+fn allow_rule(chain: &str, src: bool, (addr, masklen): (IpAddr, u8)) -> Attrs {
+    let (nfproto, offset, addr) = match addr {
+        IpAddr::V4(addr) => (
+            NFPROTO_IPV4,
+            if src { 12 } else { 16 },
+            addr.octets().to_vec(),
+        ),
+        IpAddr::V6(addr) => (
+            NFPROTO_IPV6,
+            if src { 8 } else { 24 },
+            addr.octets().to_vec(),
+        ),
+    };
+    let mask = prefix_mask(addr.len(), masklen);
+    let masked: Vec<u8> = addr.iter().zip(&mask).map(|(a, m)| a & m).collect();
+    rule(
+        chain,
+        vec![
+            meta(NFT_META_NFPROTO),
+            cmp(NFT_CMP_EQ, &[nfproto]),
+            payload(NFT_PAYLOAD_NETWORK_HEADER, offset, addr.len() as u32),
+            bitwise(&mask, &vec![0; mask.len()]),
+            cmp(NFT_CMP_EQ, &masked),
+            accept(),
+        ],
+    )
+}
+
+fn prefix_mask(len: usize, masklen: u8) -> Vec<u8> {
+    (0..len)
+        .map(|i| {
+            let bits = (masklen as usize).saturating_sub(i * 8).min(8);
+            0xffu8.checked_shl(8 - bits as u32).unwrap_or(0)
+        })
+        .collect()
+}
+
+fn table() -> Attrs {
+    Attrs::new().str(NFTA_TABLE_NAME, TABLE)
+}
+
+/// What `insert_nfqueue` installs
+pub struct Options<'a> {
+    pub queue_num: u16,
+    /// Number of queues starting from `queue_num`
+    pub queue_total: u16,
+    /// Accept packets when nobody is listening on the queue
+    pub bypass: bool,
+    pub allowlist: &'a [(IpAddr, u8)],
+    /// Connmark of accepted connections, only their first packet goes through the queue
+    pub ct_accept_mark: Option<u32>,
+    /// Connmark of dropped connections, the rest of them are dropped without the queue
+    pub ct_drop_mark: Option<u32>,
+    /// Our bits of the packet mark and the connmark, the others are kept
+    pub mark_mask: u32,
+    /// Packets accepted by the queue with this mark are rejected later
+    pub reject_mark: u32,
+    /// Also hook forwarded packets
+    pub forward: bool,
+}
+
+/// Installs the hooks, replaces the old table atomically if it exists.
+///
+/// Returns true if the table was left by a previous instance
+pub fn insert_nfqueue(opts: &Options) -> io::Result<bool> {
+    let mut probe = Batch::new();
+    probe.add(NFT_MSG_NEWTABLE, NLM_F_CREATE | NLM_F_EXCL, table());
+    let stale = match probe.send() {
+        Ok(()) => false,
+        Err(ref e) if e.raw_os_error() == Some(libc::EEXIST) => true,
+        Err(e) => return Err(e),
+    };
+
+    let mut batch = Batch::new();
+    // create then delete, so an old table left by a crash is replaced instead of duplicated
+    batch.add(NFT_MSG_NEWTABLE, NLM_F_CREATE, table());
+    batch.add(NFT_MSG_DELTABLE, 0, table());
+    batch.add(NFT_MSG_NEWTABLE, NLM_F_CREATE, table());
+    // the remote addresses to allow
+    let mut hooks = vec![
+        ("input", NF_INET_LOCAL_IN, NFT_META_IIFNAME, &[true][..]),
+        ("output", NF_INET_LOCAL_OUT, NFT_META_OIFNAME, &[false][..]),
+    ];
+    if opts.forward {
+        // both sides are remote
+        hooks.push((
+            "forward",
+            NF_INET_FORWARD,
+            NFT_META_IIFNAME,
+            &[true, false][..],
+        ));
+    }
+    for &(name, hook, ifname_key, srcs) in &hooks {
+        batch.add(
+            NFT_MSG_NEWCHAIN,
+            NLM_F_CREATE,
+            chain(name, hook, NF_IP_PRI_MANGLE),
+        );
+        for &subnet in opts.allowlist {
+            for &src in srcs {
+                batch.add(
+                    NFT_MSG_NEWRULE,
+                    NLM_F_CREATE | NLM_F_APPEND,
+                    allow_rule(name, src, subnet),
+                );
+            }
+        }
+        if let Some(mark) = opts.ct_accept_mark {
+            let mut exprs = vec![ct(NFTA_CT_DREG, NFT_CT_MARK)];
+            exprs.extend(masked_mark_eq(opts.mark_mask, mark));
+            // `th sport != 53` and `th dport != 53` for the remote side, the daemon learns
+            // names from DNS answers to the queries it saw
+            for &src in srcs {
+                let offset = if src { 0 } else { 2 };
+                exprs.push(payload(NFT_PAYLOAD_TRANSPORT_HEADER, offset, 2));
+                exprs.push(cmp(NFT_CMP_NEQ, &DNS_PORT.to_be_bytes()));
+            }
+            exprs.push(accept());
+            batch.add(
+                NFT_MSG_NEWRULE,
+                NLM_F_CREATE | NLM_F_APPEND,
+                rule(name, exprs),
+            );
+        }
+        if let Some(mark) = opts.ct_drop_mark {
+            let mut exprs = vec![ct(NFTA_CT_DREG, NFT_CT_MARK)];
+            exprs.extend(masked_mark_eq(opts.mark_mask, mark));
+            exprs.push(drop());
+            batch.add(
+                NFT_MSG_NEWRULE,
+                NLM_F_CREATE | NLM_F_APPEND,
+                rule(name, exprs),
+            );
+        }
+        batch.add(
+            NFT_MSG_NEWRULE,
+            NLM_F_CREATE | NLM_F_APPEND,
+            queue_rule(
+                name,
+                ifname_key,
+                opts.queue_num,
+                opts.queue_total,
+                opts.bypass,
+            ),
+        );
+
+        if let (Some(accept_mark), Some(drop_mark)) = (opts.ct_accept_mark, opts.ct_drop_mark) {
+            // Runs after the queue, saves the verdict from the daemon to the connection
+            let save_chain = format!("{}-ct", name);
+            batch.add(
+                NFT_MSG_NEWCHAIN,
+                NLM_F_CREATE,
+                chain(&save_chain, hook, NF_IP_PRI_MANGLE + 1),
+            );
+            for (mark, verdict) in vec![(accept_mark, None), (drop_mark, Some(drop()))] {
+                let mut exprs = vec![meta(NFT_META_MARK)];
+                exprs.extend(masked_mark_eq(opts.mark_mask, mark));
+                exprs.extend(set_ct_mark(opts.mark_mask, mark));
+                // the queue can't drop them, or they would never get here
+                exprs.extend(verdict);
+                batch.add(
+                    NFT_MSG_NEWRULE,
+                    NLM_F_CREATE | NLM_F_APPEND,
+                    rule(&save_chain, exprs),
+                );
+            }
+        }
+
+        // Runs after the queue, `reject` doesn't exist as a queue verdict
+        let reject_chain = format!("{}-reject", name);
+        batch.add(
+            NFT_MSG_NEWCHAIN,
+            NLM_F_CREATE,
+            chain(&reject_chain, hook, NF_IP_PRI_FILTER),
+        );
+        let mut tcp = vec![meta(NFT_META_MARK)];
+        tcp.extend(masked_mark_eq(opts.mark_mask, opts.reject_mark));
+        tcp.extend(vec![
+            meta(NFT_META_L4PROTO),
+            cmp(NFT_CMP_EQ, &[IPPROTO_TCP]),
+            reject(NFT_REJECT_TCP_RST, 0),
+        ]);
+        let mut other = vec![meta(NFT_META_MARK)];
+        other.extend(masked_mark_eq(opts.mark_mask, opts.reject_mark));
+        other.push(reject(
+            NFT_REJECT_ICMPX_UNREACH,
+            NFT_REJECT_ICMPX_PORT_UNREACH,
+        ));
+        for exprs in vec![tcp, other] {
+            batch.add(
+                NFT_MSG_NEWRULE,
+                NLM_F_CREATE | NLM_F_APPEND,
+                rule(&reject_chain, exprs),
+            );
+        }
+    }
+    if let Err(e) = batch.send() {
+        // the empty table of the probe would look like hooks left by a crash at startup
+        if !stale {
+            if let Err(e) = remove_nfqueue() {
+                eprintln!("Failed to remove nftables table: {}", e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(stale)
+}
+
+pub fn remove_nfqueue() -> io::Result<()> {
+    let mut batch = Batch::new();
+    batch.add(NFT_MSG_DELTABLE, 0, table());
+    batch.send()
+}
+
+#[test]
+fn attrs_padding() {
+    let attrs = Attrs::new().str(1, "lo").be16(2, 786);
+    assert_eq!(
+        attrs.0,
+        [7, 0, 1, 0, b'l', b'o', 0, 0, 6, 0, 2, 0, 0x03, 0x12, 0, 0]
+    );
+}
+
+#[test]
+fn subnet_mask() {
+    assert_eq!(prefix_mask(4, 0), [0, 0, 0, 0]);
+    assert_eq!(prefix_mask(4, 20), [0xff, 0xff, 0xf0, 0]);
+    assert_eq!(prefix_mask(4, 32), [0xff; 4]);
+}
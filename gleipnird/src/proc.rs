@@ -2,17 +2,41 @@ use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{self, prelude::*};
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{Uid, User};
+
+use crate::proc_events::{ProcEvent, ProcEvents};
 
 type Pid = usize;
 type Inode = u32;
 
 const PROC: &str = "/proc/";
+/// Stops walking up the tree, a PID may be reused while we walk it
+const MAX_ANCESTORS: usize = 64;
+/// Where Docker keeps `<ID>/config.v2.json`
+const DOCKER_CONTAINERS: &str = "/var/lib/docker/containers/";
+/// Every container of Podman run by root
+const PODMAN_CONTAINERS: &str = "/var/lib/containers/storage/overlay-containers/containers.json";
 
 thread_local! {
     static PROC_CACHE: RefCell<ProcCache> = Default::default();
     static INODE_INDEX: RefCell<HashMap<Inode, Pid>> = Default::default();
     static PROC_INDEX: RefCell<HashMap<Pid, Process>> = Default::default();
+    static USER_NAMES: RefCell<HashMap<u32, String>> = Default::default();
+    /// By container ID, names can't be changed in Podman and rarely are in Docker
+    static CONTAINER_NAMES: RefCell<HashMap<String, String>> = Default::default();
+    /// By device and inode, with the modification time and size they were hashed at
+    static EXE_HASHES: RefCell<HashMap<(u64, u64), ((i64, i64, u64), String)>> = Default::default();
+    static PROC_EVENTS: RefCell<Option<ProcEvents>> = RefCell::new(match ProcEvents::subscribe() {
+        Ok(r) => Some(r),
+        Err(e) => {
+            eprintln!("Proc connector unavailable ({}), fallback to listing /proc", e);
+            None
+        }
+    });
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +47,21 @@ pub struct Process {
     /// The process group ID of the process.
     pub pgrp: usize,
     pub exe: String,
+    /// See `get_app`
+    pub app: String,
+    /// See `gleipnir_interface::ProcessInfo::cmdline`
+    pub cmdline: String,
+    /// Real UID and GID
+    pub uid: u32,
+    pub gid: u32,
+    /// See `user_name`
+    pub user: String,
+    /// See `get_ancestors`, only filled by `get_proc_by_inode`
+    pub ancestors: Vec<String>,
+    /// See `get_cgroup`
+    pub cgroup: String,
+    /// See `unit_of`
+    pub unit: String,
     pub inodes: Vec<Inode>,
 }
 
@@ -31,9 +70,14 @@ struct ProcCache {
     new: HashSet<Pid>,
     old: HashSet<Pid>,
     garbage: HashSet<Pid>,
+    /// Forked or executed since they were parsed, told by the proc connector
+    changed: HashSet<Pid>,
+    /// Every process in `/proc` is known, the proc connector tells us about the rest
+    complete: bool,
 }
 
-pub fn get_proc_by_inode(inode: Inode) -> Option<Process> {
+/// `uid` is the owner of the socket, its processes are checked first
+pub fn get_proc_by_inode(inode: Inode, uid: Option<u32>) -> Option<Process> {
     fn get(inode: Inode) -> Option<Process> {
         let_tls!(inode_index, INODE_INDEX);
         let inodes = inode_index.borrow();
@@ -41,7 +85,10 @@ pub fn get_proc_by_inode(inode: Inode) -> Option<Process> {
             let_tls!(proc_index, PROC_INDEX);
             let procs = proc_index.borrow();
             let proc = procs.get(pid).expect("broken cache");
-            proc.clone()
+            Process {
+                ancestors: get_ancestors(*pid),
+                ..proc.clone()
+            }
         })
     }
     if inode == 0 {
@@ -49,23 +96,151 @@ pub fn get_proc_by_inode(inode: Inode) -> Option<Process> {
     }
     get(inode)
         .or_else(|| {
-            add_new_proc_to_cache();
+            if apply_proc_events() {
+                parse_changed_procs();
+            } else {
+                add_new_proc_to_cache();
+            }
             get(inode)
         })
         .or_else(|| {
-            refresh_old_proc_in_cache();
+            // a socket opened by a known process, no event tells us about that
+            refresh_old_proc_in_cache(uid);
             get(inode)
         })
+        .or_else(|| {
+            // the process may have changed its UID after opening the socket
+            if uid.is_none() {
+                return None;
+            }
+            refresh_old_proc_in_cache(None);
+            get(inode)
+        })
+}
+
+/// Finds sockets sock_diag doesn't know in `/proc/net/<table>`, like raw sockets.
+///
+/// The port of raw sockets is the protocol, unspecified addresses match anything
+pub fn find_socket_inode(
+    table: &str,
+    local: SocketAddr,
+    remote: IpAddr,
+) -> Result<Option<Inode>, io::Error> {
+    fn parse_addr(s: &str) -> Option<(IpAddr, u16)> {
+        let (addr, port) = s.split_at(s.find(':')?);
+        let port = u16::from_str_radix(&port[1..], 16).ok()?;
+        // every 32 bits are printed in host byte order
+        let mut words = (0..addr.len() / 8)
+            .map(|i| u32::from_str_radix(&addr[i * 8..i * 8 + 8], 16).map(u32::to_ne_bytes));
+        let addr: IpAddr = if addr.len() == 8 {
+            Ipv4Addr::from(words.next()?.ok()?).into()
+        } else {
+            let mut octets = [0u8; 16];
+            for (i, word) in words.enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word.ok()?);
+            }
+            Ipv6Addr::from(octets).into()
+        };
+        Some((addr, port))
+    }
+
+    let sockets = fs::read_to_string(format!("{}net/{}", PROC, table))?;
+    for line in sockets.lines().skip(1) {
+        let mut columns = line.split_whitespace();
+        let parsed: Option<_> = try {
+            let (local_addr, port) = parse_addr(columns.nth(1)?)?;
+            let (remote_addr, _) = parse_addr(columns.next()?)?;
+            let inode = columns.nth(6)?.parse::<Inode>().ok()?;
+            (local_addr, port, remote_addr, inode)
+        };
+        let (local_addr, port, remote_addr, inode) = match parsed {
+            Some(r) => r,
+            None => continue,
+        };
+        if port == local.port()
+            && (local_addr.is_unspecified() || local_addr == local.ip())
+            && (remote_addr.is_unspecified() || remote_addr == remote)
+            && inode != 0
+        {
+            return Ok(Some(inode));
+        }
+    }
+    Ok(None)
+}
+
+/// Applies events from the proc connector, returns false if `/proc` must be listed again
+fn apply_proc_events() -> bool {
+    let_tls!(proc_events, PROC_EVENTS);
+    let_tls!(proc_cache, PROC_CACHE);
+    let mut events = proc_events.borrow_mut();
+    let events = match &mut *events {
+        Some(r) => r,
+        None => return false,
+    };
+    let mut cache = proc_cache.borrow_mut();
+    loop {
+        match events.recv() {
+            Ok(Some(ProcEvent::Fork { child: pid, .. }))
+            | Ok(Some(ProcEvent::Exec(pid)))
+            | Ok(Some(ProcEvent::Id(pid))) => {
+                cache.changed.insert(pid as Pid);
+            }
+            Ok(Some(ProcEvent::Exit(pid))) => {
+                let pid = pid as Pid;
+                cache.changed.remove(&pid);
+                cache.new.remove(&pid);
+                cache.old.remove(&pid);
+                remove_proc(pid);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Lost proc events: {}", e);
+                cache.complete = false;
+                break;
+            }
+        }
+    }
+    cache.complete
+}
+
+/// Only parses processes the proc connector told us about
+fn parse_changed_procs() {
+    let_tls!(proc_cache, PROC_CACHE);
+    let mut cache = proc_cache.borrow_mut();
+    let ProcCache {
+        new, old, changed, ..
+    } = &mut *cache;
+    // sockets may have been opened since they were parsed
+    old.extend(new.drain());
+    for pid in changed.drain() {
+        old.remove(&pid);
+        let path: PathBuf = format!("{}{}", PROC, pid).into();
+        match parse_proc_pid(path, pid) {
+            Ok(proc) => {
+                insert_proc(proc);
+                new.insert(pid);
+            }
+            // exited already
+            Err(_) => remove_proc(pid),
+        }
+    }
 }
 
 fn add_new_proc_to_cache() {
+    let has_events = {
+        let_tls!(proc_events, PROC_EVENTS);
+        let has_events = proc_events.borrow().is_some();
+        has_events
+    };
     let_tls!(proc_cache, PROC_CACHE);
-    let_tls!(inode_index, INODE_INDEX);
-    let_tls!(proc_index, PROC_INDEX);
     let mut cache = proc_cache.borrow_mut();
-    let mut inodes = inode_index.borrow_mut();
-    let mut procs = proc_index.borrow_mut();
-    let ProcCache { new, old, garbage } = &mut *cache;
+    let ProcCache {
+        new,
+        old,
+        garbage,
+        changed,
+        complete,
+    } = &mut *cache;
     garbage.clear();
     garbage.extend(new.drain());
     garbage.extend(old.drain());
@@ -85,54 +260,369 @@ fn add_new_proc_to_cache() {
             }
         })
     {
-        if garbage.remove(&pid) {
+        if garbage.remove(&pid) && !changed.contains(&pid) {
             old.insert(pid);
         } else {
-            let proc = match parse_proc_pid(entry.path(), pid) {
-                Ok(r) => r,
-                Err(_) => continue,
-            };
-            for &inode in &proc.inodes {
-                inodes.insert(inode, pid);
+            match parse_proc_pid(entry.path(), pid) {
+                Ok(proc) => {
+                    insert_proc(proc);
+                    new.insert(pid);
+                }
+                Err(_) => remove_proc(pid),
+            }
+        }
+    }
+    changed.clear();
+    for pid in garbage.drain() {
+        remove_proc(pid);
+    }
+    *complete = has_events;
+}
+
+/// Parses old processes again, only those of `uid` if it's not `None`
+fn refresh_old_proc_in_cache(uid: Option<u32>) {
+    let_tls!(proc_cache, PROC_CACHE);
+    let mut cache = proc_cache.borrow_mut();
+    let ProcCache { new, old, .. } = &mut *cache;
+    let pids: Vec<Pid> = old
+        .iter()
+        .cloned()
+        .filter(|&pid| uid.is_none() || proc_uid(pid) == uid)
+        .collect();
+    for pid in pids {
+        old.remove(&pid);
+        let path: PathBuf = format!("{}{}", PROC, pid).into();
+        match parse_proc_pid(path, pid) {
+            Ok(proc) => {
+                insert_proc(proc);
+                new.insert(pid);
             }
-            procs.insert(pid, proc);
-            new.insert(pid);
+            Err(_) => remove_proc(pid),
         }
     }
-    for pid in &*garbage {
-        let proc = procs.remove(pid).expect("");
+}
+
+/// The owner of `/proc/<pid>` is the effective UID of the process
+fn proc_uid(pid: Pid) -> Option<u32> {
+    fs::metadata(format!("{}{}", PROC, pid))
+        .ok()
+        .map(|m| m.uid())
+}
+
+/// Replaces the process in both indexes
+fn insert_proc(proc: Process) {
+    let pid = proc.pid;
+    remove_proc(pid);
+    let_tls!(inode_index, INODE_INDEX);
+    let_tls!(proc_index, PROC_INDEX);
+    let mut inodes = inode_index.borrow_mut();
+    for &inode in &proc.inodes {
+        inodes.insert(inode, pid);
+    }
+    proc_index.borrow_mut().insert(pid, proc);
+}
+
+fn remove_proc(pid: Pid) {
+    let_tls!(inode_index, INODE_INDEX);
+    let_tls!(proc_index, PROC_INDEX);
+    if let Some(proc) = proc_index.borrow_mut().remove(&pid) {
+        let mut inodes = inode_index.borrow_mut();
         for inode in &proc.inodes {
-            inodes.remove(inode);
-        }
-    }
-}
-
-fn refresh_old_proc_in_cache() {
-    PROC_CACHE.with(|proc_cache| {
-        INODE_INDEX.with(|inode_index| {
-            PROC_INDEX.with(|proc_index| {
-                let mut cache = proc_cache.borrow_mut();
-                let mut inodes = inode_index.borrow_mut();
-                let mut procs = proc_index.borrow_mut();
-                let ProcCache { new, old, .. } = &mut *cache;
-                for pid in old.drain() {
-                    let path: PathBuf = format!("{}{}", PROC, pid).into();
-                    if let Ok(proc) = parse_proc_pid(path, pid) {
-                        for &inode in &proc.inodes {
-                            inodes.insert(inode, pid);
-                        }
-                        procs.insert(pid, proc);
-                        new.insert(pid);
-                    } else {
-                        let proc = procs.remove(&pid).expect("");
-                        for inode in &proc.inodes {
-                            inodes.remove(inode);
-                        }
-                    }
-                }
+            // a shared socket may be indexed to another process
+            if inodes.get(inode) == Some(&pid) {
+                inodes.remove(inode);
+            }
+        }
+    }
+}
+
+/// Empty if the process is gone or has no executable, like kernel threads
+pub fn get_exe(pid: Pid) -> String {
+    fs::read_link(format!("{}{}/exe", PROC, pid))
+        .unwrap_or_default()
+        .to_str()
+        .expect("symlink not a vaild UTF-8")
+        .to_owned()
+}
+
+/// See `gleipnir_interface::Rule::sha256`, `None` if the process is gone.
+///
+/// Files are hashed again only if modified, replaced ones have another inode anyway
+pub fn get_exe_sha256(pid: Pid) -> Option<String> {
+    // the file running, even if it was deleted since then
+    let path = format!("{}{}/exe", PROC, pid);
+    let meta = fs::metadata(&path).ok()?;
+    let key = (meta.dev(), meta.ino());
+    let version = (meta.mtime(), meta.mtime_nsec(), meta.size());
+    let_tls!(exe_hashes, EXE_HASHES);
+    if let Some((hashed, hash)) = exe_hashes.borrow().get(&key) {
+        if *hashed == version {
+            return Some(hash.clone());
+        }
+    }
+    let hash = gleipnir_interface::sha256_file(&path).ok()?;
+    exe_hashes.borrow_mut().insert(key, (version, hash.clone()));
+    Some(hash)
+}
+
+/// See `gleipnir_interface::ProcessInfo::cmdline`, empty if the process is gone
+pub fn get_cmdline(pid: Pid, exe: &str) -> String {
+    let path = format!("{}{}", PROC, pid);
+    read_args(format!("{}/cmdline", path))
+        .map(|args| {
+            resolve_cmdline(exe, &args, || {
+                fs::read_link(format!("{}/cwd", path)).unwrap_or_default()
             })
         })
-    })
+        .unwrap_or_default()
+}
+
+/// See `gleipnir_interface::ProcessInfo::app`, empty for other programs
+pub fn get_app(pid: Pid, exe: &str, cgroup: &str) -> String {
+    fn get(path: &str, exe: &str, cgroup: &str) -> Option<String> {
+        // the executable is inside the image, the container says more
+        if let Some((runtime, id)) = container_id(cgroup) {
+            let name = container_name(runtime, id).unwrap_or_else(|| id[..12].to_owned());
+            Some(format!("{}:{}", runtime, name))
+        } else if let Ok(info) = fs::read_to_string(format!("{}/root/.flatpak-info", path)) {
+            // in the root of the sandbox, the app can't change it
+            Some(format!("flatpak:{}", flatpak_app(&info)?))
+        } else if exe.starts_with("/snap/") {
+            // only root mounts snaps, but wrappers run executables of the base snaps
+            let name = snap_name(cgroup).or_else(|| exe.split('/').nth(2))?;
+            Some(format!("snap:{}", name))
+        } else if exe.starts_with("/tmp/.mount_") {
+            // set by the runtime which mounted the image, and the source of the mount
+            let environ = fs::read(format!("{}/environ", path)).ok()?;
+            let mountinfo = fs::read_to_string(format!("{}/mountinfo", path)).ok()?;
+            Some(format!(
+                "appimage:{}",
+                appimage_path(&environ, &mountinfo, exe)?
+            ))
+        } else {
+            None
+        }
+    }
+    get(&format!("{}{}", PROC, pid), exe, cgroup).unwrap_or_default()
+}
+
+/// See `gleipnir_interface::ProcessInfo::cgroup`, empty if the process is gone
+pub fn get_cgroup(pid: Pid) -> String {
+    fs::read_to_string(format!("{}{}/cgroup", PROC, pid))
+        .ok()
+        .and_then(|cgroups| parse_cgroup(&cgroups).map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Lines are `<hierarchy ID>:<controllers>:<path>`
+fn parse_cgroup(cgroups: &str) -> Option<&str> {
+    let mut unified = None;
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        match (id, controllers) {
+            ("0", "") if path != "/" => return Some(path),
+            // only the root in the hybrid mode of systemd
+            ("0", "") => unified = Some(path),
+            (_, "name=systemd") => return Some(path),
+            _ => (),
+        }
+    }
+    unified
+}
+
+/// See `gleipnir_interface::ProcessInfo::unit`, empty if it's in none
+pub fn unit_of(cgroup: &str) -> &str {
+    cgroup
+        .rsplit('/')
+        .find(|name| name.ends_with(".service") || name.ends_with(".scope"))
+        .unwrap_or_default()
+}
+
+/// `name` in the `Application` group
+fn flatpak_app(info: &str) -> Option<&str> {
+    let mut group = "";
+    for line in info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            group = line;
+        } else if group == "[Application]" && line.starts_with("name=") {
+            return Some(&line[5..]);
+        }
+    }
+    None
+}
+
+/// Users can start scopes and services named like anything in their own sessions
+fn is_user_cgroup(cgroup: &str) -> bool {
+    cgroup.starts_with("/user.slice/")
+}
+
+/// Units of snaps are named like `snap.<name>.<app>-<uuid>.scope` or `snap.<name>.<app>.service`,
+/// system ones only
+fn snap_name(cgroup: &str) -> Option<&str> {
+    if is_user_cgroup(cgroup) {
+        return None;
+    }
+    cgroup
+        .rsplit('/')
+        .next()
+        .filter(|unit| unit.starts_with("snap."))
+        .and_then(|unit| unit.split('.').nth(1))
+}
+
+/// The runtime and the ID, from scopes like `docker-<ID>.scope` and `libpod-<ID>.scope`,
+/// or `/docker/<ID>` without systemd. Rootless containers are in user sessions, not trusted
+fn container_id(cgroup: &str) -> Option<(&str, &str)> {
+    if is_user_cgroup(cgroup) {
+        return None;
+    }
+    let mut parent = "";
+    for name in cgroup.split('/') {
+        let scope = name.trim_end_matches(".scope");
+        let found = if scope.starts_with("docker-") {
+            ("docker", &scope[7..])
+        } else if scope.starts_with("libpod-") {
+            ("podman", &scope[7..])
+        } else if parent == "docker" {
+            ("docker", name)
+        } else {
+            ("", "")
+        };
+        // conmon watches the container from `libpod-conmon-<ID>.scope`, not a container
+        if found.1.len() == 64 && found.1.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Some(found);
+        }
+        parent = name;
+    }
+    None
+}
+
+/// Named by their short IDs if the runtime doesn't know them
+fn container_name(runtime: &str, id: &str) -> Option<String> {
+    let_tls!(container_names, CONTAINER_NAMES);
+    if let Some(name) = container_names.borrow().get(id) {
+        return Some(name.clone());
+    }
+    let name = if runtime == "docker" {
+        let config = fs::read_to_string(format!("{}{}/config.v2.json", DOCKER_CONTAINERS, id));
+        docker_name(&config.ok()?)?
+    } else {
+        podman_name(&fs::read_to_string(PODMAN_CONTAINERS).ok()?, id)?
+    };
+    container_names
+        .borrow_mut()
+        .insert(id.to_owned(), name.clone());
+    Some(name)
+}
+
+fn docker_name(config: &str) -> Option<String> {
+    let config: serde_json::Value = serde_json::from_str(config).ok()?;
+    let name = config.get("Name")?.as_str()?;
+    Some(name.trim_start_matches('/').to_owned())
+}
+
+fn podman_name(containers: &str, id: &str) -> Option<String> {
+    let containers: Vec<serde_json::Value> = serde_json::from_str(containers).ok()?;
+    let container = containers
+        .iter()
+        .find(|c| c.get("id").and_then(serde_json::Value::as_str) == Some(id))?;
+    Some(container.get("names")?.get(0)?.as_str()?.to_owned())
+}
+
+/// `APPIMAGE` in the environment, if it's the source of the FUSE mount `exe` is in. Anyone
+/// can set the variable, or mount anything in `/tmp`
+fn appimage_path(environ: &[u8], mountinfo: &str, exe: &str) -> Option<String> {
+    let image = environ
+        .split(|&b| b == 0)
+        .find(|var| var.starts_with(b"APPIMAGE="))
+        .map(|var| String::from_utf8_lossy(&var[9..]).into_owned())?;
+    // `/tmp/.mount_<ID>`
+    let mount_point_len = exe.match_indices('/').nth(2).map_or(exe.len(), |(i, _)| i);
+    let mount_point = &exe[..mount_point_len];
+    // `<ID> <parent> <device> <root> <mount point> <options>... - <type> <source> <options>`
+    let source = mountinfo.lines().find_map(|line| {
+        let mut halves = line.splitn(2, " - ");
+        let fields = halves.next()?;
+        let mut fs = halves.next()?.split(' ');
+        let (kind, source) = (fs.next()?, fs.next()?);
+        let this_mount_point = fields.split(' ').nth(4)?;
+        if kind.starts_with("fuse") && unescape_mount(this_mount_point) == mount_point {
+            Some(unescape_mount(source))
+        } else {
+            None
+        }
+    })?;
+    Some(image).filter(|image| *image == source)
+}
+
+/// Spaces, tabs, newlines and backslashes are escaped as octal like `\040` in mountinfo
+fn unescape_mount(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('\\') {
+        r.push_str(&rest[..i]);
+        match rest
+            .get(i + 1..i + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok())
+        {
+            Some(b) => {
+                r.push(b as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                r.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    r.push_str(rest);
+    r
+}
+
+/// Executables of the parent, its parent and so on.
+///
+/// Orphans are adopted without any event, so the tree is read again every time
+pub fn get_ancestors(mut pid: Pid) -> Vec<String> {
+    let mut ancestors = Vec::new();
+    while ancestors.len() < MAX_ANCESTORS {
+        let ppid = match fs::read_to_string(format!("{}{}/stat", PROC, pid))
+            .ok()
+            .as_deref()
+            .and_then(parse_stat)
+        {
+            // init and kthreadd have no parent
+            Some((ppid, _)) if ppid != 0 => ppid,
+            _ => break,
+        };
+        ancestors.push(get_exe(ppid));
+        pid = ppid;
+    }
+    ancestors
+}
+
+/// Clock ticks after boot when the process started, which tells it from a later one with the
+/// same PID. `None` if it's gone
+pub fn get_start_time(pid: Pid) -> Option<u64> {
+    let stat = fs::read_to_string(format!("{}{}/stat", PROC, pid)).ok()?;
+    // the 22nd field, counted after the name like in `parse_stat`
+    stat.rsplit(')').next()?.split(' ').nth(20)?.parse().ok()
+}
+
+/// The parent and process group of a process
+fn parse_stat(stat: &str) -> Option<(Pid, usize)> {
+    // the name in parentheses may have spaces and parentheses too
+    let mut iter = stat.rsplit(')').next()?.split(' ').skip(2);
+    let ppid = iter.next()?.parse().ok()?;
+    let pgrp = iter.next()?.parse().ok()?;
+    Some((ppid, pgrp))
+}
+
+fn read_args(path: impl AsRef<Path>) -> io::Result<Vec<String>> {
+    let args = fs::read(path)?;
+    Ok(String::from_utf8_lossy(&args)
+        .split_terminator('\0')
+        .map(str::to_owned)
+        .collect())
 }
 
 // http://manpages.ubuntu.com/manpages/bionic/en/man5/proc.5.html
@@ -157,20 +647,361 @@ fn parse_proc_pid(mut path: PathBuf, pid: usize) -> Result<Process, io::Error> {
         .expect("symlink not a vaild UTF-8")
         .to_owned();
     path.pop();
+    path.push("cgroup");
+    let cgroup = parse_cgroup(&fs::read_to_string(&path)?)
+        .unwrap_or_default()
+        .to_owned();
+    let unit = unit_of(&cgroup).to_owned();
+    let app = get_app(pid, &exe, &cgroup);
+    path.pop();
+    path.push("cmdline");
+    let args = read_args(&path)?;
+    path.pop();
+    path.push("cwd");
+    let cmdline = resolve_cmdline(&exe, &args, || fs::read_link(&path).unwrap_or_default());
+    path.pop();
+    path.push("status");
+    let status = fs::read_to_string(&path)?;
+    // real, effective, saved set and filesystem ones
+    let real_id = |key: &str| -> io::Result<u32> {
+        status
+            .lines()
+            .find(|line| line.starts_with(key))
+            .and_then(|line| line[key.len()..].split_whitespace().next())
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no ID in status"))
+    };
+    let (uid, gid) = (real_id("Uid:")?, real_id("Gid:")?);
+    path.pop();
     path.push("stat");
     let mut stat = File::open(path)?;
     let mut buf = [0u8; 512];
     let n = stat.read(&mut buf)?;
     let stat = std::str::from_utf8(&buf[..n]).expect("stat not a vaild UTF-8");
-    // TODO: expect message
-    let mut iter = stat.rsplit(')').next().expect("").split(' ').skip(2);
-    let ppid = iter.next().expect("").parse().expect("");
-    let pgrp = iter.next().expect("").parse().expect("");
+    let (ppid, pgrp) = parse_stat(stat).expect("broken stat");
     Ok(Process {
         pid,
         ppid,
         pgrp,
         exe,
+        app,
+        cmdline,
+        uid,
+        gid,
+        user: user_name(uid),
+        ancestors: Vec::new(),
+        cgroup,
+        unit,
         inodes,
     })
 }
+
+/// The number if the user has no name
+pub fn user_name(uid: u32) -> String {
+    let_tls!(user_names, USER_NAMES);
+    user_names
+        .borrow_mut()
+        .entry(uid)
+        .or_insert_with(|| match User::from_uid(Uid::from_raw(uid)) {
+            Ok(Some(user)) => user.name,
+            _ => uid.to_string(),
+        })
+        .clone()
+}
+
+struct Interpreter {
+    /// Without the version suffix, `python` for `python3.8`
+    name: &'static str,
+    /// Options running code from arguments, no script then
+    code: &'static [&'static str],
+    /// Options taking the next argument as value
+    valued: &'static [&'static str],
+}
+
+const SHELL_VALUED: &[&str] = &["-o", "-O", "+o", "+O"];
+const NODE_CODE: &[&str] = &["-e", "-p", "--eval", "--print"];
+const NODE_VALUED: &[&str] = &["-r", "--require"];
+
+const INTERPRETERS: &[Interpreter] = &[
+    Interpreter {
+        name: "python",
+        code: &["-c", "-m"],
+        valued: &["-W", "-X", "--check-hash-based-pycs"],
+    },
+    Interpreter {
+        name: "perl",
+        code: &["-e", "-E"],
+        valued: &[],
+    },
+    Interpreter {
+        name: "ruby",
+        code: &["-e"],
+        valued: &["-I", "-r", "-C"],
+    },
+    Interpreter {
+        name: "node",
+        code: NODE_CODE,
+        valued: NODE_VALUED,
+    },
+    Interpreter {
+        name: "nodejs",
+        code: NODE_CODE,
+        valued: NODE_VALUED,
+    },
+    Interpreter {
+        name: "php",
+        code: &["-r"],
+        valued: &["-c", "-d", "-z"],
+    },
+    Interpreter {
+        name: "lua",
+        code: &["-e"],
+        valued: &["-l"],
+    },
+    Interpreter {
+        name: "sh",
+        code: &["-c"],
+        valued: SHELL_VALUED,
+    },
+    Interpreter {
+        name: "bash",
+        code: &["-c"],
+        valued: SHELL_VALUED,
+    },
+    Interpreter {
+        name: "dash",
+        code: &["-c"],
+        valued: SHELL_VALUED,
+    },
+    Interpreter {
+        name: "zsh",
+        code: &["-c"],
+        valued: SHELL_VALUED,
+    },
+    Interpreter {
+        name: "fish",
+        code: &["-c", "--command"],
+        valued: &["-C", "--init-command"],
+    },
+    Interpreter {
+        name: "java",
+        code: &[],
+        valued: &[
+            "-cp",
+            "-classpath",
+            "--class-path",
+            "-p",
+            "--module-path",
+            "--add-modules",
+        ],
+    },
+];
+
+/// Replaces the interpreter and its options with the script, relative paths are resolved in `cwd`
+fn resolve_cmdline(exe: &str, args: &[String], cwd: impl FnOnce() -> PathBuf) -> String {
+    let name = Path::new(exe)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let interpreter = match INTERPRETERS.iter().find(|i| i.name == name) {
+        Some(r) => r,
+        None => return args.join(" "),
+    };
+    let mut i = 1;
+    let script = loop {
+        let arg = match args.get(i) {
+            Some(r) => r.as_str(),
+            None => return args.join(" "),
+        };
+        if arg == "--" || arg == "-jar" {
+            i += 1;
+            match args.get(i) {
+                Some(r) => break r,
+                None => return args.join(" "),
+            }
+        }
+        if interpreter.code.contains(&arg) {
+            return args.join(" ");
+        }
+        if !arg.starts_with('-') && !arg.starts_with('+') {
+            break &args[i];
+        }
+        if interpreter.valued.contains(&arg) {
+            i += 1;
+        }
+        i += 1;
+    };
+    // the main class of java is not a file
+    let is_class = interpreter.name == "java" && args[i - 1] != "-jar";
+    let mut cmdline = if is_class {
+        script.clone()
+    } else {
+        // `.` is dropped by `components`
+        let path: PathBuf = cwd().join(script).components().collect();
+        path.to_string_lossy().into_owned()
+    };
+    for arg in &args[i + 1..] {
+        cmdline.push(' ');
+        cmdline.push_str(arg);
+    }
+    cmdline
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interpreter_cmdline() {
+        let resolve = |exe, args: &[&str]| {
+            let args: Vec<_> = args.iter().map(|s| s.to_string()).collect();
+            resolve_cmdline(exe, &args, || "/home/user".into())
+        };
+        assert_eq!(
+            resolve("/usr/bin/python3.8", &["python3", "-u", "./foo.py", "-v"]),
+            "/home/user/foo.py -v"
+        );
+        assert_eq!(
+            resolve(
+                "/usr/bin/python3.8",
+                &["python3", "-W", "ignore", "/opt/bar.py"]
+            ),
+            "/opt/bar.py"
+        );
+        assert_eq!(
+            resolve("/usr/bin/python3.8", &["python3", "-m", "http.server"]),
+            "python3 -m http.server"
+        );
+        assert_eq!(
+            resolve(
+                "/usr/lib/jvm/bin/java",
+                &["java", "-Xmx1g", "-jar", "x.jar", "a"]
+            ),
+            "/home/user/x.jar a"
+        );
+        assert_eq!(
+            resolve("/usr/lib/jvm/bin/java", &["java", "-cp", "lib", "org.Main"]),
+            "org.Main"
+        );
+        assert_eq!(resolve("/usr/bin/bash", &["-bash"]), "-bash");
+        assert_eq!(
+            resolve("/usr/bin/curl", &["curl", "-s", "example.com"]),
+            "curl -s example.com"
+        );
+    }
+
+    #[test]
+    fn stat() {
+        assert_eq!(
+            parse_stat("1234 (a) b (c)) S 1000 1234 1000 34817 1234 4194304"),
+            Some((1000, 1234))
+        );
+        assert_eq!(parse_stat("1234 (a"), None);
+    }
+
+    #[test]
+    fn app() {
+        let info = "[Application]\nname=org.mozilla.firefox\n\n[Instance]\nname=x\n";
+        assert_eq!(flatpak_app(info), Some("org.mozilla.firefox"));
+        assert_eq!(flatpak_app("[Instance]\nname=x\n"), None);
+        assert_eq!(
+            snap_name("/system.slice/snap.lxd.daemon.service"),
+            Some("lxd")
+        );
+        // started by the user, it may be named like any snap
+        assert_eq!(
+            snap_name("/user.slice/user-1000.slice/user@1000.service/app.slice/snap.firefox.firefox-4cc7.scope"),
+            None
+        );
+        assert_eq!(snap_name("/system.slice/cron.service"), None);
+
+        let environ = b"HOME=/home/user\0APPIMAGE=/home/user/My Apps/Gleipnir.AppImage\0";
+        let mountinfo = "26 1 0:23 / /tmp rw,nosuid - tmpfs tmpfs rw\n\
+                         90 26 0:48 / /tmp/.mount_GleipnAbCdEf ro,nosuid,nodev - \
+                         fuse.Gleipnir.AppImage /home/user/My\\040Apps/Gleipnir.AppImage ro\n";
+        let exe = "/tmp/.mount_GleipnAbCdEf/usr/bin/gleipnir";
+        assert_eq!(
+            appimage_path(environ, mountinfo, exe),
+            Some("/home/user/My Apps/Gleipnir.AppImage".into())
+        );
+        assert_eq!(appimage_path(b"HOME=/home/user\0", mountinfo, exe), None);
+        // the variable names another image than the one mounted
+        let other = b"APPIMAGE=/home/user/Other.AppImage\0";
+        assert_eq!(appimage_path(other, mountinfo, exe), None);
+        // not a mount at all
+        let fake = "/tmp/.mount_GleipnFake/usr/bin/gleipnir";
+        assert_eq!(appimage_path(environ, mountinfo, fake), None);
+    }
+
+    #[test]
+    fn container() {
+        let id = "4f8c0b1e2a3d5c6b7a8f9e0d1c2b3a4f5e6d7c8b9a0f1e2d3c4b5a6f7e8d9c0b";
+        let scope = format!("/system.slice/docker-{}.scope", id);
+        assert_eq!(container_id(&scope), Some(("docker", id)));
+        assert_eq!(
+            container_id(&format!("/docker/{}", id)),
+            Some(("docker", id))
+        );
+        let rootless = format!(
+            "/user.slice/user-1000.slice/user@1000.service/user.slice/libpod-{}.scope/container",
+            id
+        );
+        assert_eq!(container_id(&rootless), None);
+        let machine = format!("/machine.slice/libpod-{}.scope/container", id);
+        assert_eq!(container_id(&machine), Some(("podman", id)));
+        let conmon = format!("/machine.slice/libpod-conmon-{}.scope", id);
+        assert_eq!(container_id(&conmon), None);
+        assert_eq!(container_id("/system.slice/docker.service"), None);
+
+        let config = r#"{"ID":"4f8c","Name":"/web","Config":{"Image":"nginx"}}"#;
+        assert_eq!(docker_name(config), Some("web".into()));
+        let containers = format!(
+            r#"[{{"id":"0123","names":["db"]}},{{"id":"{}","names":["web"],"image":"a1b2"}}]"#,
+            id
+        );
+        assert_eq!(podman_name(&containers, id), Some("web".into()));
+        assert_eq!(podman_name(&containers, "4567"), None);
+    }
+
+    #[test]
+    fn cgroup() {
+        let v2 = "0::/system.slice/systemd-resolved.service\n";
+        assert_eq!(
+            parse_cgroup(v2),
+            Some("/system.slice/systemd-resolved.service")
+        );
+        let hybrid = "12:pids:/user.slice/user-1000.slice\n\
+                      1:name=systemd:/user.slice/user-1000.slice/session-2.scope\n\
+                      0::/\n";
+        assert_eq!(
+            parse_cgroup(hybrid),
+            Some("/user.slice/user-1000.slice/session-2.scope")
+        );
+        assert_eq!(parse_cgroup("0::/\n"), Some("/"));
+        assert_eq!(
+            unit_of("/user.slice/user-1000.slice/user@1000.service/app.slice/foo.service"),
+            "foo.service"
+        );
+        assert_eq!(
+            unit_of("/user.slice/user-1000.slice/user@1000.service/app.slice"),
+            "user@1000.service"
+        );
+        assert_eq!(unit_of("/"), "");
+    }
+
+    #[test]
+    fn exe_sha256() {
+        let pid = std::process::id() as Pid;
+        let hash = get_exe_sha256(pid).unwrap();
+        assert_eq!(hash.len(), 64);
+        assert_eq!(get_exe_sha256(pid), Some(hash));
+    }
+
+    #[test]
+    fn ancestors_of_self() {
+        let ancestors = get_ancestors(std::process::id() as Pid);
+        assert!(!ancestors.is_empty());
+        assert!(ancestors.len() < MAX_ANCESTORS);
+    }
+}
@@ -0,0 +1,211 @@
+//! A client of the netlink proc connector, the kernel tells us about new and dead processes
+//!
+//! Every subscriber receives all events, so each thread can have its own
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const NLMSG_DONE: u16 = 3;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+
+const PROC_EVENT_FORK: u32 = 0x1;
+const PROC_EVENT_EXEC: u32 = 0x2;
+const PROC_EVENT_UID: u32 = 0x4;
+const PROC_EVENT_GID: u32 = 0x40;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDR_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+/// `what`, `cpu` and `timestamp_ns` of `struct proc_event`
+const PROC_EVENT_HDR_LEN: usize = 16;
+
+/// PIDs are thread group ids, events of other threads are ignored
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProcEvent {
+    Fork {
+        parent: u32,
+        child: u32,
+    },
+    Exec(u32),
+    /// UID or GID changed
+    Id(u32),
+    Exit(u32),
+}
+
+pub struct ProcEvents {
+    fd: RawFd,
+}
+
+impl ProcEvents {
+    /// Fails without `CAP_NET_ADMIN`, or if the kernel has no `CONFIG_PROC_EVENTS`
+    pub fn subscribe() -> io::Result<ProcEvents> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let events = ProcEvents { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = CN_IDX_PROC;
+        let r = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let len = NLMSG_HDR_LEN + CN_MSG_LEN + 4;
+        let mut msg = Vec::with_capacity(len);
+        // nlmsghdr
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&NLMSG_DONE.to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        // cn_msg
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&4u16.to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        msg.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+        let r = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(events)
+    }
+
+    /// Returns `None` when there are no more events for now.
+    ///
+    /// An error means some events are lost, the caller must find out what changed by itself
+    pub fn recv(&mut self) -> io::Result<Option<ProcEvent>> {
+        let mut buf = [0u8; 256];
+        loop {
+            let n =
+                unsafe { libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(None),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            if let Some(event) = parse(&buf[..n as usize]) {
+                return Ok(Some(event));
+            }
+        }
+    }
+}
+
+impl AsRawFd for ProcEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for ProcEvents {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// `None` for events we don't care about
+fn parse(msg: &[u8]) -> Option<ProcEvent> {
+    let event = msg.get(NLMSG_HDR_LEN + CN_MSG_LEN..)?;
+    let u32_at = |i: usize| -> Option<u32> {
+        let b = event.get(i..i + 4)?;
+        Some(u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    let data = PROC_EVENT_HDR_LEN;
+    Some(match u32_at(0)? {
+        PROC_EVENT_FORK => {
+            let (parent, child_pid, child) =
+                (u32_at(data + 4)?, u32_at(data + 8)?, u32_at(data + 12)?);
+            // a new thread
+            if child_pid != child {
+                return None;
+            }
+            ProcEvent::Fork { parent, child }
+        }
+        PROC_EVENT_EXEC => ProcEvent::Exec(u32_at(data + 4)?),
+        PROC_EVENT_UID | PROC_EVENT_GID => {
+            let (pid, tgid) = (u32_at(data)?, u32_at(data + 4)?);
+            // every thread changes, report it once
+            if pid != tgid {
+                return None;
+            }
+            ProcEvent::Id(tgid)
+        }
+        PROC_EVENT_EXIT => {
+            let (pid, tgid) = (u32_at(data)?, u32_at(data + 4)?);
+            // only the whole thread group
+            if pid != tgid {
+                return None;
+            }
+            ProcEvent::Exit(tgid)
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(what: u32, data: &[u32]) -> Vec<u8> {
+        let mut msg = vec![0u8; NLMSG_HDR_LEN + CN_MSG_LEN + PROC_EVENT_HDR_LEN];
+        msg[NLMSG_HDR_LEN + CN_MSG_LEN..][..4].copy_from_slice(&what.to_ne_bytes());
+        for x in data {
+            msg.extend_from_slice(&x.to_ne_bytes());
+        }
+        msg
+    }
+
+    #[test]
+    fn parse_events() {
+        assert_eq!(
+            parse(&msg(PROC_EVENT_FORK, &[1, 1, 2, 2])),
+            Some(ProcEvent::Fork {
+                parent: 1,
+                child: 2
+            })
+        );
+        // thread
+        assert_eq!(parse(&msg(PROC_EVENT_FORK, &[1, 1, 3, 1])), None);
+        assert_eq!(
+            parse(&msg(PROC_EVENT_EXEC, &[2, 2])),
+            Some(ProcEvent::Exec(2))
+        );
+        assert_eq!(
+            parse(&msg(PROC_EVENT_EXIT, &[2, 2, 0, 17])),
+            Some(ProcEvent::Exit(2))
+        );
+        assert_eq!(parse(&msg(PROC_EVENT_EXIT, &[3, 2, 0, 17])), None);
+        assert_eq!(
+            parse(&msg(PROC_EVENT_UID, &[2, 2, 1000, 0])),
+            Some(ProcEvent::Id(2))
+        );
+        // truncated
+        assert_eq!(parse(&msg(PROC_EVENT_EXEC, &[2])), None);
+    }
+}
@@ -0,0 +1,431 @@
+//! Reads the ClientHello in QUIC Initial packets
+//!
+//! Initial packets are protected with keys derived from their destination connection ID,
+//! which anyone on the path can do (RFC 9001, section 5). No secret is involved, so the
+//! forward cipher of AES-128 and HKDF over SHA-256 are all we need. The tag is not checked
+
+use sha2::{Digest, Sha256};
+
+const VERSION_1: u32 = 1;
+/// `initial_salt` of QUIC version 1
+const INITIAL_SALT: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+const TAG_LEN: usize = 16;
+const SAMPLE_LEN: usize = 16;
+
+const FRAME_PADDING: u64 = 0x00;
+const FRAME_PING: u64 = 0x01;
+const FRAME_ACK: u64 = 0x02;
+const FRAME_ACK_ECN: u64 = 0x03;
+const FRAME_CRYPTO: u64 = 0x06;
+
+/// The beginning of the handshake messages in a client Initial packet, as far as the
+/// packet has them in order
+pub fn initial_crypto(packet: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader::new(packet);
+    let first = r.u8()?;
+    // long header, fixed bit, Initial
+    if first & 0xf0 != 0xc0 || r.u32()? != VERSION_1 {
+        return None;
+    }
+    let dcid_len = r.u8()? as usize;
+    let dcid = r.bytes(dcid_len)?;
+    let scid_len = r.u8()? as usize;
+    r.bytes(scid_len)?;
+    let token_len = r.varint()? as usize;
+    r.bytes(token_len)?;
+    let len = r.varint()? as usize;
+    let pn_offset = r.pos;
+
+    let keys = Keys::client_initial(dcid);
+    let sample = packet.get(pn_offset + 4..pn_offset + 4 + SAMPLE_LEN)?;
+    let mut mask = [0u8; 16];
+    mask.copy_from_slice(sample);
+    keys.hp.encrypt(&mut mask);
+    let first = first ^ (mask[0] & 0x0f);
+    let pn_len = (first & 0x03) as usize + 1;
+    let mut pn = 0u64;
+    for (i, b) in packet[pn_offset..pn_offset + pn_len].iter().enumerate() {
+        pn = pn << 8 | u64::from(b ^ mask[1 + i]);
+    }
+
+    let end = pn_offset + len;
+    let mut payload = match packet.get(pn_offset + pn_len..end) {
+        Some(r) => r.get(..r.len().checked_sub(TAG_LEN)?)?.to_vec(),
+        // cut, the rest is still readable
+        None => packet.get(pn_offset + pn_len..)?.to_vec(),
+    };
+    keys.decrypt(pn, &mut payload);
+    crypto_stream(&payload)
+}
+
+/// CRYPTO frames may be out of order, returns what's contiguous from the beginning
+fn crypto_stream(payload: &[u8]) -> Option<Vec<u8>> {
+    let mut r = Reader::new(payload);
+    let mut pieces = Vec::new();
+    while let Some(kind) = r.varint() {
+        match kind {
+            FRAME_PADDING | FRAME_PING => (),
+            FRAME_ACK | FRAME_ACK_ECN => {
+                // largest acknowledged, delay, then the ranges
+                r.varint()?;
+                r.varint()?;
+                let ranges = r.varint()?;
+                r.varint()?;
+                for _ in 0..ranges {
+                    r.varint()?;
+                    r.varint()?;
+                }
+                if kind == FRAME_ACK_ECN {
+                    for _ in 0..3 {
+                        r.varint()?;
+                    }
+                }
+            }
+            FRAME_CRYPTO => {
+                let offset = r.varint()? as usize;
+                let len = r.varint()? as usize;
+                let data = r.bytes(len).unwrap_or_else(|| r.rest());
+                pieces.push((offset, data));
+            }
+            _ => break,
+        }
+    }
+    pieces.sort_by_key(|(offset, _)| *offset);
+    let mut stream = Vec::new();
+    for (offset, data) in pieces {
+        if offset > stream.len() {
+            break;
+        }
+        if offset + data.len() > stream.len() {
+            stream.extend_from_slice(&data[stream.len() - offset..]);
+        }
+    }
+    if stream.is_empty() {
+        None
+    } else {
+        Some(stream)
+    }
+}
+
+/// Big endian, `None` if it's cut
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pub pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let r = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(r)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let r = &self.data[self.pos.min(self.data.len())..];
+        self.pos = self.data.len();
+        r
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// The first two bits are the length, 1, 2, 4 or 8 bytes
+    pub fn varint(&mut self) -> Option<u64> {
+        let first = *self.data.get(self.pos)?;
+        let len = 1 << (first >> 6);
+        let bytes = self.bytes(len)?;
+        let v = bytes[1..]
+            .iter()
+            .fold(u64::from(first & 0x3f), |v, &b| v << 8 | u64::from(b));
+        Some(v)
+    }
+}
+
+struct Keys {
+    key: Aes128,
+    iv: [u8; 12],
+    hp: Aes128,
+}
+
+impl Keys {
+    fn client_initial(dcid: &[u8]) -> Self {
+        let initial_secret = hmac_sha256(&INITIAL_SALT, &[dcid]);
+        let secret = expand_label(&initial_secret, b"client in", 32);
+        let mut iv = [0u8; 12];
+        iv.copy_from_slice(&expand_label(&secret, b"quic iv", 12));
+        Keys {
+            key: Aes128::new(&expand_label(&secret, b"quic key", 16)),
+            iv,
+            hp: Aes128::new(&expand_label(&secret, b"quic hp", 16)),
+        }
+    }
+
+    /// AES-GCM without the tag is AES-CTR, counting from 2
+    fn decrypt(&self, pn: u64, payload: &mut [u8]) {
+        let mut nonce = self.iv;
+        for (n, b) in nonce[4..].iter_mut().zip(&pn.to_be_bytes()) {
+            *n ^= b;
+        }
+        for (i, chunk) in payload.chunks_mut(16).enumerate() {
+            let mut block = [0u8; 16];
+            block[..12].copy_from_slice(&nonce);
+            block[12..].copy_from_slice(&(i as u32 + 2).to_be_bytes());
+            self.key.encrypt(&mut block);
+            for (b, k) in chunk.iter_mut().zip(&block) {
+                *b ^= k;
+            }
+        }
+    }
+}
+
+/// `HKDF-Expand-Label` of TLS 1.3 with an empty context, up to 32 bytes
+fn expand_label(secret: &[u8], label: &[u8], len: usize) -> Vec<u8> {
+    let mut info = vec![0, len as u8, (6 + label.len()) as u8];
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label);
+    info.push(0);
+    let mut okm = hmac_sha256(secret, &[&info, &[1]]);
+    okm.truncate(len);
+    okm
+}
+
+fn hmac_sha256(key: &[u8], data: &[&[u8]]) -> Vec<u8> {
+    const BLOCK_LEN: usize = 64;
+    // our keys are never longer than a block
+    let mut ipad = [0x36u8; BLOCK_LEN];
+    let mut opad = [0x5cu8; BLOCK_LEN];
+    for (i, k) in key.iter().enumerate() {
+        ipad[i] ^= k;
+        opad[i] ^= k;
+    }
+    let mut inner = Sha256::new();
+    inner.update(&ipad[..]);
+    for d in data {
+        inner.update(d);
+    }
+    let mut outer = Sha256::new();
+    outer.update(&opad[..]);
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Encryption only, FIPS 197
+struct Aes128 {
+    round_keys: [[u8; 16]; 11],
+    sbox: [u8; 256],
+}
+
+impl Aes128 {
+    fn new(key: &[u8]) -> Self {
+        let sbox = sbox();
+        let mut words = [[0u8; 4]; 44];
+        for (i, word) in key.chunks(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        let mut rcon = 1u8;
+        for i in 4..44 {
+            let mut temp = words[i - 1];
+            if i % 4 == 0 {
+                temp.rotate_left(1);
+                for b in temp.iter_mut() {
+                    *b = sbox[*b as usize];
+                }
+                temp[0] ^= rcon;
+                rcon = xtime(rcon);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 4][j] ^ temp[j];
+            }
+        }
+        let mut round_keys = [[0u8; 16]; 11];
+        for (i, round_key) in round_keys.iter_mut().enumerate() {
+            for j in 0..4 {
+                round_key[j * 4..j * 4 + 4].copy_from_slice(&words[i * 4 + j]);
+            }
+        }
+        Aes128 { round_keys, sbox }
+    }
+
+    /// The state is column by column, like the input
+    fn encrypt(&self, block: &mut [u8; 16]) {
+        let add_round_key = |block: &mut [u8; 16], round: usize| {
+            for (b, k) in block.iter_mut().zip(&self.round_keys[round]) {
+                *b ^= k;
+            }
+        };
+        add_round_key(block, 0);
+        for round in 1..=10 {
+            for b in block.iter_mut() {
+                *b = self.sbox[*b as usize];
+            }
+            // row r moves left by r
+            let old = *block;
+            for r in 1..4 {
+                for c in 0..4 {
+                    block[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+                }
+            }
+            if round != 10 {
+                for column in block.chunks_mut(4) {
+                    let [a, b, c, d] = [column[0], column[1], column[2], column[3]];
+                    let all = a ^ b ^ c ^ d;
+                    column[0] ^= all ^ xtime(a ^ b);
+                    column[1] ^= all ^ xtime(b ^ c);
+                    column[2] ^= all ^ xtime(c ^ d);
+                    column[3] ^= all ^ xtime(d ^ a);
+                }
+            }
+            add_round_key(block, round);
+        }
+    }
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+/// The multiplicative inverse in GF(2^8), then the affine transformation
+fn sbox() -> [u8; 256] {
+    let mul = |mut a: u8, mut b: u8| {
+        let mut p = 0;
+        while b != 0 {
+            if b & 1 != 0 {
+                p ^= a;
+            }
+            a = xtime(a);
+            b >>= 1;
+        }
+        p
+    };
+    let mut sbox = [0u8; 256];
+    for (x, s) in sbox.iter_mut().enumerate() {
+        // x^254 is the inverse, 0 stays 0
+        let mut inverse = 1u8;
+        for _ in 0..254 {
+            inverse = mul(inverse, x as u8);
+        }
+        *s = inverse
+            ^ inverse.rotate_left(1)
+            ^ inverse.rotate_left(2)
+            ^ inverse.rotate_left(3)
+            ^ inverse.rotate_left(4)
+            ^ 0x63;
+    }
+    sbox
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn aes128() {
+        let aes = Aes128::new(&hex("000102030405060708090a0b0c0d0e0f"));
+        let mut block = [0u8; 16];
+        block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+        aes.encrypt(&mut block);
+        assert_eq!(block.to_vec(), hex("69c4e0d86a7b0430d8cdb78070b4c55a"));
+    }
+
+    /// RFC 9001, appendix A
+    #[test]
+    fn client_initial() {
+        let dcid = hex("8394c8f03e515708");
+        let initial_secret = hmac_sha256(&INITIAL_SALT, &[&dcid]);
+        let secret = expand_label(&initial_secret, b"client in", 32);
+        assert_eq!(
+            secret,
+            hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea")
+        );
+        assert_eq!(
+            expand_label(&secret, b"quic key", 16),
+            hex("1f369613dd76d5467730efcbe3b1a22d")
+        );
+        assert_eq!(
+            expand_label(&secret, b"quic iv", 12),
+            hex("fa044b2f42a3fd3b46fb255c")
+        );
+        assert_eq!(
+            expand_label(&secret, b"quic hp", 16),
+            hex("9f50449e04a0e810283a1e9933adedd2")
+        );
+
+        // the beginning of the protected packet, packet number 2
+        let packet = hex("c000000001088394c8f03e5157080000449e7b9aec34\
+             d1b1c98dd7689fb8ec11d242b123dc9b");
+        // a CRYPTO frame with the beginning of the ClientHello
+        assert_eq!(
+            initial_crypto(&packet),
+            Some(hex("010000ed0303ebf8fa56f129"))
+        );
+    }
+
+    /// A client Initial with a one byte packet number
+    fn protect(dcid: &[u8], pn: u8, payload: &[u8]) -> Vec<u8> {
+        let keys = Keys::client_initial(dcid);
+        let mut packet = vec![0xc0, 0, 0, 0, 1, dcid.len() as u8];
+        packet.extend_from_slice(dcid);
+        let len = 1 + payload.len() + TAG_LEN;
+        packet.extend_from_slice(&[0, 0, 0x40 | (len >> 8) as u8, len as u8]);
+        let pn_offset = packet.len();
+        packet.push(pn);
+        let mut encrypted = payload.to_vec();
+        keys.decrypt(pn.into(), &mut encrypted);
+        packet.extend_from_slice(&encrypted);
+        packet.extend_from_slice(&[0; TAG_LEN]);
+
+        let mut mask = [0u8; 16];
+        mask.copy_from_slice(&packet[pn_offset + 4..pn_offset + 4 + SAMPLE_LEN]);
+        keys.hp.encrypt(&mut mask);
+        packet[0] ^= mask[0] & 0x0f;
+        packet[pn_offset] ^= mask[1];
+        packet
+    }
+
+    #[test]
+    fn roundtrip() {
+        // CRYPTO at 0, then PADDING
+        let mut payload = vec![0x06, 0, 5, 1, 2, 3, 4, 5];
+        payload.extend_from_slice(&[0; 32]);
+        let packet = protect(&hex("0011223344556677"), 7, &payload);
+        assert_eq!(initial_crypto(&packet), Some(vec![1, 2, 3, 4, 5]));
+        // a short header packet
+        let mut packet = packet;
+        packet[0] &= 0x7f;
+        assert_eq!(initial_crypto(&packet), None);
+    }
+
+    #[test]
+    fn out_of_order() {
+        // PADDING, CRYPTO at 3, PING, CRYPTO at 0, CRYPTO at 9
+        let payload = [
+            0, 0, 0x06, 3, 3, 4, 5, 6, 0x01, 0x06, 0, 4, 1, 2, 3, 4, 0x06, 9, 1, 10,
+        ];
+        assert_eq!(crypto_stream(&payload), Some(vec![1, 2, 3, 4, 5, 6]));
+        assert_eq!(crypto_stream(&[0, 0, 0]), None);
+    }
+}
@@ -1,32 +1,56 @@
+use std::collections::HashMap;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::UnixStream;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Instant, SystemTime};
 
 use crossbeam_channel;
 use futures::{compat::Future01CompatExt, executor::block_on, prelude::*};
 use futures_locks::Mutex;
-use gleipnir_interface::{self, unixtransport, Daemon, PackageReport, Rules};
+use gleipnir_interface::{
+    self, unixtransport, AskAnswer, Daemon, PackageReport, Profiles, Rule, RuleTarget, Rules,
+};
 use slab::Slab;
 use tarpc::rpc::context::Context;
 use tarpc::server::Channel;
 use tokio::task::block_in_place;
 use tokio_serde::formats::Bincode;
 
+use crate::ask::{Answer, AnswerSender, Ask, ASK_TIMEOUT};
 use crate::config;
+use crate::expiry;
 use crate::lrlock::Setter;
-use crate::rules::IndexedRules;
+use crate::netfilter::NfqueueGuard;
+use crate::rules::{check_schedules, IndexedRules};
 
 #[derive(Clone)]
 struct MyDaemon {
     peer_pid: u32,
     authenticated: Arc<AtomicBool>,
     rules_setter: Arc<Mutex<Setter<IndexedRules>>>,
+    /// Reinstalled with new rules, `None` with the eBPF backend or without root
+    hooks: Option<Arc<NfqueueGuard>>,
     rules: Arc<Mutex<Rules>>,
+    /// Of the indexed `rules`, changed with them
+    generation: Arc<AtomicU32>,
+    /// Name of the active profile
+    profile: Arc<Mutex<String>>,
     clients: Arc<Mutex<Slab<gleipnir_interface::MonitorClient>>>,
     client_id: Arc<Mutex<Option<usize>>>,
+    answers: AnswerSender,
+    questions: Arc<Mutex<HashMap<u64, Question>>>,
+}
+
+/// Sent to monitors, until they answer or it times out
+#[derive(Clone)]
+struct Question {
+    asked: Instant,
+    report: PackageReport,
+    /// Index and value of the rule which asked, if it is still in the rules
+    asking_rule: Option<(usize, Rule)>,
 }
 
 impl Drop for MyDaemon {
@@ -47,43 +71,241 @@ impl Drop for MyDaemon {
     }
 }
 
+impl MyDaemon {
+    /// Replaces `current`, the locked `rules`. Callers hold the lock of `profile` too until it
+    /// returns, so rules are never saved to another profile than the one they are used with,
+    /// and no change is lost between reading `current` and replacing it
+    async fn update_rules(
+        self,
+        profile: &str,
+        current: &mut Rules,
+        mut rules: Rules,
+        mut notify_self: bool,
+    ) {
+        // the sender has to know when its rules expire
+        if expiry::normalize(&mut rules.rules) {
+            notify_self = true;
+        }
+        self.index_rules(&rules).await;
+        config::save_rules(profile, &rules);
+        *current = rules.clone();
+        let boardcast = async move {
+            let self_id = *self.client_id.lock().compat().await.unwrap();
+            let mut clients = self.clients.lock().compat().await.unwrap();
+            for (id, client) in clients.iter_mut() {
+                if Some(id) == self_id && !notify_self {
+                    continue;
+                }
+                if let Err(e) = client
+                    .on_rules_updated(tarpc::context::current(), rules.clone())
+                    .await
+                {
+                    // TODO: remove client from clients?
+                    dbg!(e);
+                }
+            }
+        };
+        tokio::spawn(boardcast);
+    }
+    /// Workers get a new generation of rules, and forget what the old one accepted
+    async fn index_rules(&self, rules: &Rules) {
+        let indexed_rules = IndexedRules::from(rules.clone());
+        let generation = indexed_rules.generation();
+        self.generation.store(generation, Ordering::Relaxed);
+        self.rules_setter
+            .lock()
+            .compat()
+            .await
+            .unwrap()
+            .set(indexed_rules);
+        if let Some(hooks) = &self.hooks {
+            hooks.set_ct_generation(generation);
+        }
+    }
+    async fn profiles(&self) -> Profiles {
+        let active = self.profile.lock().compat().await.unwrap().clone();
+        let mut names = config::list_profiles().unwrap_or_else(|e| {
+            dbg!(e);
+            Vec::new()
+        });
+        // not saved yet
+        if let Err(i) = names.binary_search(&active) {
+            names.insert(i, active.clone());
+        }
+        Profiles { names, active }
+    }
+    async fn broadcast_profiles(self) {
+        let profiles = self.profiles().await;
+        let mut clients = self.clients.lock().compat().await.unwrap();
+        for (_id, client) in clients.iter_mut() {
+            if let Err(e) = client
+                .on_profiles_updated(tarpc::context::current(), profiles.clone())
+                .await
+            {
+                dbg!(e);
+            }
+        }
+    }
+}
+
 impl gleipnir_interface::Daemon for MyDaemon {
     type SetRulesFut = impl Future<Output = ()>;
     type UnlockFut = impl Future<Output = bool>;
     type InitMonitorFut = impl Future<Output = ()>;
+    type AnswerAskFut = impl Future<Output = ()>;
+    type ApproveSha256Fut = impl Future<Output = ()>;
+    type ListProfilesFut = impl Future<Output = Profiles>;
+    type ActivateProfileFut = impl Future<Output = bool>;
+    type RenameProfileFut = impl Future<Output = bool>;
 
     fn set_rules(self, _: Context, rules: Rules) -> Self::SetRulesFut {
         async move {
             if self.authenticated.load(Ordering::Relaxed) {
-                let indexed_rules = IndexedRules::from(rules.clone());
-                self.rules_setter
-                    .lock()
-                    .compat()
-                    .await
-                    .unwrap()
-                    .set(indexed_rules);
-                config::save_rules(&rules);
-                *self.rules.lock().compat().await.unwrap() = rules.clone();
-                let boardcast = async move {
-                    let self_id = self.client_id.lock().compat().await.unwrap();
-                    if let Some(self_id) = &*self_id {
-                        let mut clients = self.clients.lock().compat().await.unwrap();
-                        for (id, client) in clients.iter_mut() {
-                            if id == *self_id {
-                                continue;
-                            }
-                            if let Err(e) = client
-                                .on_rules_updated(tarpc::context::current(), rules.clone())
-                                .await
-                            {
-                                // TODO: remove client from clients?
-                                dbg!(e);
-                            }
+                let profile = self.profile.lock().compat().await.unwrap();
+                let mut current = self.rules.lock().compat().await.unwrap();
+                self.update_rules(&profile, &mut current, rules, false)
+                    .await;
+            }
+        }
+    }
+    fn answer_ask(self, _: Context, id: u64, answer: AskAnswer) -> Self::AnswerAskFut {
+        async move {
+            if !self.authenticated.load(Ordering::Relaxed) {
+                return;
+            }
+            let report = {
+                let mut questions = self.questions.lock().compat().await.unwrap();
+                if answer == AskAnswer::AllowForever {
+                    questions.remove(&id)
+                } else {
+                    questions.get(&id).cloned()
+                }
+            };
+            let Question {
+                report,
+                asking_rule,
+                ..
+            } = match report {
+                Some(r) => r,
+                None => return, // expired
+            };
+            self.answers.send(Answer {
+                id,
+                exe: report.exe.clone(),
+                answer: Some(answer),
+            });
+            if answer != AskAnswer::AllowForever {
+                return;
+            }
+            let profile = self.profile.lock().compat().await.unwrap();
+            let mut current = self.rules.lock().compat().await.unwrap();
+            let mut rules = current.clone();
+            // right in front of the rule that asked, the ones above it still apply first.
+            // Monitors get the shifted indices with the new rules
+            let position = match asking_rule {
+                Some((i, rule)) if rules.rules.get(i) == Some(&rule) => i,
+                // the rules changed while the user was asked
+                Some((_, rule)) => rules
+                    .rules
+                    .iter()
+                    .position(|r| *r == rule)
+                    .unwrap_or(rules.rules.len()),
+                None => rules.rules.len(),
+            };
+            rules.rules.insert(
+                position,
+                Rule {
+                    // the executables of apps are in sandboxes or temporary mounts
+                    exe: if report.app.is_empty() {
+                        Some(report.exe)
+                    } else {
+                        None
+                    },
+                    app: Some(report.app).filter(|app| !app.is_empty()),
+                    target: RuleTarget::Accept,
+                    ..Default::default()
+                },
+            );
+            self.update_rules(&profile, &mut current, rules, true).await;
+        }
+    }
+    fn approve_sha256(self, _: Context, rule: usize, sha256: String) -> Self::ApproveSha256Fut {
+        async move {
+            if !self.authenticated.load(Ordering::Relaxed) {
+                return;
+            }
+            let profile = self.profile.lock().compat().await.unwrap();
+            let mut current = self.rules.lock().compat().await.unwrap();
+            let mut rules = current.clone();
+            match rules.rules.get_mut(rule) {
+                Some(rule) if rule.sha256.is_some() => rule.sha256 = Some(sha256),
+                // changed since the report
+                _ => return,
+            }
+            self.update_rules(&profile, &mut current, rules, true).await;
+        }
+    }
+    fn list_profiles(self, _: Context) -> Self::ListProfilesFut {
+        async move { self.profiles().await }
+    }
+    fn activate_profile(self, _: Context, name: String) -> Self::ActivateProfileFut {
+        async move {
+            if !self.authenticated.load(Ordering::Relaxed) || !config::is_valid_profile_name(&name)
+            {
+                return false;
+            }
+            {
+                let mut profile = self.profile.lock().compat().await.unwrap();
+                if *profile == name {
+                    return true;
+                }
+                let mut current = self.rules.lock().compat().await.unwrap();
+                let rules = if config::profile_exists(&name) {
+                    match config::load_rules(&name) {
+                        Ok(rules) => rules,
+                        Err(e) => {
+                            dbg!(e);
+                            return false;
                         }
                     }
+                } else {
+                    // only what it saves
+                    let mut rules = current.clone();
+                    expiry::remove_volatile(&mut rules.rules);
+                    rules
                 };
-                tokio::spawn(boardcast);
+                config::save_active_profile(&name);
+                self.clone()
+                    .update_rules(&name, &mut current, rules, true)
+                    .await;
+                *profile = name;
             }
+            self.broadcast_profiles().await;
+            true
+        }
+    }
+    fn rename_profile(self, _: Context, name: String, new_name: String) -> Self::RenameProfileFut {
+        async move {
+            if !self.authenticated.load(Ordering::Relaxed) {
+                return false;
+            }
+            {
+                let mut profile = self.profile.lock().compat().await.unwrap();
+                // the active profile is only saved once its rules change
+                if *profile == name && !config::profile_exists(&name) {
+                    config::save_rules(&name, &*self.rules.lock().compat().await.unwrap());
+                }
+                if let Err(e) = config::rename_profile(&name, &new_name) {
+                    dbg!(e);
+                    return false;
+                }
+                if *profile == name {
+                    config::save_active_profile(&new_name);
+                    *profile = new_name;
+                }
+            }
+            self.broadcast_profiles().await;
+            true
         }
     }
     fn unlock(self, _: Context) -> Self::UnlockFut {
@@ -115,6 +337,9 @@ impl gleipnir_interface::Daemon for MyDaemon {
                 client
                     .on_rules_updated(tarpc::context::current(), rules)
                     .await?;
+                client
+                    .on_profiles_updated(tarpc::context::current(), self.profiles().await)
+                    .await?;
                 *client_id = Some(clients.insert(client));
             };
             if let Err(e) = r {
@@ -125,9 +350,14 @@ impl gleipnir_interface::Daemon for MyDaemon {
 }
 
 pub fn run(
+    profile: String,
     rules: Rules,
     rules_setter: Setter<IndexedRules>,
+    generation: u32,
+    hooks: Option<Arc<NfqueueGuard>>,
     pkt_logs: crossbeam_channel::Receiver<PackageReport>,
+    asks: crossbeam_channel::Receiver<Ask>,
+    answers: AnswerSender,
 ) -> Result<(), std::io::Error> {
     let addr = std::path::PathBuf::from("/var/run/gleipnird");
     if addr.exists() {
@@ -140,10 +370,32 @@ pub fn run(
 
     let rules_setter = Arc::new(Mutex::new(rules_setter));
     let rules = Arc::new(Mutex::new(rules));
+    let generation = Arc::new(AtomicU32::new(generation));
+    let profile = Arc::new(Mutex::new(profile));
 
     let clients: Arc<Mutex<Slab<gleipnir_interface::MonitorClient>>> =
         Arc::new(Mutex::new(Slab::new()));
     let clients2 = clients.clone();
+    let clients3 = clients.clone();
+    let questions: Arc<Mutex<HashMap<u64, Question>>> = Default::default();
+    let questions2 = questions.clone();
+    let rules2 = rules.clone();
+    let generation2 = generation.clone();
+    let answers2 = answers.clone();
+    // updates rules for the daemon itself, when they expire or schedules change
+    let daemon = MyDaemon {
+        peer_pid: 0,
+        authenticated: Arc::new(AtomicBool::new(false)),
+        rules_setter: rules_setter.clone(),
+        hooks: hooks.clone(),
+        rules: rules.clone(),
+        generation: generation.clone(),
+        profile: profile.clone(),
+        clients: clients.clone(),
+        client_id: Arc::new(Mutex::new(None)),
+        answers: answers.clone(),
+        questions: questions.clone(),
+    };
 
     let mut runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
 
@@ -164,9 +416,14 @@ pub fn run(
                     peer_pid,
                     authenticated: Arc::new(AtomicBool::new(false)),
                     rules_setter: rules_setter.clone(),
+                    hooks: hooks.clone(),
                     rules: rules.clone(),
+                    generation: generation.clone(),
+                    profile: profile.clone(),
                     clients: clients.clone(),
                     client_id: Arc::new(Mutex::new(None)),
+                    answers: answers.clone(),
+                    questions: questions.clone(),
                 };
                 channel.respond_with(server.serve()).execute()
             })
@@ -194,5 +451,95 @@ pub fn run(
         handle.spawn(fut);
     });
 
+    let handle = runtime.handle().clone();
+
+    thread::spawn(move || loop {
+        let Ask {
+            id,
+            report,
+            generation,
+        } = asks.recv().expect("asks disconnected");
+        let clients = clients3.clone();
+        let questions = questions2.clone();
+        let rules = rules2.clone();
+        let current_generation = generation2.clone();
+        let answers = answers2.clone();
+        let fut = async move {
+            let mut clients = clients.lock().compat().await.unwrap();
+            if clients.is_empty() {
+                // nobody can answer it
+                answers.send(Answer {
+                    id,
+                    exe: report.exe,
+                    answer: None,
+                });
+                return;
+            }
+            // indices of older rules point to other ones
+            let asking_rule = {
+                let rules = rules.lock().compat().await.unwrap();
+                match report.matched_rule {
+                    Some(i) if generation == Some(current_generation.load(Ordering::Relaxed)) => {
+                        rules.rules.get(i).map(|rule| (i, rule.clone()))
+                    }
+                    _ => None,
+                }
+            };
+            {
+                let mut questions = questions.lock().compat().await.unwrap();
+                let now = Instant::now();
+                questions.retain(|_, question| question.asked + ASK_TIMEOUT > now);
+                questions.insert(
+                    id,
+                    Question {
+                        asked: now,
+                        report: report.clone(),
+                        asking_rule,
+                    },
+                );
+            }
+            for (_id, client) in clients.iter_mut() {
+                let r = client
+                    .on_ask(tarpc::context::current(), id, report.clone())
+                    .await;
+                if let Err(e) = r {
+                    dbg!(e);
+                }
+            }
+        };
+        handle.spawn(fut);
+    });
+
+    let handle = runtime.handle().clone();
+
+    thread::spawn(move || {
+        let mut off_schedule = Vec::new();
+        loop {
+            thread::sleep(expiry::CHECK_INTERVAL);
+            let daemon = daemon.clone();
+            let off_schedule = &mut off_schedule;
+            let fut = async move {
+                let profile = daemon.profile.lock().compat().await.unwrap();
+                let mut current = daemon.rules.lock().compat().await.unwrap();
+                let mut rules = current.clone();
+                let expired = expiry::remove_expired(&mut rules.rules);
+                // indices of the rules which stay
+                let (now_off, _) = check_schedules(&rules.rules, SystemTime::now());
+                let changed = *off_schedule != now_off;
+                *off_schedule = now_off;
+                if expired {
+                    daemon
+                        .update_rules(&profile, &mut current, rules, true)
+                        .await;
+                } else if changed {
+                    // connections accepted by conntrack are judged again
+                    daemon.index_rules(&current).await;
+                }
+            };
+            // one check at a time
+            handle.enter(|| block_on(fut));
+        }
+    });
+
     runtime.block_on(server)
 }
@@ -1,16 +1,21 @@
-use std::cell::RefCell;
+use std::cmp;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
-use std::iter::FromIterator;
+use std::iter::{self, FromIterator};
+use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use intervaltree::IntervalTree;
 use lru_time_cache::LruCache;
 use treebitmap::IpLookupTable;
 
-use gleipnir_interface::{Address, Device, Proto, Rule, RuleTarget, Rules};
+use gleipnir_interface::{
+    exe_matches, Address, Device, ProcessInfo, Proto, Rule, RuleTarget, Rules,
+};
 
 struct Bucket {
     bytes: usize,
@@ -46,23 +51,89 @@ impl Bucket {
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Drop,
+    /// No decision yet, ask the user
+    Ask,
+    Reject,
+}
+
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+const DAY: i64 = 24 * 60 * 60;
+/// Schedules are checked again at least this often, the local time changes its offset with
+/// daylight saving time
+const SCHEDULE_RECHECK: Duration = Duration::from_secs(60 * 60);
+
+/// Results of `match_target`, every worker has its own
+pub struct MatchCache {
+    generation: u32,
+    /// Scheduled rules not applying now
+    off_schedule: Vec<usize>,
+    /// When a schedule may start or end applying, the results are outdated then
+    expiry: Option<Instant>,
+    lru: LruCache<u64, (Option<usize>, RuleTarget)>,
+}
+
+impl Default for MatchCache {
+    fn default() -> Self {
+        MatchCache {
+            generation: 0,
+            off_schedule: Vec::new(),
+            expiry: None,
+            lru: LruCache::with_capacity(2048),
+        }
+    }
+}
+
 pub struct IndexedRules {
+    /// Changes every time rules are indexed
+    generation: u32,
     device: HashMap<Device, Vec<usize>>,
     any_device: Vec<usize>,
     proto: HashMap<Proto, Vec<usize>>,
     any_proto: Vec<usize>,
     exe: HashMap<String, Vec<usize>>,
+    /// Patterns by the directory before their first `*`, with the trailing slash
+    exe_globs: HashMap<String, Vec<usize>>,
     any_exe: Vec<usize>,
+    sha256: HashMap<String, Vec<usize>>,
+    any_sha256: Vec<usize>,
+    /// Rules with both an executable and its SHA-256
+    pinned: HashMap<String, Vec<usize>>,
+    /// Rules with both an executable pattern and a SHA-256
+    pinned_globs: Vec<usize>,
+    /// Some rules have a SHA-256 but no executable
+    pins_any: bool,
+    app: HashMap<String, Vec<usize>>,
+    any_app: Vec<usize>,
+    /// Indexed by the first argument, the rest may be a prefix
+    cmdline: HashMap<String, Vec<usize>>,
+    any_cmdline: Vec<usize>,
+    user: HashMap<u32, Vec<usize>>,
+    any_user: Vec<usize>,
+    group: HashMap<u32, Vec<usize>>,
+    any_group: Vec<usize>,
+    parent: HashMap<String, Vec<usize>>,
+    any_parent: Vec<usize>,
+    /// By the name of the unit, or the cgroup without the trailing slash
+    unit: HashMap<String, Vec<usize>>,
+    any_unit: Vec<usize>,
     v4_table: IpLookupTable<Ipv4Addr, Vec<usize>>,
     any_v4: Vec<usize>,
     v6_table: IpLookupTable<Ipv6Addr, Vec<usize>>,
     any_v6: Vec<usize>,
     port: IntervalTree<u16, usize>,
     any_port: Vec<usize>,
+    /// By the name, or the suffix after `*` like `.example.com`
+    domain: HashMap<String, Vec<usize>>,
+    any_domain: Vec<usize>,
     raw: Vec<Rule>,
     default_target: RuleTarget,
-    rate_state: RefCell<Vec<Bucket>>,
-    cache: RefCell<LruCache<u64, (Option<usize>, RuleTarget)>>,
+    /// Shared by all workers
+    rate_state: Mutex<Vec<Bucket>>,
 }
 
 impl IndexedRules {
@@ -77,29 +148,56 @@ impl IndexedRules {
             };
         }
 
+        // names from DNS are lowercase without the trailing dot, patterns are written by hand
+        let rules: Vec<Rule> = rules
+            .into_iter()
+            .map(|mut rule| {
+                if let Some(domain) = &mut rule.domain {
+                    *domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                }
+                rule
+            })
+            .collect();
+
         let mut r = Self {
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed),
             device: Default::default(),
             any_device: Default::default(),
             proto: Default::default(),
             any_proto: Default::default(),
             exe: Default::default(),
+            exe_globs: Default::default(),
             any_exe: Default::default(),
+            sha256: Default::default(),
+            any_sha256: Default::default(),
+            pinned: Default::default(),
+            pinned_globs: Vec::new(),
+            pins_any: false,
+            app: Default::default(),
+            any_app: Default::default(),
+            cmdline: Default::default(),
+            any_cmdline: Default::default(),
+            user: Default::default(),
+            any_user: Default::default(),
+            group: Default::default(),
+            any_group: Default::default(),
+            parent: Default::default(),
+            any_parent: Default::default(),
+            unit: Default::default(),
+            any_unit: Default::default(),
             v4_table: IpLookupTable::new(),
             any_v4: Default::default(),
             v6_table: IpLookupTable::new(),
             any_v6: Default::default(),
             port: IntervalTree::from_iter(vec![(0..0, 0); 0].into_iter()),
             any_port: Default::default(),
+            domain: Default::default(),
+            any_domain: Default::default(),
             raw: rules.clone(),
             default_target: default_target,
-            rate_state: Default::default(),
-            cache: RefCell::new(LruCache::with_capacity(2048)),
+            rate_state: Mutex::new(rate_rules.into_iter().map(Bucket::new).collect()),
         };
 
-        for limit in rate_rules {
-            r.rate_state.borrow_mut().push(Bucket::new(limit));
-        }
-
         let mut v4_hashmap: HashMap<(Ipv4Addr, u8), Vec<usize>> = HashMap::new();
         let mut v6_hashmap: HashMap<(Ipv6Addr, u8), Vec<usize>> = HashMap::new();
 
@@ -108,7 +206,42 @@ impl IndexedRules {
         for (index, rule) in rules.into_iter().enumerate() {
             insert_rule!(r, rule, device, any_device, index);
             insert_rule!(r, rule, proto, any_proto, index);
-            insert_rule!(r, rule, exe, any_exe, index);
+            match (&rule.exe, &rule.sha256) {
+                (Some(exe), Some(_)) if exe.contains('*') => r.pinned_globs.push(index),
+                (Some(exe), Some(_)) => r.pinned.entry(exe.clone()).or_default().push(index),
+                (None, Some(_)) => r.pins_any = true,
+                (_, None) => (),
+            }
+            match &rule.exe {
+                Some(exe) if exe.contains('*') => {
+                    let literal = &exe[..exe.find('*').unwrap()];
+                    let dir = &literal[..literal.rfind('/').map_or(0, |i| i + 1)];
+                    r.exe_globs.entry(dir.into()).or_default().push(index);
+                }
+                Some(exe) => r.exe.entry(exe.clone()).or_default().push(index),
+                None => r.any_exe.push(index),
+            }
+            insert_rule!(r, rule, sha256, any_sha256, index);
+            insert_rule!(r, rule, app, any_app, index);
+            if let Some(cmdline) = &rule.cmdline {
+                r.cmdline
+                    .entry(first_arg(cmdline).into())
+                    .or_default()
+                    .push(index);
+            } else {
+                r.any_cmdline.push(index);
+            }
+            insert_rule!(r, rule, user, any_user, index);
+            insert_rule!(r, rule, group, any_group, index);
+            insert_rule!(r, rule, parent, any_parent, index);
+            if let Some(unit) = &rule.unit {
+                r.unit
+                    .entry(unit.trim_end_matches('/').into())
+                    .or_default()
+                    .push(index);
+            } else {
+                r.any_unit.push(index);
+            }
             if let Some(port_range) = rule.port {
                 let (start, end) = port_range.into_inner();
                 port_rules.push((start..end + 1, index));
@@ -133,6 +266,14 @@ impl IndexedRules {
                     r.any_v6.push(index);
                 }
             }
+            if let Some(domain) = &rule.domain {
+                r.domain
+                    .entry(domain.trim_start_matches('*').into())
+                    .or_default()
+                    .push(index);
+            } else {
+                r.any_domain.push(index);
+            }
         }
 
         r.port = IntervalTree::from_iter(port_rules);
@@ -147,31 +288,108 @@ impl IndexedRules {
         r
     }
 
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    pub fn raw(&self) -> &[Rule] {
+        &self.raw
+    }
+
+    pub fn default_target(&self) -> RuleTarget {
+        self.default_target
+    }
+
+    /// The SHA-256 of the executable is needed to match it
+    pub fn is_pinned(&self, exe: &str) -> bool {
+        self.pins_any || self.pinned.contains_key(exe) || self.globs_pinning(exe).next().is_some()
+    }
+
+    /// The rules in `pinned_globs` matching `exe`
+    fn globs_pinning<'a>(&'a self, exe: &'a str) -> impl Iterator<Item = usize> + 'a {
+        self.pinned_globs
+            .iter()
+            .cloned()
+            .filter(move |&id| exe_matches(self.raw[id].exe.as_ref().unwrap(), exe))
+    }
+
+    /// The first rule pinning `exe` with another SHA-256
+    pub fn broken_pin(&self, exe: &str, sha256: &str) -> Option<usize> {
+        self.pinned
+            .get(exe)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(self.globs_pinning(exe))
+            .filter(|&id| self.raw[id].sha256.as_deref() != Some(sha256))
+            .min()
+    }
+
+    /// The verdict of a rate limited packet says nothing about the rest of its connection
+    pub fn is_rate_limited(&self, rule_id: Option<usize>) -> bool {
+        let target = rule_id
+            .map(|id| self.raw[id].target)
+            .unwrap_or(self.default_target);
+        match target {
+            RuleTarget::RateLimit(_) => true,
+            _ => false,
+        }
+    }
+
     pub fn is_acceptable(
         &self,
+        cache: &mut MatchCache,
         device: Device,
         protocol: Proto,
         addr: SocketAddr,
+        domains: &[String],
+        icmp: Option<(u8, u8)>,
         len: usize,
-        exe: &str,
-    ) -> (Option<usize>, bool) {
+        process: ProcessInfo,
+    ) -> (Option<usize>, Verdict) {
         let mut hasher = DefaultHasher::new();
-        (device, protocol, addr, exe).hash(&mut hasher);
+        (device, protocol, addr, domains, icmp, process).hash(&mut hasher);
         let lru_index = hasher.finish();
 
-        let mut cache = self.cache.borrow_mut();
-        let (rule_id, target) = cache.get(&lru_index).cloned().unwrap_or_else(|| {
-            let result = self.match_target(device, protocol, addr, exe);
-            cache.insert(lru_index, result);
+        let expired = cache
+            .expiry
+            .map_or(false, |expiry| expiry <= Instant::now());
+        if cache.generation != self.generation || expired {
+            *cache = MatchCache::default();
+            cache.generation = self.generation;
+            let (off_schedule, expiry) = self.check_schedules(SystemTime::now());
+            cache.off_schedule = off_schedule;
+            cache.expiry = expiry;
+        }
+        let MatchCache {
+            lru, off_schedule, ..
+        } = cache;
+        let (rule_id, target) = lru.get(&lru_index).cloned().unwrap_or_else(|| {
+            let result =
+                self.match_target(device, protocol, addr, domains, icmp, process, off_schedule);
+            lru.insert(lru_index, result);
             result
         });
 
-        let accept = match target {
-            RuleTarget::Accept => true,
-            RuleTarget::Drop => false,
-            RuleTarget::RateLimit(rate_id) => self.rate_state.borrow_mut()[rate_id].stuff(len),
+        let verdict = match target {
+            RuleTarget::Accept => Verdict::Accept,
+            RuleTarget::Drop => Verdict::Drop,
+            RuleTarget::Ask => Verdict::Ask,
+            RuleTarget::Reject => Verdict::Reject,
+            RuleTarget::RateLimit(rate_id) => {
+                if self.rate_state.lock().unwrap()[rate_id].stuff(len) {
+                    Verdict::Accept
+                } else {
+                    Verdict::Drop
+                }
+            }
         };
-        (rule_id, accept)
+        (rule_id, verdict)
+    }
+
+    /// Scheduled rules not applying at `now`, and when that may change
+    fn check_schedules(&self, now: SystemTime) -> (Vec<usize>, Option<Instant>) {
+        check_schedules(&self.raw, now)
     }
 
     fn match_target(
@@ -179,17 +397,74 @@ impl IndexedRules {
         device: Device,
         protocol: Proto,
         addr: SocketAddr,
-        exe: &str,
+        domains: &[String],
+        icmp: Option<(u8, u8)>,
+        process: ProcessInfo,
+        off_schedule: &[usize],
     ) -> (Option<usize>, RuleTarget) {
         let empty = Vec::new();
         let exact_device = self.device.get(&device).unwrap_or(&empty);
         let exact_proto = self.proto.get(&protocol).unwrap_or(&empty);
-        let exact_exe = self.exe.get(exe).unwrap_or(&empty);
+        let exact_exe = &self
+            .exe
+            .get(process.exe)
+            .into_iter()
+            .chain(
+                // patterns anywhere, and in every directory the executable is in
+                iter::once(0)
+                    .chain(process.exe.match_indices('/').map(|(i, _)| i + 1))
+                    .filter_map(|end| self.exe_globs.get(&process.exe[..end])),
+            )
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let exact_sha256 = process
+            .sha256
+            .and_then(|sha256| self.sha256.get(sha256))
+            .unwrap_or(&empty);
+        let exact_app = self.app.get(process.app).unwrap_or(&empty);
+        let exact_cmdline = self
+            .cmdline
+            .get(first_arg(process.cmdline))
+            .unwrap_or(&empty);
+        let exact_user = self.user.get(&process.uid).unwrap_or(&empty);
+        let exact_group = self.group.get(&process.gid).unwrap_or(&empty);
+        let exact_parent = &process
+            .ancestors
+            .iter()
+            .filter_map(|exe| self.parent.get(exe))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let exact_unit = &iter::once(process.unit)
+            // the cgroup and every one above it
+            .chain(
+                process
+                    .cgroup
+                    .match_indices('/')
+                    .map(|(i, _)| &process.cgroup[..i]),
+            )
+            .chain(iter::once(process.cgroup))
+            .filter_map(|unit| self.unit.get(unit))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
         let exact_port = &self
             .port
             .query_point(addr.port())
             .map(|v| v.value)
             .collect::<Vec<_>>(); // TODO: zero alloc
+        let exact_domain = &domains
+            .iter()
+            // the name, and every domain above it
+            .flat_map(|name| {
+                iter::once(name.as_str())
+                    .chain(name.match_indices('.').map(move |(i, _)| &name[i..]))
+            })
+            .filter_map(|domain| self.domain.get(domain))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
         let (exact_ip, any_ip) = match addr.ip() {
             IpAddr::V4(ip) => (
                 self.v4_table
@@ -210,8 +485,16 @@ impl IndexedRules {
             (exact_device, &self.any_device),
             (exact_proto, &self.any_proto),
             (exact_exe, &self.any_exe),
+            (exact_sha256, &self.any_sha256),
+            (exact_app, &self.any_app),
+            (exact_cmdline, &self.any_cmdline),
+            (exact_user, &self.any_user),
+            (exact_group, &self.any_group),
+            (exact_parent, &self.any_parent),
+            (exact_unit, &self.any_unit),
             (exact_port, &self.any_port),
             (exact_ip, any_ip),
+            (exact_domain, &self.any_domain),
         ];
         let (exact, any) = list
             .iter()
@@ -221,9 +504,10 @@ impl IndexedRules {
         exact
             .into_iter()
             .chain(*any)
+            .filter(|id| !off_schedule.contains(id))
             .filter_map(|&id| {
                 self.raw[id]
-                    .match_target(device, protocol, addr, exe)
+                    .match_target(device, protocol, addr, domains, icmp, process)
                     .map(|t| (id, t))
             })
             .min_by_key(|(id, _)| *id)
@@ -232,6 +516,63 @@ impl IndexedRules {
     }
 }
 
+/// Rules not applying at `now` because of their schedules, and when that may change. `None`
+/// if no rule is scheduled
+pub fn check_schedules(rules: &[Rule], now: SystemTime) -> (Vec<usize>, Option<Instant>) {
+    let scheduled = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(id, rule)| Some((id, rule.schedule.as_ref()?)))
+        .collect::<Vec<_>>();
+    if scheduled.is_empty() {
+        return (Vec::new(), None);
+    }
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let local_offset = local_utc_offset(now);
+    let mut off_schedule = Vec::new();
+    let mut next_change = SCHEDULE_RECHECK.as_secs() as i64;
+    for (id, schedule) in scheduled {
+        let local = now + i64::from(schedule.utc_offset.unwrap_or(local_offset)) * 60;
+        // the epoch was a Thursday
+        let day = ((local.div_euclid(DAY) + 4) % 7) as u8;
+        let second = local.rem_euclid(DAY);
+        if !schedule.contains(day, (second / 60) as u16) {
+            off_schedule.push(id);
+        }
+        // the starts and ends of the times, and the next day
+        let boundaries = schedule
+            .times
+            .iter()
+            .flat_map(|&(start, end)| vec![start, end])
+            .chain(iter::once(0));
+        for minute in boundaries {
+            match (i64::from(minute) * 60 - second).rem_euclid(DAY) {
+                0 => (),
+                until => next_change = cmp::min(next_change, until),
+            }
+        }
+    }
+    let expiry = Instant::now() + Duration::from_secs(next_change as u64);
+    (off_schedule, Some(expiry))
+}
+
+/// Minutes east of UTC in the time zone of the system, at `time` since the epoch
+fn local_utc_offset(time: i64) -> i32 {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    (tm.tm_gmtoff / 60) as i32
+}
+
+/// The script of an interpreter, or the program itself
+fn first_arg(cmdline: &str) -> &str {
+    cmdline.split(' ').next().unwrap_or_default()
+}
+
 impl From<Rules> for IndexedRules {
     fn from(r: Rules) -> Self {
         Self::new(
@@ -245,50 +586,58 @@ impl From<Rules> for IndexedRules {
 #[cfg(test)]
 mod test {
     use super::*;
+    use gleipnir_interface::{IcmpType, Schedule};
     use std::ops::RangeInclusive;
 
+    /// The verdict of an outgoing HTTPS connection of `process`, to an address resolved from
+    /// `domains`
+    fn check(r: &IndexedRules, domains: &[&str], process: ProcessInfo) -> (Option<usize>, Verdict) {
+        let domains: Vec<String> = domains.iter().map(|&d| d.into()).collect();
+        r.is_acceptable(
+            &mut MatchCache::default(),
+            Device::Output,
+            Proto::Tcp,
+            ([1, 1, 1, 1], 443).into(),
+            &domains,
+            None,
+            0,
+            process,
+        )
+    }
+
     #[test]
     fn rules_indexing() {
         let raw_rules = vec![
             Rule {
                 device: Some(Device::Input),
-                proto: None,
-                exe: None,
-                port: None,
                 subnet: Some(([1, 1, 1, 1].into(), 32)),
-                target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
                 proto: Some(Proto::Tcp),
-                exe: None,
-                port: None,
                 subnet: Some(([1, 1, 1, 1].into(), 32)),
-                target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
                 proto: Some(Proto::Tcp),
-                exe: None,
-                port: None,
                 subnet: Some(([2, 2, 2, 2].into(), 30)),
-                target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
-                proto: None,
                 exe: Some("".into()),
                 port: Some(RangeInclusive::new(10, 200)),
                 subnet: Some(([2, 2, 2, 2].into(), 32)),
-                target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
-                proto: None,
                 exe: Some("".into()),
                 port: Some(RangeInclusive::new(100, 100)),
                 subnet: Some(([0, 0, 0, 0].into(), 0)),
-                target: RuleTarget::Accept,
+                ..Default::default()
             },
         ];
 
@@ -329,8 +678,506 @@ mod test {
         assert_eq!(r.default_target, RuleTarget::Drop);
 
         assert_eq!(
-            r.is_acceptable(Device::Input, Proto::Tcp, ([2, 2, 2, 2], 100).into(), 0, "",),
-            (Some(3), true)
+            r.is_acceptable(
+                &mut MatchCache::default(),
+                Device::Input,
+                Proto::Tcp,
+                ([2, 2, 2, 2], 100).into(),
+                &[],
+                None,
+                0,
+                ProcessInfo::default(),
+            ),
+            (Some(3), Verdict::Accept)
+        );
+    }
+
+    #[test]
+    fn icmp_type() {
+        let raw_rules = vec![
+            Rule {
+                port: Some(0..=0),
+                ..Default::default()
+            },
+            Rule {
+                icmp: Some(IcmpType {
+                    kind: 3,
+                    code: Some(3),
+                }),
+                ..Default::default()
+            },
+            Rule {
+                icmp: Some(IcmpType {
+                    kind: 8,
+                    code: None,
+                }),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        let mut cache = MatchCache::default();
+        let mut check = |icmp| {
+            let addr = ([1, 1, 1, 1], 0).into();
+            r.is_acceptable(
+                &mut cache,
+                Device::Output,
+                Proto::Icmp,
+                addr,
+                &[],
+                icmp,
+                0,
+                ProcessInfo::default(),
+            )
+        };
+        assert_eq!(check(Some((8, 0))), (Some(2), Verdict::Accept));
+        assert_eq!(check(Some((3, 3))), (Some(1), Verdict::Accept));
+        assert_eq!(check(Some((3, 1))), (None, Verdict::Drop));
+    }
+
+    #[test]
+    fn cmdline() {
+        let raw_rules = vec![
+            Rule {
+                cmdline: Some("/home/user/server.py --public".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/python3.8".into()),
+                cmdline: Some("/home/user/server.py".into()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Ask, raw_rules, vec![]);
+        assert_eq!(r.cmdline["/home/user/server.py"], vec![0, 1]);
+        let check = |cmdline| {
+            let process = ProcessInfo {
+                exe: "/usr/bin/python3.8",
+                cmdline,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(
+            check("/home/user/server.py --public --port 80"),
+            (Some(0), Verdict::Drop)
+        );
+        assert_eq!(check("/home/user/server.py"), (Some(1), Verdict::Accept));
+        assert_eq!(check("/home/user/server.py2"), (None, Verdict::Ask));
+        assert_eq!(check("/home/user/client.py"), (None, Verdict::Ask));
+    }
+
+    #[test]
+    fn user_and_group() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/usr/bin/firefox".into()),
+                user: Some(1000),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/firefox".into()),
+                group: Some(100),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/firefox".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert_eq!(r.user[&1000], vec![0]);
+        assert_eq!(r.group[&100], vec![1]);
+        let check = |uid, gid| {
+            let process = ProcessInfo {
+                exe: "/usr/bin/firefox",
+                uid,
+                gid,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(check(1000, 1000), (Some(0), Verdict::Accept));
+        assert_eq!(check(1001, 100), (Some(1), Verdict::Accept));
+        assert_eq!(check(1002, 1002), (Some(2), Verdict::Drop));
+    }
+
+    #[test]
+    fn parent() {
+        let raw_rules = vec![
+            Rule {
+                parent: Some("/usr/bin/code".into()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                parent: Some("/usr/sbin/sshd".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Ask, raw_rules, vec![]);
+        assert_eq!(r.parent["/usr/sbin/sshd"], vec![1]);
+        let check = |ancestors: &[String]| {
+            let process = ProcessInfo {
+                exe: "/usr/bin/npm",
+                ancestors,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        let tree = |exes: &[&str]| exes.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            check(&tree(&[
+                "/usr/bin/bash",
+                "/usr/bin/code",
+                "/usr/lib/systemd/systemd"
+            ])),
+            (Some(0), Verdict::Accept)
+        );
+        assert_eq!(
+            check(&tree(&["/usr/bin/bash", "/usr/sbin/sshd", "/usr/bin/code"])),
+            (Some(0), Verdict::Accept)
+        );
+        assert_eq!(
+            check(&tree(&["/usr/bin/bash", "/usr/sbin/sshd"])),
+            (Some(1), Verdict::Drop)
+        );
+        assert_eq!(check(&[]), (None, Verdict::Ask));
+    }
+
+    #[test]
+    fn pinned() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/usr/bin/curl".into()),
+                sha256: Some("aa".into()),
+                ..Default::default()
+            },
+            Rule {
+                sha256: Some("bb".into()),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert!(r.is_pinned("/usr/bin/wget"));
+        assert_eq!(r.broken_pin("/usr/bin/curl", "aa"), None);
+        assert_eq!(r.broken_pin("/usr/bin/curl", "cc"), Some(0));
+        assert_eq!(r.broken_pin("/usr/bin/wget", "cc"), None);
+
+        let r = IndexedRules::new(RuleTarget::Drop, r.raw[..1].to_vec(), vec![]);
+        assert!(r.is_pinned("/usr/bin/curl"));
+        assert!(!r.is_pinned("/usr/bin/wget"));
+        let check = |sha256| {
+            let process = ProcessInfo {
+                exe: "/usr/bin/curl",
+                sha256,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(check(Some("aa")), (Some(0), Verdict::Accept));
+        assert_eq!(check(Some("cc")), (None, Verdict::Drop));
+        assert_eq!(check(None), (None, Verdict::Drop));
+
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/opt/*/bin/java".into()),
+                sha256: Some("aa".into()),
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/opt/jdk/bin/java".into()),
+                sha256: Some("bb".into()),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert!(r.is_pinned("/opt/jre/bin/java"));
+        assert!(!r.is_pinned("/usr/bin/java"));
+        assert_eq!(r.broken_pin("/opt/jre/bin/java", "aa"), None);
+        assert_eq!(r.broken_pin("/opt/jre/bin/java", "cc"), Some(0));
+        assert_eq!(r.broken_pin("/opt/jdk/bin/java", "bb"), Some(0));
+        assert_eq!(r.broken_pin("/opt/jdk/bin/java", "aa"), Some(1));
+        let process = ProcessInfo {
+            exe: "/opt/jre/bin/java",
+            sha256: Some("aa"),
+            ..Default::default()
+        };
+        assert_eq!(self::check(&r, &[], process), (Some(0), Verdict::Accept));
+    }
+
+    #[test]
+    fn exe_glob() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/opt/jetbrains/idea-*/bin/java".into()),
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/home/*/.cargo/bin/**".into()),
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/curl".into()),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("**/curl".into()),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert_eq!(r.exe_globs["/opt/jetbrains/"], vec![0]);
+        assert_eq!(r.exe_globs["/home/"], vec![1]);
+        assert_eq!(r.exe_globs[""], vec![3]);
+        let check = |exe| {
+            let process = ProcessInfo {
+                exe,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(
+            check("/opt/jetbrains/idea-2020.3/bin/java"),
+            (Some(0), Verdict::Accept)
         );
+        assert_eq!(
+            check("/opt/jetbrains/idea-2020.3/jbr/bin/java"),
+            (None, Verdict::Drop)
+        );
+        assert_eq!(
+            check("/home/user/.cargo/bin/cargo"),
+            (Some(1), Verdict::Accept)
+        );
+        assert_eq!(
+            check("/home/user/.cargo/bin/sub/dir/x"),
+            (Some(1), Verdict::Accept)
+        );
+        assert_eq!(check("/home/.cargo/bin/cargo"), (None, Verdict::Drop));
+        assert_eq!(check("/usr/bin/curl"), (Some(2), Verdict::Reject));
+        assert_eq!(check("/usr/local/bin/curl"), (Some(3), Verdict::Accept));
+    }
+
+    #[test]
+    fn exe_glob_segments() {
+        let pattern = "/**/lib/**/bin/*";
+        assert!(exe_matches(pattern, "/usr/lib/jvm/java/bin/java"));
+        assert!(!exe_matches(pattern, "/usr/lib/jvm/bin/java/x"));
+        assert!(exe_matches("/**/**/bin/*", "/a/b/bin/x"));
+        assert!(!exe_matches("/**/**/bin/*", "/bin/x"));
+        // each part of the pattern is tried once for every position, however long the path
+        let path = format!("/{}", "a".repeat(4096));
+        assert!(!exe_matches("/**a**a**a**a**a**a**a**a**a**b", &path));
+        assert!(!exe_matches("/*a*a*a*a*a*a*a*a*a*b", &path));
+        assert!(exe_matches("/**a**a**a**a**a**a**a**a**a**", &path));
+    }
+
+    #[test]
+    fn app() {
+        let raw_rules = vec![Rule {
+            app: Some("flatpak:org.mozilla.firefox".into()),
+            ..Default::default()
+        }];
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        let check = |app| {
+            let process = ProcessInfo {
+                exe: "/app/lib/firefox/firefox",
+                app,
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(
+            check("flatpak:org.mozilla.firefox"),
+            (Some(0), Verdict::Accept)
+        );
+        assert_eq!(check("snap:firefox"), (None, Verdict::Drop));
+        assert_eq!(check(""), (None, Verdict::Drop));
+    }
+
+    #[test]
+    fn unit() {
+        let raw_rules = vec![
+            Rule {
+                unit: Some("apt-daily.service".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                unit: Some("/system.slice/".into()),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Ask, raw_rules, vec![]);
+        assert_eq!(r.unit["/system.slice"], vec![1]);
+        let check = |cgroup| {
+            let process = ProcessInfo {
+                exe: "/usr/lib/apt/methods/http",
+                cgroup,
+                unit: crate::proc::unit_of(cgroup),
+                ..Default::default()
+            };
+            check(&r, &[], process)
+        };
+        assert_eq!(
+            check("/system.slice/apt-daily.service"),
+            (Some(0), Verdict::Drop)
+        );
+        assert_eq!(
+            check("/system.slice/systemd-resolved.service"),
+            (Some(1), Verdict::Accept)
+        );
+        assert_eq!(check("/system.slice-x/foo.service"), (None, Verdict::Ask));
+        // a user unit named like the system one
+        assert_eq!(
+            check("/user.slice/user-1000.slice/user@1000.service/app.slice/apt-daily.service"),
+            (None, Verdict::Ask)
+        );
+        assert_eq!(
+            check("/user.slice/user-1000.slice/session-2.scope"),
+            (None, Verdict::Ask)
+        );
+    }
+
+    #[test]
+    fn domain() {
+        let raw_rules = vec![
+            Rule {
+                domain: Some("ads.example.com".into()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                domain: Some("*.Example.com.".into()),
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Ask, raw_rules, vec![]);
+        // patterns are written by hand, unlike names from DNS
+        assert_eq!(r.domain[".example.com"], vec![1]);
+        let check = |domains: &[&str]| {
+            let process = ProcessInfo {
+                exe: "/usr/bin/curl",
+                ..Default::default()
+            };
+            check(&r, domains, process)
+        };
+        assert_eq!(check(&["ads.example.com"]), (Some(0), Verdict::Drop));
+        assert_eq!(check(&["www.example.com"]), (Some(1), Verdict::Accept));
+        // one of the aliases
+        assert_eq!(
+            check(&["cdn.net", "a.b.example.com"]),
+            (Some(1), Verdict::Accept)
+        );
+        assert_eq!(check(&["example.com"]), (None, Verdict::Ask));
+        assert_eq!(check(&["badexample.com"]), (None, Verdict::Ask));
+        assert_eq!(check(&[]), (None, Verdict::Ask));
+    }
+
+    #[test]
+    fn schedule() {
+        let work_hours = Schedule {
+            days: 0b0111110,
+            times: vec![(9 * 60, 17 * 60)],
+            utc_offset: None,
+        };
+        assert!(work_hours.contains(1, 9 * 60));
+        assert!(!work_hours.contains(1, 17 * 60));
+        assert!(!work_hours.contains(0, 12 * 60));
+        // friday night, until saturday morning
+        let night = Schedule {
+            days: 1 << 5,
+            times: vec![(22 * 60, 6 * 60)],
+            utc_offset: Some(60),
+        };
+        assert!(night.contains(5, 23 * 60));
+        assert!(night.contains(6, 60));
+        assert!(!night.contains(5, 60));
+
+        let r = IndexedRules::new(
+            RuleTarget::Accept,
+            vec![
+                Rule {
+                    target: RuleTarget::Drop,
+                    ..Default::default()
+                },
+                Rule {
+                    schedule: Some(night.clone()),
+                    target: RuleTarget::Drop,
+                    ..Default::default()
+                },
+            ],
+            vec![],
+        );
+        let in_minutes =
+            |expiry: Option<Instant>| (expiry.unwrap() - Instant::now()).as_secs() as i64 / 60 + 1;
+        // the epoch was a thursday, the schedule is an hour ahead of UTC
+        let friday = UNIX_EPOCH + Duration::from_secs(DAY as u64);
+        let (off, expiry) = r.check_schedules(friday + Duration::from_secs(20 * 60 * 60 + 30 * 60));
+        assert_eq!(off, vec![1]);
+        assert_eq!(in_minutes(expiry), 30);
+        let (off, expiry) = r.check_schedules(friday + Duration::from_secs(23 * 60 * 60));
+        assert!(off.is_empty());
+        assert_eq!(in_minutes(expiry), 60);
+
+        let process = ProcessInfo {
+            exe: "/usr/bin/steam",
+            ..Default::default()
+        };
+        let addr = ([1, 1, 1, 1], 443).into();
+        let r = IndexedRules::new(
+            RuleTarget::Drop,
+            vec![
+                Rule {
+                    schedule: Some(night),
+                    target: RuleTarget::Drop,
+                    ..Default::default()
+                },
+                Rule {
+                    target: RuleTarget::Accept,
+                    ..Default::default()
+                },
+            ],
+            vec![],
+        );
+        let check = |off_schedule: &[usize]| {
+            r.match_target(
+                Device::Output,
+                Proto::Tcp,
+                addr,
+                &[],
+                None,
+                process,
+                off_schedule,
+            )
+        };
+        assert_eq!(check(&[]), (Some(0), RuleTarget::Drop));
+        // the next rule applies outside of the schedule
+        assert_eq!(check(&[0]), (Some(1), RuleTarget::Accept));
+        let raw_rules = vec![Rule {
+            schedule: Some(work_hours),
+            target: RuleTarget::Drop,
+            ..Default::default()
+        }];
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![]);
+        let check = |off_schedule: &[usize]| {
+            r.match_target(
+                Device::Output,
+                Proto::Tcp,
+                addr,
+                &[],
+                None,
+                process,
+                off_schedule,
+            )
+        };
+        assert_eq!(check(&[]), (Some(0), RuleTarget::Drop));
+        assert_eq!(check(&[0]), (None, RuleTarget::Accept));
     }
 }
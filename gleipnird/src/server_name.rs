@@ -0,0 +1,171 @@
+//! Names of servers, told by the first data of the clients
+//!
+//! The SNI of TLS ClientHellos, bare or in QUIC Initial packets, and the `Host` of HTTP
+//! requests. Programs resolving names by themselves, over DoH for example, still tell them
+
+use std::net::IpAddr;
+
+use gleipnir_interface::Proto;
+
+use crate::quic::{self, Reader};
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_RECORD_HEADER_LEN: usize = 5;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
+/// Lowercase without the trailing dot, `data` is the payload of the transport layer
+pub fn parse(protocol: Proto, data: &[u8]) -> Option<String> {
+    let name = match protocol {
+        Proto::Tcp => match data.first() {
+            Some(&TLS_HANDSHAKE) => client_hello_sni(data.get(TLS_RECORD_HEADER_LEN..)?)?,
+            _ => http_host(data)?,
+        },
+        Proto::Udp => client_hello_sni(&quic::initial_crypto(data)?)?,
+        _ => return None,
+    };
+    normalize(&name)
+}
+
+/// The handshake message may be cut, the extensions before the end are still read
+fn client_hello_sni(handshake: &[u8]) -> Option<String> {
+    let mut r = Reader::new(handshake);
+    if r.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    // length, version and random
+    r.bytes(3 + 2 + 32)?;
+    let session_id_len = r.u8()? as usize;
+    r.bytes(session_id_len)?;
+    let cipher_suites_len = r.u16()? as usize;
+    r.bytes(cipher_suites_len)?;
+    let compression_methods_len = r.u8()? as usize;
+    r.bytes(compression_methods_len)?;
+    // length of the extensions
+    r.u16()?;
+    loop {
+        let (kind, len) = (r.u16()?, r.u16()? as usize);
+        let extension = r.bytes(len)?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut r = Reader::new(extension);
+        // length of the list, only one name of each type is allowed
+        r.u16()?;
+        while let Some(name_type) = r.u8() {
+            let len = r.u16()? as usize;
+            let name = r.bytes(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+        return None;
+    }
+}
+
+/// Without the port
+fn http_host(data: &[u8]) -> Option<String> {
+    if !HTTP_METHODS.iter().any(|method| data.starts_with(method)) {
+        return None;
+    }
+    // after the request line, until the end of the headers
+    for line in data.split(|&b| b == b'\n').skip(1) {
+        let line = match line.last() {
+            Some(b'\r') => &line[..line.len() - 1],
+            _ => line,
+        };
+        if line.is_empty() {
+            break;
+        }
+        if line.len() > 5 && line[..5].eq_ignore_ascii_case(b"host:") {
+            let host = std::str::from_utf8(&line[5..]).ok()?.trim();
+            let host = if host.starts_with('[') {
+                host.split(']').next()?.trim_start_matches('[')
+            } else {
+                host.split(':').next()?
+            };
+            return Some(host.to_owned());
+        }
+    }
+    None
+}
+
+/// Addresses are not names, and anything else than a hostname is ignored
+fn normalize(name: &str) -> Option<String> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) || name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A ClientHello with an extension before the SNI
+    fn client_hello(name: &str) -> Vec<u8> {
+        let mut sni = vec![0, 0];
+        sni.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+        sni.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        sni.push(NAME_TYPE_HOST_NAME);
+        sni.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni.extend_from_slice(name.as_bytes());
+        // supported_versions, TLS 1.3
+        let mut extensions = vec![0, 43, 0, 3, 2, 3, 4];
+        extensions.extend_from_slice(&sni);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0xaa; 32]);
+        // session id, one cipher suite, no compression
+        body.extend_from_slice(&[2, 1, 2, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&body);
+        handshake
+    }
+
+    #[test]
+    fn tls() {
+        let handshake = client_hello("WWW.Example.com.");
+        let mut record = vec![TLS_HANDSHAKE, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        assert_eq!(
+            parse(Proto::Tcp, &record),
+            Some("www.example.com".to_owned())
+        );
+        // cut before the name
+        assert_eq!(parse(Proto::Tcp, &record[..record.len() - 4]), None);
+        assert_eq!(parse(Proto::Udp, &record), None);
+    }
+
+    #[test]
+    fn http() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: curl\r\nHost: example.com:8080\r\n\r\n";
+        assert_eq!(parse(Proto::Tcp, request), Some("example.com".to_owned()));
+        let request = b"GET / HTTP/1.1\r\nhost: [::1]:8080\r\n\r\n";
+        assert_eq!(parse(Proto::Tcp, request), None);
+        // in the body
+        let request = b"POST / HTTP/1.1\r\n\r\nHost: example.com\r\n";
+        assert_eq!(parse(Proto::Tcp, request), None);
+        assert_eq!(parse(Proto::Tcp, b"SSH-2.0-OpenSSH_8.4\r\n"), None);
+    }
+}